@@ -0,0 +1,153 @@
+//! Translating the rules into a task list for Ansible's
+//! `ansible.builtin.iptables` module, for the restrictions and
+//! actions that map cleanly onto its parameters.
+
+use anyhow::{bail, Result};
+
+use crate::iptables::{Action, AnyAction, Effect, IptablesWriter, Negatable, Restriction};
+use crate::shell_quote::shell_quote_many;
+
+/// A YAML scalar (JSON strings are valid YAML).
+fn yaml_str(s: &str) -> String {
+    serde_json::to_string(s).expect("strings can always be serialized")
+}
+
+/// The value for a parameter, with the module's "!" prefix for
+/// negation.
+fn negated(neg: Negatable, value: String) -> String {
+    match neg {
+        Negatable::Is => value,
+        Negatable::IsNot => format!("! {value}"),
+    }
+}
+
+/// The module parameters (as YAML values) for `restriction`.
+fn restriction_params(restriction: &Restriction) -> Result<Vec<(&'static str, String)>> {
+    let param = |name, neg, value: String| Ok(vec![(name, yaml_str(&negated(neg, value)))]);
+    match restriction {
+        Restriction::Interface(neg, spec) => param("in_interface", *neg, spec.to_arg()),
+        Restriction::Protocol(neg, p) => param("protocol", *neg, p.into()),
+        Restriction::SourceAddress(neg, net) => param("source", *neg, net.to_string()),
+        Restriction::DestinationAddress(neg, net) => param("destination", *neg, net.to_string()),
+        Restriction::SourcePort(neg, port) => param("source_port", *neg, port.to_string()),
+        Restriction::DestinationPort(neg, port) => {
+            param("destination_port", *neg, port.to_string())
+        }
+        Restriction::DestinationPorts(Negatable::Is, ports) => {
+            let ports: Vec<String> = ports.iter().map(|p| p.to_string()).collect();
+            Ok(vec![(
+                "destination_ports",
+                serde_json::to_string(&ports).expect("strings can always be serialized"),
+            )])
+        }
+        Restriction::IcmpType(neg, t) => param("icmp_type", *neg, t.clone()),
+        Restriction::Comment(comment) => param("comment", Negatable::Is, comment.clone()),
+        Restriction::ConnState(Negatable::Is, states) => {
+            let states: Vec<String> = states.iter().map(String::from).collect();
+            Ok(vec![(
+                "ctstate",
+                serde_json::to_string(&states).expect("strings can always be serialized"),
+            )])
+        }
+        Restriction::Limit(rate) => param("limit", Negatable::Is, rate.clone()),
+        _ => bail!("restriction not supported for Ansible: {restriction:?}"),
+    }
+}
+
+/// The module parameters (as YAML values) for the rule action
+/// arguments `args`.
+fn action_params(args: &[String]) -> Result<Vec<(&'static str, String)>> {
+    Ok(match args {
+        [] => vec![],
+        [j, target] if j == "-j" => vec![("jump", yaml_str(target))],
+        [g, target] if g == "-g" => vec![("goto", yaml_str(target))],
+        [j, target, opt, value] if j == "-j" && target == "LOG" && opt == "--log-prefix" => {
+            vec![("jump", yaml_str(target)), ("log_prefix", yaml_str(value))]
+        }
+        [j, target, opt, value] if j == "-j" && target == "REJECT" && opt == "--reject-with" => {
+            vec![("jump", yaml_str(target)), ("reject_with", yaml_str(value))]
+        }
+        _ => bail!(
+            "rule action not supported for Ansible: {}",
+            shell_quote_many(args)
+        ),
+    })
+}
+
+impl IptablesWriter {
+    /// The creation plan as a YAML list of `ansible.builtin.iptables`
+    /// tasks, one per command (chain creations use
+    /// `chain_management`). Fails for restrictions and actions that
+    /// have no equivalent parameter (e.g. `Custom`, or negated
+    /// multiport matches), naming the offending one.
+    pub fn to_ansible(&self) -> Result<String> {
+        let ip_version = if self.is_ipv6() { "ipv6" } else { "ipv4" };
+        let mut out = String::new();
+        for planned in self.build_plan(Effect::Creation) {
+            if planned.rule.is_command() {
+                bail!(
+                    "command not supported for Ansible: {}",
+                    shell_quote_many(&planned.cmd_args())
+                );
+            }
+            let (table, chain) = planned.rule.table_and_chain_names();
+            let mut params = vec![
+                ("ip_version", ip_version.to_string()),
+                ("table", yaml_str(&table)),
+                ("chain", yaml_str(&chain)),
+            ];
+            match planned.action {
+                AnyAction::Creation(Action::NewChain | Action::EnsureChain) => {
+                    params.push(("chain_management", "true".into()));
+                }
+                AnyAction::Creation(Action::Append) => {
+                    params.push(("action", "append".into()));
+                }
+                AnyAction::Creation(Action::Insert(num)) => {
+                    params.push(("action", "insert".into()));
+                    params.push(("rule_num", yaml_str(&num.to_string())));
+                }
+                action => bail!("action not supported for Ansible: {action:?}"),
+            }
+            for restriction in planned.rule.restrictions() {
+                params.extend(restriction_params(restriction)?);
+            }
+            params.extend(action_params(&planned.rule.rule_action_args())?);
+
+            let cmd = planned.full_cmd(self.iptables_cmd());
+            out.push_str(&format!("- name: {}\n", yaml_str(&shell_quote_many(&cmd))));
+            out.push_str("  ansible.builtin.iptables:\n");
+            for (name, value) in params {
+                out.push_str(&format!("    {name}: {value}\n"));
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iptables::{Filter, Protocol, RecreatingMode, Rule, RuleAction};
+
+    #[test]
+    fn t_unsupported() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![
+                    Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                    Restriction::SourcePorts(Negatable::Is, vec![1, 2]),
+                ],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::Owned,
+        );
+        assert_eq!(
+            iptables.to_ansible().unwrap_err().to_string(),
+            "restriction not supported for Ansible: SourcePorts(Is, [1, 2])"
+        );
+    }
+}
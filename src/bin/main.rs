@@ -1,12 +1,19 @@
-use std::io::stderr;
+use std::io::{stderr, stdout, IsTerminal};
+use std::path::PathBuf;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
-use firewall::executor::{DryExecutor, Executor, ExecutorResult, ExecutorStatus, RealExecutor};
+use firewall::dual::{DualIptablesWriter, RulePusher};
+use firewall::executor::{
+    ensure_privileged, DryExecutor, Executor, ExecutorResult, NetnsExecutor, RealExecutor, RuleId,
+};
 use firewall::iptables::{
-    Action, AnyAction, Effect, Filter, IptablesWriter, RecreatingMode, Rule, RuleAction,
+    Action, AnyAction, Effect, ExecuteOptions, Filter, IptablesWriter, Negatable, Plan, PlanEntry,
+    Protocol, RecreatingMode, Restriction, Rule, RuleAction, MULTIPORT_MAX_PORTS,
+};
+use firewall::network_interfaces::{
+    find_network_interfaces, read_interface_file, select_network_interfaces, INTERFACES_ENV_VAR,
 };
-use firewall::network_interfaces::find_network_interfaces;
 use firewall::restrictions;
 
 #[derive(clap::Parser)]
@@ -19,20 +26,188 @@ struct Args {
     #[clap(short, long)]
     verbose: bool,
 
-    /// instead of running over all auto-detected ethernet interfaces,
+    /// show how long each command took, in the verbose output
+    #[clap(long)]
+    timing: bool,
+
+    /// group the verbose output into sections with `#` headings
+    #[clap(long)]
+    sections: bool,
+
+    /// show how each command's result was interpreted (e.g.
+    /// `E[OkForDeletions]`), in the verbose output
+    #[clap(long)]
+    interpretations: bool,
+
+    /// after running, print a line with the number of commands run,
+    /// the chains they were run for and how many of them failed (for
+    /// 'status', the number of rules checked and absent)
+    #[clap(long)]
+    summary: bool,
+
+    /// 'auto' (if stderr is a terminal), 'always' or 'never': color
+    /// the lines of the verbose output by the success of the commands
+    #[clap(long, default_value = "auto")]
+    color: String,
+
+    /// 'text' (run, with `+`-prefixed lines if verbose), or 'json'
+    /// (only print the planned commands as JSON to stdout)
+    #[clap(long, default_value = "text")]
+    output: String,
+
+    /// run the commands inside this network namespace (via `ip netns
+    /// exec`)
+    #[clap(long)]
+    netns: Option<String>,
+
+    /// instead of running over all auto-detected ethernet interfaces
+    /// (or those listed in $FIREWALL_INTERFACES, comma-separated),
     /// run for these specified interfaces.
     #[clap(short, long, multiple(true))]
     interfaces: Vec<String>,
 
-    /// 'start', 'stop', or 'restart'
-    action: String,
+    /// read interfaces from this file, one per line (blank lines and
+    /// `#` comments are ignored), in addition to those given via
+    /// `--interfaces`
+    #[clap(long)]
+    interfaces_file: Option<PathBuf>,
+
+    /// '4' (iptables), '6' (ip6tables) or 'both'
+    #[clap(long, default_value = "both")]
+    family: String,
+
+    /// instead of building the rules, run the commands of this plan
+    /// as written via `--output json` (possibly on another host)
+    #[clap(long)]
+    apply_plan: Option<PathBuf>,
+
+    /// 'start', 'stop', 'restart', 'status' (report which rules
+    /// are present, failing if any are absent), or 'reset' (set the
+    /// policies to ACCEPT, then flush the tables used and delete
+    /// their custom chains, for recovery)
+    #[clap(required_unless_present("apply-plan"))]
+    action: Option<String>,
+}
+
+/// The executor for running the commands for real or, if `dry_run`,
+/// not (requiring root for the former).
+fn make_executor(dry_run: bool, netns: &Option<String>) -> Result<Box<dyn Executor<AnyAction>>> {
+    if !dry_run {
+        ensure_privileged()?;
+    }
+    Ok(match (dry_run, netns) {
+        (true, None) => Box::new(DryExecutor),
+        (true, Some(netns)) => Box::new(NetnsExecutor::new(netns, DryExecutor)?),
+        (false, None) => Box::new(RealExecutor),
+        (false, Some(netns)) => Box::new(NetnsExecutor::new(netns, RealExecutor)?),
+    })
+}
+
+/// Wraps an executor to count the commands run through it (and those
+/// that failed, whether tolerated or not) and collect the chains they
+/// were run for, for `--summary`.
+struct CountingExecutor<'e> {
+    inner: &'e mut dyn Executor<AnyAction>,
+    executed: usize,
+    failed: usize,
+    /// `table/chain`, in the order of first use.
+    chains: Vec<String>,
+}
+
+impl<'e> CountingExecutor<'e> {
+    fn new(inner: &'e mut dyn Executor<AnyAction>) -> Self {
+        CountingExecutor {
+            inner,
+            executed: 0,
+            failed: 0,
+            chains: Vec::new(),
+        }
+    }
+
+    fn count(&mut self, result: &ExecutorResult) {
+        self.executed += 1;
+        if !result.is_success() {
+            self.failed += 1;
+        }
+    }
+
+    /// The line printed for `--summary` after running `action`,
+    /// `aborted` if it stopped on an error.
+    fn summary(&self, action: &str, aborted: bool) -> String {
+        let outcome = if aborted { "aborted after" } else { "ran" };
+        let across = if self.chains.is_empty() {
+            String::new()
+        } else {
+            format!(" across {}", self.chains.join(", "))
+        };
+        format!(
+            "{action}: {outcome} {} commands{across} ({} failed)",
+            self.executed, self.failed
+        )
+    }
+}
+
+impl Executor<AnyAction> for CountingExecutor<'_> {
+    fn execute<'t>(&mut self, context: AnyAction, cmd: &'t [String]) -> ExecutorResult<'t> {
+        let result = self.inner.execute(context, cmd);
+        self.count(&result);
+        result
+    }
+
+    fn execute_for_rule<'t>(
+        &mut self,
+        context: AnyAction,
+        rule: &RuleId,
+        cmd: &'t [String],
+    ) -> ExecutorResult<'t> {
+        let result = self.inner.execute_for_rule(context, rule, cmd);
+        self.count(&result);
+        // Commands (as pushed via `push_command`) have no chain.
+        if !rule.table.is_empty() {
+            let name = format!("{}/{}", rule.table, rule.chain);
+            if !self.chains.contains(&name) {
+                self.chains.push(name);
+            }
+        }
+        result
+    }
+
+    fn read_output(&mut self, context: AnyAction, cmd: &[String]) -> Result<String> {
+        self.inner.read_output(context, cmd)
+    }
+}
+
+/// Let tcp traffic to the given ports on `interface` pass, using
+/// multiport matches when more than one port is given.
+fn allow_ports(iptables: &mut impl RulePusher, chain: &Filter, interface: &str, ports: &[u16]) {
+    for ports in ports.chunks(MULTIPORT_MAX_PORTS) {
+        iptables.push_rule(
+            Action::Append,
+            Rule {
+                chain: chain.clone(),
+                restrictions: vec![
+                    Restriction::Interface(Negatable::Is, interface.into()),
+                    Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                    Restriction::destination_ports(Negatable::Is, ports),
+                ],
+                rule_action: RuleAction::Return,
+            },
+            RecreatingMode::Owned,
+        )
+    }
 }
 
+/// The example ruleset, for `ip6tables`.
+#[cfg(test)]
 fn example(interfaces: Vec<String>) -> IptablesWriter {
-    let mut iptables = IptablesWriter::new(vec!["ip6tables".into()]);
+    example_into(IptablesWriter::new(vec!["ip6tables".into()]), interfaces)
+}
+
+/// Push the example ruleset into `iptables`.
+fn example_into<W: RulePusher>(mut iptables: W, interfaces: Vec<String>) -> W {
     let our_chain = Filter::Custom("our-chain".into());
 
-    iptables.push(
+    iptables.push_rule(
         Action::NewChain,
         Rule {
             chain: our_chain.clone(),
@@ -43,7 +218,7 @@ fn example(interfaces: Vec<String>) -> IptablesWriter {
     );
 
     for chain in [Filter::INPUT, Filter::FORWARD] {
-        iptables.push(
+        iptables.push_rule(
             Action::Insert(1),
             Rule {
                 chain: chain.clone(),
@@ -55,26 +230,12 @@ fn example(interfaces: Vec<String>) -> IptablesWriter {
     }
 
     for interface in interfaces {
-        for port in [22, 80, 9080] {
-            iptables.push(
-                Action::Append,
-                Rule {
-                    chain: our_chain.clone(),
-                    restrictions: restrictions![
-                        Interface(Is, interface.clone()),
-                        Protocol(Is, Tcp),
-                        DestinationPort(Is, port),
-                    ],
-                    rule_action: RuleAction::Return,
-                },
-                RecreatingMode::Owned,
-            )
-        }
-        iptables.push(
+        allow_ports(&mut iptables, &our_chain, &interface, &[22, 80, 9080]);
+        iptables.push_rule(
             Action::Append,
             Rule {
                 chain: our_chain.clone(),
-                restrictions: restrictions![Interface(Is, interface.clone()),],
+                restrictions: restrictions![Interface(Is, Exact(interface.clone())),],
                 rule_action: RuleAction::Reject,
             },
             RecreatingMode::Owned,
@@ -87,57 +248,158 @@ fn example(interfaces: Vec<String>) -> IptablesWriter {
 fn main() -> Result<()> {
     let args: Args = Args::parse();
 
-    let want = match &*args.action {
-        "start" | "restart" => Effect::Recreation,
-        "stop" => Effect::Deletion,
-        _ => bail!("invalid action {:?}", args.action),
-    };
+    if let Some(path) = &args.apply_plan {
+        let json = std::fs::read_to_string(path).with_context(|| format!("reading {path:?}"))?;
+        let plan = Plan::from_json(&json).with_context(|| format!("loading plan {path:?}"))?;
+        let mut executor = make_executor(args.dry_run, &args.netns)?;
+        let verbose = args.dry_run || args.verbose;
+        let verbose_output = if verbose { Some(stderr()) } else { None };
+        return plan.execute(verbose_output, &mut *executor);
+    }
 
-    let interfaces = if args.interfaces.is_empty() {
-        find_network_interfaces()?
-    } else {
-        args.interfaces
+    let action = args.action.as_deref().unwrap_or_default();
+    let want = match action {
+        "start" | "restart" => Some(Effect::Recreation),
+        "stop" => Some(Effect::Deletion),
+        "reset" => Some(Effect::Creation),
+        "status" => None,
+        _ => bail!("invalid action {action:?}"),
+    };
+    let color = match &*args.color {
+        "auto" => stderr().is_terminal(),
+        "always" => true,
+        "never" => false,
+        _ => bail!("invalid color mode {:?}", args.color),
+    };
+    let json = match &*args.output {
+        "text" => false,
+        "json" => true,
+        _ => bail!("invalid output format {:?}", args.output),
     };
 
-    let mut executor: Box<dyn Executor<AnyAction>> = if args.dry_run {
-        Box::new(DryExecutor)
+    let mut given_interfaces = args.interfaces;
+    if let Some(path) = &args.interfaces_file {
+        for interface in read_interface_file(path)? {
+            if !given_interfaces.contains(&interface) {
+                given_interfaces.push(interface);
+            }
+        }
+    }
+    let interfaces = select_network_interfaces(
+        given_interfaces,
+        std::env::var(INTERFACES_ENV_VAR).ok(),
+        find_network_interfaces,
+    )?;
+
+    let writers: Vec<IptablesWriter> = match &*args.family {
+        "4" => vec![example_into(
+            IptablesWriter::new(vec!["iptables".into()]),
+            interfaces,
+        )],
+        "6" => vec![example_into(
+            IptablesWriter::new(vec!["ip6tables".into()]),
+            interfaces,
+        )],
+        "both" => {
+            let DualIptablesWriter { v4, v6 } = example_into(DualIptablesWriter::new(), interfaces);
+            vec![v4, v6]
+        }
+        _ => bail!("invalid family {:?}", args.family),
+    };
+    let writers = if action == "reset" {
+        writers.iter().map(IptablesWriter::flush_all).collect()
     } else {
-        Box::new(RealExecutor)
+        writers
     };
-    let verbose = args.dry_run || args.verbose;
-    let verbose_output = if verbose { Some(stderr()) } else { None };
-    example(interfaces).execute(want, verbose_output, &mut *executor)
-}
 
-// =============================================================================
-// Tests that want to be based on the above `example` rules:
+    if json {
+        let want = want.unwrap_or(Effect::Creation);
+        let mut entries: Vec<PlanEntry> = writers
+            .iter()
+            .flat_map(|writer| writer.plan_entries(want))
+            .collect();
+        for (i, entry) in entries.iter_mut().enumerate() {
+            entry.order = i + 1;
+        }
+        serde_json::to_writer_pretty(stdout(), &Plan::new(entries))?;
+        println!();
+        return Ok(());
+    }
 
-struct MockExecutor(Vec<(&'static str, ExecutorStatus, String)>);
-
-impl Executor<AnyAction> for MockExecutor {
-    fn execute<'t>(&mut self, _action: AnyAction, cmd: &'t [String]) -> ExecutorResult<'t> {
-        for (arg, status, output) in &self.0 {
-            if cmd.contains(&String::from(*arg)) {
-                return ExecutorResult {
-                    cmd,
-                    status: status.clone(),
-                    combined_output: output.clone(),
-                };
+    for writer in &writers {
+        for warning in writer.warnings() {
+            eprintln!("warning: {warning}");
+        }
+    }
+    let mut executor = make_executor(args.dry_run, &args.netns)?;
+    let Some(want) = want else {
+        let mut statuses = Vec::new();
+        for writer in &writers {
+            statuses.append(&mut writer.status(&mut *executor)?);
+        }
+        let absent = statuses.iter().filter(|s| !s.present).count();
+        for status in &statuses {
+            println!("{status}");
+        }
+        if args.summary {
+            println!("status: checked {} rules ({absent} absent)", statuses.len());
+        }
+        if absent > 0 {
+            bail!("{absent} of {} rules are absent", statuses.len());
+        }
+        return Ok(());
+    };
+    let verbose = args.dry_run || args.verbose;
+    let mut verbose_output = if verbose { Some(stderr()) } else { None };
+    let options = ExecuteOptions {
+        sections: args.sections,
+        interpretations: args.interpretations,
+        color,
+        ..Default::default()
+    };
+    let mut executor = CountingExecutor::new(&mut *executor);
+    let mut result = Ok(());
+    for writer in writers {
+        if !verbose {
+            match writer.skipped_commands(want) {
+                Ok(skipped) => {
+                    for (cmd, reason) in skipped {
+                        eprintln!("warning: skipping `{cmd}`: {reason}");
+                    }
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
             }
         }
-        ExecutorResult {
-            cmd,
-            status: ExecutorStatus::Success,
-            combined_output: "".into(),
+        result = writer.with_timing(args.timing).execute_with(
+            want,
+            verbose_output.as_mut(),
+            &mut executor,
+            &options,
+        );
+        if result.is_err() {
+            break;
         }
     }
+    if args.summary {
+        println!("{}", executor.summary(action, result.is_err()));
+    }
+    Ok(result?)
 }
 
+// =============================================================================
+// Tests that want to be based on the above `example` rules:
+
+#[cfg(test)]
+use firewall::executor::{ExecutorStatus, ScriptedDryExecutor};
+
 #[test]
 fn verify_error_mode() {
     use indoc::indoc;
 
-    let run = |mut executor: MockExecutor| -> Result<String> {
+    let run = |mut executor: ScriptedDryExecutor| -> Result<String> {
         let iptables = example(vec!["eth42".into()]);
         let mut output = Vec::new();
         iptables.execute(Effect::Recreation, Some(&mut output), &mut executor)?;
@@ -145,17 +407,12 @@ fn verify_error_mode() {
     };
 
     assert_eq!(
-        run(MockExecutor(vec![
-            // code 1 would happen if the chain didn't exist (OK, -F
-            // should also fail, then)
-            ("-X", ExecutorStatus::ExitCode(1), "".into()),
-        ]))
-        .unwrap(),
+        // code 1 would happen if the chain didn't exist (OK, -F
+        // should also fail, then)
+        run(ScriptedDryExecutor::new().on("-X", ExecutorStatus::ExitCode(1), "")).unwrap(),
         indoc! {"
             + ip6tables -t filter -D our-chain -i eth42 -j REJECT
-            + ip6tables -t filter -D our-chain -i eth42 -p tcp --dport 9080 -j RETURN
-            + ip6tables -t filter -D our-chain -i eth42 -p tcp --dport 80 -j RETURN
-            + ip6tables -t filter -D our-chain -i eth42 -p tcp --dport 22 -j RETURN
+            + ip6tables -t filter -D our-chain -i eth42 -p tcp -m multiport --dports 22,80,9080 -j RETURN
             + ip6tables -t filter -D FORWARD -j our-chain
             + ip6tables -t filter -D INPUT -j our-chain
             + ip6tables -t filter -F our-chain
@@ -163,23 +420,19 @@ fn verify_error_mode() {
             + ip6tables -t filter -N our-chain
             + ip6tables -t filter -I INPUT 1 -j our-chain
             + ip6tables -t filter -I FORWARD 1 -j our-chain
-            + ip6tables -t filter -A our-chain -i eth42 -p tcp --dport 22 -j RETURN
-            + ip6tables -t filter -A our-chain -i eth42 -p tcp --dport 80 -j RETURN
-            + ip6tables -t filter -A our-chain -i eth42 -p tcp --dport 9080 -j RETURN
+            + ip6tables -t filter -A our-chain -i eth42 -p tcp -m multiport --dports 22,80,9080 -j RETURN
             + ip6tables -t filter -A our-chain -i eth42 -j REJECT
         "}
     );
 
     assert_eq!(
-        run(MockExecutor(vec![
-            // code 4 happens if the chain can't be removed because it
-            // is still "in use", but in this case iptables also
-            // prints a message saying so. We're not giving that
-            // message so we expect an error.
-            ("-X", ExecutorStatus::ExitCode(4), "".into()),
-        ]))
-        .unwrap_err()
-        .to_string(),
+        // code 4 happens if the chain can't be removed because it
+        // is still "in use", but in this case iptables also
+        // prints a message saying so. We're not giving that
+        // message so we expect an error.
+        run(ScriptedDryExecutor::new().on("-X", ExecutorStatus::ExitCode(4), ""))
+            .unwrap_err()
+            .to_string(),
         "command `ip6tables -t filter -X our-chain` exited with code 4: "
     );
 
@@ -187,17 +440,15 @@ fn verify_error_mode() {
     // do not simulate the subsequent error on the -N action--for that
     // see the next test.
     assert_eq!(
-        run(MockExecutor(vec![(
+        run(ScriptedDryExecutor::new().on(
             "-X",
             ExecutorStatus::ExitCode(4),
-            ".. CHAIN_DEL failed (Device or resource busy) ..".into()
-        ),]))
+            ".. CHAIN_DEL failed (Device or resource busy) .."
+        ))
         .unwrap(),
         indoc! {"
             + ip6tables -t filter -D our-chain -i eth42 -j REJECT
-            + ip6tables -t filter -D our-chain -i eth42 -p tcp --dport 9080 -j RETURN
-            + ip6tables -t filter -D our-chain -i eth42 -p tcp --dport 80 -j RETURN
-            + ip6tables -t filter -D our-chain -i eth42 -p tcp --dport 22 -j RETURN
+            + ip6tables -t filter -D our-chain -i eth42 -p tcp -m multiport --dports 22,80,9080 -j RETURN
             + ip6tables -t filter -D FORWARD -j our-chain
             + ip6tables -t filter -D INPUT -j our-chain
             + ip6tables -t filter -F our-chain
@@ -205,9 +456,7 @@ fn verify_error_mode() {
             + ip6tables -t filter -N our-chain
             + ip6tables -t filter -I INPUT 1 -j our-chain
             + ip6tables -t filter -I FORWARD 1 -j our-chain
-            + ip6tables -t filter -A our-chain -i eth42 -p tcp --dport 22 -j RETURN
-            + ip6tables -t filter -A our-chain -i eth42 -p tcp --dport 80 -j RETURN
-            + ip6tables -t filter -A our-chain -i eth42 -p tcp --dport 9080 -j RETURN
+            + ip6tables -t filter -A our-chain -i eth42 -p tcp -m multiport --dports 22,80,9080 -j RETURN
             + ip6tables -t filter -A our-chain -i eth42 -j REJECT
         "}
     );
@@ -215,24 +464,21 @@ fn verify_error_mode() {
     // Simulate chain deletion failure because of it being busy,
     // including subsequent error on -N action.
     assert_eq!(
-        run(MockExecutor(vec![
-            (
+        run(ScriptedDryExecutor::new()
+            .on(
                 "-X",
                 ExecutorStatus::ExitCode(4),
-                ".. CHAIN_DEL failed (Device or resource busy) ..".into()
-            ),
-            (
+                ".. CHAIN_DEL failed (Device or resource busy) .."
+            )
+            .on(
                 "-N",
                 ExecutorStatus::ExitCode(1),
-                "iptables: Chain already exists.".into()
-            )
-        ]))
+                "iptables: Chain already exists."
+            ))
         .unwrap(),
         indoc! {"
             + ip6tables -t filter -D our-chain -i eth42 -j REJECT
-            + ip6tables -t filter -D our-chain -i eth42 -p tcp --dport 9080 -j RETURN
-            + ip6tables -t filter -D our-chain -i eth42 -p tcp --dport 80 -j RETURN
-            + ip6tables -t filter -D our-chain -i eth42 -p tcp --dport 22 -j RETURN
+            + ip6tables -t filter -D our-chain -i eth42 -p tcp -m multiport --dports 22,80,9080 -j RETURN
             + ip6tables -t filter -D FORWARD -j our-chain
             + ip6tables -t filter -D INPUT -j our-chain
             + ip6tables -t filter -F our-chain
@@ -240,9 +486,7 @@ fn verify_error_mode() {
             E ip6tables -t filter -N our-chain
             + ip6tables -t filter -I INPUT 1 -j our-chain
             + ip6tables -t filter -I FORWARD 1 -j our-chain
-            + ip6tables -t filter -A our-chain -i eth42 -p tcp --dport 22 -j RETURN
-            + ip6tables -t filter -A our-chain -i eth42 -p tcp --dport 80 -j RETURN
-            + ip6tables -t filter -A our-chain -i eth42 -p tcp --dport 9080 -j RETURN
+            + ip6tables -t filter -A our-chain -i eth42 -p tcp -m multiport --dports 22,80,9080 -j RETURN
             + ip6tables -t filter -A our-chain -i eth42 -j REJECT
         "}
     );
@@ -301,18 +545,13 @@ fn verify_try_creation_mode_with_chains() {
             recreating_mode,
         );
         let mut output = Vec::new();
-        let mut executor = MockExecutor(vec![
-            (
-                "-N",
-                ExecutorStatus::ExitCode(1),
-                "Chain already exists".into(),
-            ),
-            (
+        let mut executor = ScriptedDryExecutor::new()
+            .on("-N", ExecutorStatus::ExitCode(1), "Chain already exists")
+            .on(
                 "-X",
                 ExecutorStatus::ExitCode(1),
-                "No chain/target/match by that name".into(),
-            ),
-        ]);
+                "No chain/target/match by that name",
+            );
         iptables.execute(Effect::Recreation, Some(&mut output), &mut executor)?;
         Ok(String::from_utf8(output).unwrap())
     };
@@ -367,18 +606,17 @@ fn verify_try_creation_mode_with_rules() {
             recreating_mode,
         );
         let mut output = Vec::new();
-        let mut executor = MockExecutor(vec![
-            (
+        let mut executor = ScriptedDryExecutor::new()
+            .on(
                 "-A",
                 ExecutorStatus::ExitCode(1),
-                "iptables: No chain/target/match by that name.".into(),
-            ),
-            (
+                "iptables: No chain/target/match by that name.",
+            )
+            .on(
                 "-D",
                 ExecutorStatus::ExitCode(1),
-                "iptables: Bad rule (does a matching rule exist in that chain?).".into(),
-            ),
-        ]);
+                "iptables: Bad rule (does a matching rule exist in that chain?).",
+            );
         iptables.execute(Effect::Recreation, Some(&mut output), &mut executor)?;
         Ok(String::from_utf8(output).unwrap())
     };
@@ -403,3 +641,362 @@ fn verify_try_creation_mode_with_rules() {
         "E ip6tables -t filter -A SOMEONE-ELSES-CHAIN\n"
     );
 }
+
+#[test]
+fn test_teardown_script() {
+    use indoc::indoc;
+
+    let script = example(vec!["eth42".into()]).teardown_script();
+    assert_eq!(
+        script,
+        example(vec!["eth42".into()]).to_script(Effect::Deletion)
+    );
+    assert_eq!(
+        script,
+        indoc! {"
+            #!/bin/bash
+            set -euo pipefail
+            ip6tables -t filter -D our-chain -i eth42 -j REJECT || true
+            ip6tables -t filter -D our-chain -i eth42 -p tcp -m multiport --dports 22,80,9080 -j RETURN || true
+            ip6tables -t filter -D FORWARD -j our-chain || true
+            ip6tables -t filter -D INPUT -j our-chain || true
+            ip6tables -t filter -F our-chain || true
+            ip6tables -t filter -X our-chain || true
+        "}
+    );
+}
+
+#[test]
+fn test_inverse_plan() {
+    let iptables = example(vec!["eth42".into()]);
+    let inverse: Vec<String> = iptables
+        .inverse_plan()
+        .iter()
+        .map(|p| format!("+ ip6tables {}", p.cmd_args().join(" ")))
+        .collect();
+    let mut output = Vec::new();
+    iptables
+        .execute(Effect::Recreation, Some(&mut output), &mut DryExecutor)
+        .unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(inverse, lines[..inverse.len()]);
+    assert_eq!(
+        inverse.len(),
+        lines.len() - iptables.build_plan(Effect::Creation).len()
+    );
+}
+
+#[test]
+fn test_to_csv() {
+    let csv = example(vec!["eth42".into()]).to_csv(Effect::Creation);
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines[0], "order,table,chain,action,match,target");
+    assert_eq!(lines[1], "1,filter,our-chain,newchain,,");
+    assert_eq!(lines[2], "2,filter,INPUT,insert 1,,-j our-chain");
+    assert_eq!(
+        lines[4],
+        "4,filter,our-chain,append,\"-i eth42 -p tcp -m multiport --dports 22,80,9080\",-j RETURN"
+    );
+    assert_eq!(lines.len(), 6);
+
+    let csv = example(vec!["eth42".into()]).to_csv(Effect::Deletion);
+    assert_eq!(
+        csv.lines().nth(1).unwrap(),
+        "1,filter,our-chain,delete,-i eth42,-j REJECT"
+    );
+}
+
+#[test]
+fn test_output_sections() {
+    use indoc::indoc;
+
+    let iptables = example(vec!["eth0".into(), "eth1".into()]);
+    let mut output = Vec::new();
+    iptables
+        .execute_with(
+            Effect::Creation,
+            Some(&mut output),
+            &mut DryExecutor,
+            &ExecuteOptions {
+                sections: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        indoc! {"
+            # chain setup
+            + ip6tables -t filter -N our-chain
+
+            # jumps
+            + ip6tables -t filter -I INPUT 1 -j our-chain
+            + ip6tables -t filter -I FORWARD 1 -j our-chain
+
+            # eth0 rules
+            + ip6tables -t filter -A our-chain -i eth0 -p tcp -m multiport --dports 22,80,9080 -j RETURN
+            + ip6tables -t filter -A our-chain -i eth0 -j REJECT
+
+            # eth1 rules
+            + ip6tables -t filter -A our-chain -i eth1 -p tcp -m multiport --dports 22,80,9080 -j RETURN
+            + ip6tables -t filter -A our-chain -i eth1 -j REJECT
+        "}
+    );
+}
+
+#[test]
+fn test_to_ansible() {
+    use indoc::indoc;
+
+    assert_eq!(
+        example(vec!["eth42".into()]).to_ansible().unwrap(),
+        indoc! {r#"
+            - name: "ip6tables -t filter -N our-chain"
+              ansible.builtin.iptables:
+                ip_version: ipv6
+                table: "filter"
+                chain: "our-chain"
+                chain_management: true
+            - name: "ip6tables -t filter -I INPUT 1 -j our-chain"
+              ansible.builtin.iptables:
+                ip_version: ipv6
+                table: "filter"
+                chain: "INPUT"
+                action: insert
+                rule_num: "1"
+                jump: "our-chain"
+            - name: "ip6tables -t filter -I FORWARD 1 -j our-chain"
+              ansible.builtin.iptables:
+                ip_version: ipv6
+                table: "filter"
+                chain: "FORWARD"
+                action: insert
+                rule_num: "1"
+                jump: "our-chain"
+            - name: "ip6tables -t filter -A our-chain -i eth42 -p tcp -m multiport --dports 22,80,9080 -j RETURN"
+              ansible.builtin.iptables:
+                ip_version: ipv6
+                table: "filter"
+                chain: "our-chain"
+                action: append
+                in_interface: "eth42"
+                protocol: "tcp"
+                destination_ports: ["22","80","9080"]
+                jump: "RETURN"
+            - name: "ip6tables -t filter -A our-chain -i eth42 -j REJECT"
+              ansible.builtin.iptables:
+                ip_version: ipv6
+                table: "filter"
+                chain: "our-chain"
+                action: append
+                in_interface: "eth42"
+                jump: "REJECT"
+        "#}
+    );
+}
+
+#[test]
+fn test_allow_ports() {
+    let plan = |ports: &[u16]| -> Vec<String> {
+        let mut iptables = IptablesWriter::new(vec!["ip6tables".into()]);
+        allow_ports(&mut iptables, &Filter::INPUT, "eth0", ports);
+        iptables
+            .build_plan(Effect::Creation)
+            .iter()
+            .map(|p| p.cmd_args().join(" "))
+            .collect()
+    };
+    let per_port: Vec<String> = [22, 80, 9080]
+        .iter()
+        .flat_map(|port| plan(&[*port]))
+        .collect();
+    assert_eq!(
+        per_port,
+        [
+            "-t filter -A INPUT -i eth0 -p tcp --dport 22 -j RETURN",
+            "-t filter -A INPUT -i eth0 -p tcp --dport 80 -j RETURN",
+            "-t filter -A INPUT -i eth0 -p tcp --dport 9080 -j RETURN",
+        ]
+    );
+    assert_eq!(
+        plan(&[22, 80, 9080]),
+        ["-t filter -A INPUT -i eth0 -p tcp -m multiport --dports 22,80,9080 -j RETURN"]
+    );
+    let many: Vec<u16> = (1..=20).collect();
+    assert_eq!(plan(&many).len(), 2);
+}
+
+#[test]
+fn test_affected_tablechains() {
+    use firewall::iptables::Table;
+
+    assert_eq!(
+        example(vec!["eth0".into(), "eth1".into()]).affected_tablechains(),
+        [
+            (Table::Filter, "our-chain".into()),
+            (Table::Filter, "INPUT".into()),
+            (Table::Filter, "FORWARD".into()),
+        ]
+    );
+}
+
+#[test]
+fn test_check() {
+    use firewall::fake_iptables::FakeIptables;
+    use firewall::iptables::DeletionAction;
+
+    let iptables = example(vec!["eth0".into()]);
+    let mut fake = FakeIptables::new();
+    assert!(iptables
+        .check(&mut fake, true)
+        .unwrap_err()
+        .to_string()
+        .starts_with("4 of 4 rules are absent: "));
+
+    iptables
+        .execute(Effect::Creation, None::<Vec<u8>>, &mut fake)
+        .unwrap();
+    // Remove the REJECT rule
+    let reject: Vec<String> = "ip6tables -t filter -D our-chain -i eth0 -j REJECT"
+        .split(' ')
+        .map(String::from)
+        .collect();
+    assert!(fake
+        .execute(AnyAction::Deletion(DeletionAction::Delete), &reject)
+        .is_success());
+
+    let statuses = iptables.check(&mut fake, false).unwrap();
+    assert_eq!(
+        statuses.iter().map(|s| s.present).collect::<Vec<_>>(),
+        [true, true, true, false]
+    );
+    assert_eq!(
+        iptables.check(&mut fake, true).unwrap_err().to_string(),
+        "1 of 4 rules are absent: ip6tables -t filter -C our-chain -i eth0 -j REJECT"
+    );
+}
+
+#[test]
+fn test_restrictions_not() {
+    assert_eq!(
+        restrictions![
+            not!(Interface(Is, Exact("eth0".into()))),
+            Protocol(Is, Tcp),
+            not!(SourcePort(IsNot, 22)),
+        ],
+        vec![
+            Restriction::Interface(
+                Negatable::IsNot,
+                firewall::iptables::InterfaceSpec::Exact("eth0".into())
+            ),
+            Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+            Restriction::SourcePort(Negatable::Is, 22),
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "not!: Limit(\"5/minute\".into()) can't be negated")]
+fn test_restrictions_not_unnegatable() {
+    restrictions![not!(Limit("5/minute".into()))];
+}
+
+#[test]
+fn test_plan_summary() {
+    use firewall::iptables::{PlanSummary, Table};
+
+    let iptables = example(vec!["eth0".into(), "eth1".into()]);
+    let chain = |name: &str| (Table::Filter, name.to_string());
+    assert_eq!(
+        iptables.plan_summary(),
+        PlanSummary {
+            rules_per_chain: vec![
+                (chain("INPUT"), 1),
+                (chain("FORWARD"), 1),
+                (chain("our-chain"), 4)
+            ],
+            interfaces: 2,
+            // RETURN and REJECT for each interface
+            terminating: 4,
+            // the jumps to our-chain
+            non_terminating: 2,
+        }
+    );
+}
+
+#[test]
+fn test_execute_filtered() {
+    use firewall::iptables::Table;
+    use indoc::indoc;
+
+    let iptables = example(vec!["eth42".into()]);
+    let mut output = Vec::new();
+    iptables
+        .execute_filtered(
+            Effect::Recreation,
+            |(table, chain)| *table == Table::Filter && chain == "our-chain",
+            Some(&mut output),
+            &mut DryExecutor,
+        )
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        indoc! {"
+            + ip6tables -t filter -D our-chain -i eth42 -j REJECT
+            + ip6tables -t filter -D our-chain -i eth42 -p tcp -m multiport --dports 22,80,9080 -j RETURN
+            + ip6tables -t filter -F our-chain
+            + ip6tables -t filter -X our-chain
+            + ip6tables -t filter -N our-chain
+            + ip6tables -t filter -A our-chain -i eth42 -p tcp -m multiport --dports 22,80,9080 -j RETURN
+            + ip6tables -t filter -A our-chain -i eth42 -j REJECT
+        "}
+    );
+}
+
+#[test]
+fn test_flush_all() {
+    use indoc::indoc;
+
+    let reset = example(vec!["eth0".into()]).flush_all();
+    let mut output = Vec::new();
+    for _ in 0..2 {
+        reset
+            .execute(Effect::Creation, Some(&mut output), &mut DryExecutor)
+            .unwrap();
+    }
+    let once = indoc! {"
+        + ip6tables -t filter -P INPUT ACCEPT
+        + ip6tables -t filter -P FORWARD ACCEPT
+        + ip6tables -t filter -P OUTPUT ACCEPT
+        + ip6tables -t filter -F
+        + ip6tables -t filter -X
+    "};
+    assert_eq!(String::from_utf8(output).unwrap(), once.repeat(2));
+}
+
+#[test]
+fn test_summary() {
+    let run = |mut executor: ScriptedDryExecutor| {
+        let iptables = example(vec!["eth42".into()]);
+        let mut executor = CountingExecutor::new(&mut executor);
+        let result = iptables.execute(Effect::Recreation, None::<Vec<u8>>, &mut executor);
+        executor.summary("start", result.is_err())
+    };
+
+    assert_eq!(
+        run(ScriptedDryExecutor::new()),
+        "start: ran 11 commands across filter/our-chain, filter/FORWARD, filter/INPUT (0 failed)"
+    );
+    // A tolerated failure (the chain didn't exist) is counted, but
+    // doesn't abort.
+    assert_eq!(
+        run(ScriptedDryExecutor::new().on("-X", ExecutorStatus::ExitCode(1), "")),
+        "start: ran 11 commands across filter/our-chain, filter/FORWARD, filter/INPUT (1 failed)"
+    );
+    assert_eq!(
+        run(ScriptedDryExecutor::new().on("-X", ExecutorStatus::ExitCode(4), "")),
+        "start: aborted after 6 commands across filter/our-chain, filter/FORWARD, filter/INPUT \
+         (1 failed)"
+    );
+}
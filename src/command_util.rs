@@ -1,11 +1,22 @@
 use std::process::Output;
 
 pub trait CombinedString {
-    fn combined_string(&self) -> String;
+    /// stdout and stderr (those that aren't empty) joined with `sep`.
+    fn combined_string_with(&self, sep: &str) -> String;
+
+    /// stdout and stderr joined with " / ".
+    fn combined_string(&self) -> String {
+        self.combined_string_with(" / ")
+    }
+
+    /// Like `combined_string`, but with the sections labeled, e.g.
+    /// "stdout: foo; stderr: bar", to make clear where each part came
+    /// from.
+    fn labeled_combined_string(&self) -> String;
 }
 
 impl CombinedString for Output {
-    fn combined_string(&self) -> String {
+    fn combined_string_with(&self, sep: &str) -> String {
         let mut outs = Vec::new();
         let mut add = |vec: &Vec<u8>| {
             if !vec.is_empty() {
@@ -14,6 +25,46 @@ impl CombinedString for Output {
         };
         add(&self.stdout);
         add(&self.stderr);
-        outs.join(" / ")
+        outs.join(sep)
+    }
+
+    fn labeled_combined_string(&self) -> String {
+        let mut outs = Vec::new();
+        let mut add = |label: &str, vec: &Vec<u8>| {
+            if !vec.is_empty() {
+                outs.push(format!("{label}: {}", String::from_utf8_lossy(vec)));
+            }
+        };
+        add("stdout", &self.stdout);
+        add("stderr", &self.stderr);
+        outs.join("; ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    fn output(stdout: &str, stderr: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(0),
+            stdout: stdout.into(),
+            stderr: stderr.into(),
+        }
+    }
+
+    #[test]
+    fn t_combined_string() {
+        assert_eq!(output("a/b", "c").combined_string(), "a/b / c");
+        assert_eq!(output("", "c").combined_string(), "c");
+        assert_eq!(output("a", "c").combined_string_with("\n"), "a\nc");
+        assert_eq!(
+            output("a/b", "c").labeled_combined_string(),
+            "stdout: a/b; stderr: c"
+        );
+        assert_eq!(output("", "c").labeled_combined_string(), "stderr: c");
+        assert_eq!(output("", "").labeled_combined_string(), "");
     }
 }
@@ -0,0 +1,32 @@
+//! Minimal CSV field escaping (RFC 4180 style) for the tabular
+//! outputs.
+
+use std::borrow::Cow;
+
+/// Quote the field if it contains a comma, double quote or line
+/// break, doubling any double quotes inside.
+pub fn csv_escape(s: &str) -> Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        let mut out = String::new();
+        out.push('"');
+        out.push_str(&s.replace('"', "\"\""));
+        out.push('"');
+        Cow::Owned(out)
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_csv_escape() {
+        assert_eq!(csv_escape(""), "");
+        assert_eq!(csv_escape("-i eth0"), "-i eth0");
+        assert_eq!(csv_escape("1,2"), "\"1,2\"");
+        assert_eq!(csv_escape("a \"b\""), "\"a \"\"b\"\"\"");
+        assert_eq!(csv_escape("a\nb"), "\"a\nb\"");
+    }
+}
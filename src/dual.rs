@@ -0,0 +1,133 @@
+//! Writing the same rules for both IPv4 (`iptables`) and IPv6
+//! (`ip6tables`).
+
+use crate::error::FirewallError;
+use crate::executor::Executor;
+use crate::iptables::{
+    Action, AnyAction, Effect, IptablesWriter, RecreatingMode, Rule, TablechainTrait,
+};
+
+/// Something rules can be pushed to, so that rule-building code can
+/// target both `IptablesWriter` and `DualIptablesWriter`.
+pub trait RulePusher {
+    /// See `IptablesWriter::push`.
+    fn push_rule<T: TablechainTrait + 'static>(
+        &mut self,
+        action: Action,
+        rule: Rule<T>,
+        recreating_mode: RecreatingMode,
+    );
+}
+
+impl RulePusher for IptablesWriter {
+    #[track_caller]
+    fn push_rule<T: TablechainTrait + 'static>(
+        &mut self,
+        action: Action,
+        rule: Rule<T>,
+        recreating_mode: RecreatingMode,
+    ) {
+        self.push(action, rule, recreating_mode)
+    }
+}
+
+/// Two writers, for `iptables` and `ip6tables`, receiving the same
+/// rules. (Rules with address-family specific restrictions, like IPv4
+/// addresses, should be pushed to `v4` or `v6` directly.)
+#[derive(Debug, Clone)]
+pub struct DualIptablesWriter {
+    pub v4: IptablesWriter,
+    pub v6: IptablesWriter,
+}
+
+impl Default for DualIptablesWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DualIptablesWriter {
+    /// With the commands `iptables` and `ip6tables`.
+    pub fn new() -> Self {
+        Self {
+            v4: IptablesWriter::new(vec!["iptables".into()]),
+            v6: IptablesWriter::new(vec!["ip6tables".into()]),
+        }
+    }
+
+    /// Push the rule to both writers.
+    #[track_caller]
+    pub fn push<T: TablechainTrait + 'static>(
+        &mut self,
+        action: Action,
+        rule: Rule<T>,
+        recreating_mode: RecreatingMode,
+    ) {
+        self.v4.push(action, rule.clone(), recreating_mode);
+        self.v6.push(action, rule, recreating_mode);
+    }
+
+    /// The v4 and v6 writers, in this order.
+    pub fn writers(&self) -> [&IptablesWriter; 2] {
+        [&self.v4, &self.v6]
+    }
+
+    /// `IptablesWriter::execute` for v4, then for v6 (only if v4
+    /// succeeded).
+    pub fn execute<O: std::io::Write>(
+        &self,
+        want: Effect,
+        mut verbose_output: Option<O>,
+        executor: &mut dyn Executor<AnyAction>,
+    ) -> Result<(), FirewallError> {
+        for writer in self.writers() {
+            writer.execute(want, verbose_output.as_mut(), executor)?;
+        }
+        Ok(())
+    }
+}
+
+impl RulePusher for DualIptablesWriter {
+    #[track_caller]
+    fn push_rule<T: TablechainTrait + 'static>(
+        &mut self,
+        action: Action,
+        rule: Rule<T>,
+        recreating_mode: RecreatingMode,
+    ) {
+        self.push(action, rule, recreating_mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_iptables::FakeIptables;
+    use crate::iptables::{Filter, RuleAction};
+
+    #[test]
+    fn t_dual_writer() {
+        let mut dual = DualIptablesWriter::new();
+        dual.push_rule(
+            Action::Append,
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![],
+                rule_action: RuleAction::Drop,
+            },
+            RecreatingMode::Owned,
+        );
+        let mut output = Vec::new();
+        dual.execute(
+            Effect::Creation,
+            Some(&mut output),
+            &mut FakeIptables::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "+ iptables -t filter -A INPUT -j DROP\n\
+             + ip6tables -t filter -A INPUT -j DROP\n"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Typed errors for running rule plans, so that callers can
+//! distinguish e.g. a chain being in use from a failure to start
+//! iptables. They convert into `anyhow::Error` via `?`.
+
+use thiserror::Error;
+
+use crate::executor::{ExecutorResult, ExecutorStatus};
+use crate::iptables::AnyAction;
+use crate::shell_quote::shell_quote_many;
+
+#[derive(Error, Debug)]
+pub enum FirewallError {
+    /// The command could not be started at all.
+    #[error("command `{cmd}` could not be started: {message}")]
+    ExecFailure { cmd: String, message: String },
+
+    /// The command exited with an error code not covered by the more
+    /// specific variants.
+    #[error("command `{cmd}` exited with code {code}: {output}")]
+    ExitCode {
+        cmd: String,
+        code: i32,
+        output: String,
+    },
+
+    #[error("command `{cmd}` was killed by signal {signal}: {output}")]
+    Signal {
+        cmd: String,
+        signal: i32,
+        output: String,
+    },
+
+    /// A chain or rule that the action refers to doesn't exist (fine
+    /// for deletions, but not for creating actions).
+    #[error(
+        "command `{cmd}` exited with code {code} for non-deleting action {action:?}: {output}"
+    )]
+    NotFound {
+        cmd: String,
+        code: i32,
+        action: AnyAction,
+        output: String,
+    },
+
+    /// A chain couldn't be deleted because it is still referenced.
+    #[error(
+        "command `{cmd}` exited with code {code} because chain is in use, \
+         for non-deleting action {action:?}: {output}"
+    )]
+    ChainInUse {
+        cmd: String,
+        code: i32,
+        action: AnyAction,
+        output: String,
+    },
+
+    /// iptables reported that a chain already exists for an action
+    /// that isn't chain creation.
+    #[error(
+        "command `{cmd}` exited with code {code}: got 'chain already exists' error \
+         even though action is not chain creation, but {action:?}"
+    )]
+    ChainAlreadyExists {
+        cmd: String,
+        code: i32,
+        action: AnyAction,
+        output: String,
+    },
+
+    /// The existing interfaces couldn't be determined (for
+    /// `IptablesWriter::with_skip_missing_interfaces`).
+    #[error("looking up network interfaces: {0}")]
+    InterfaceLookup(String),
+
+    /// More rules were pushed than allowed via
+    /// `IptablesWriter::with_max_rules`.
+    #[error("{count} rules were pushed, more than the maximum of {max}")]
+    TooManyRules { count: usize, max: usize },
+
+    /// A non-creating action (pushed via `IptablesWriter::_push`)
+    /// was found when executing an `Effect` that deletes, which
+    /// can't derive its inverse.
+    #[error(
+        "non-creating action `{action}` for chain {chain:?} of table {table} \
+         can't be used with a deleting effect"
+    )]
+    NonCreatingAction {
+        action: AnyAction,
+        table: String,
+        chain: String,
+    },
+
+    /// Failure writing the verbose output.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// Errors from `execute_parallel`, per table name.
+    #[error("{}", .0.iter().map(|(t, e)| format!("table {t}: {e}")).collect::<Vec<_>>().join("; "))]
+    Tables(Vec<(String, FirewallError)>),
+}
+
+impl FirewallError {
+    /// The error for the status of `result` (`ExitCode`, `Signal` or
+    /// `ExecFailure`), None on success.
+    pub fn from_status(result: &ExecutorResult) -> Option<Self> {
+        let cmd = shell_quote_many(result.cmd);
+        let output = result.combined_output.clone();
+        match &result.status {
+            ExecutorStatus::Success => None,
+            ExecutorStatus::ExitCode(code) => Some(FirewallError::ExitCode {
+                cmd,
+                code: *code,
+                output,
+            }),
+            ExecutorStatus::Signal(signal) => Some(FirewallError::Signal {
+                cmd,
+                signal: *signal,
+                output,
+            }),
+            ExecutorStatus::ExecFailure(message) => Some(FirewallError::ExecFailure {
+                cmd,
+                message: message.clone(),
+            }),
+        }
+    }
+}
@@ -3,8 +3,10 @@
 
 use std::os::unix::process::ExitStatusExt;
 use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
 
-use anyhow::bail;
+use anyhow::{bail, Context};
 
 use crate::command_util::CombinedString;
 use crate::shell_quote::shell_quote_many;
@@ -88,10 +90,59 @@ impl<'t> ExecutorResult<'t> {
     }
 }
 
+/// Which pushed rule a command is being run for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleId {
+    /// The position of the rule in the order of pushing (0-based).
+    pub index: usize,
+    pub table: String,
+    pub chain: String,
+}
+
 pub trait Executor<C> {
     fn execute<'t>(&mut self, context: C, cmd: &'t [String]) -> ExecutorResult<'t>;
+
+    /// Like `execute`, but also told which rule the command is run
+    /// for, e.g. for logging. By default just calls `execute`.
+    fn execute_for_rule<'t>(
+        &mut self,
+        context: C,
+        _rule: &RuleId,
+        cmd: &'t [String],
+    ) -> ExecutorResult<'t> {
+        self.execute(context, cmd)
+    }
+
+    /// Run `cmd` for its output (e.g. `iptables-save`), failing if
+    /// it doesn't succeed. By default the combined output of
+    /// `execute`; executors that can should return only stdout.
+    fn read_output(&mut self, context: C, cmd: &[String]) -> anyhow::Result<String> {
+        let result = self.execute(context, cmd);
+        result.to_anyhow(None)?;
+        Ok(result.combined_output)
+    }
+}
+
+impl<C, E: Executor<C> + ?Sized> Executor<C> for Box<E> {
+    fn execute<'t>(&mut self, context: C, cmd: &'t [String]) -> ExecutorResult<'t> {
+        (**self).execute(context, cmd)
+    }
+
+    fn execute_for_rule<'t>(
+        &mut self,
+        context: C,
+        rule: &RuleId,
+        cmd: &'t [String],
+    ) -> ExecutorResult<'t> {
+        (**self).execute_for_rule(context, rule, cmd)
+    }
+
+    fn read_output(&mut self, context: C, cmd: &[String]) -> anyhow::Result<String> {
+        (**self).read_output(context, cmd)
+    }
 }
 
+#[derive(Clone)]
 pub struct DryExecutor;
 impl<C> Executor<C> for DryExecutor {
     fn execute<'t>(&mut self, _context: C, cmd: &'t [String]) -> ExecutorResult<'t> {
@@ -103,6 +154,136 @@ impl<C> Executor<C> for DryExecutor {
     }
 }
 
+/// Like `DryExecutor`, never running anything, but returning
+/// configured results, e.g. for simulating failures: the status and
+/// output of the first rule (see `on`) whose argument is one of the
+/// arguments of the command, or success.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedDryExecutor {
+    rules: Vec<(String, ExecutorStatus, String)>,
+}
+
+impl ScriptedDryExecutor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return `status` and `output` for the commands having `arg`
+    /// (e.g. "-X") as one of their arguments, unless matched by a
+    /// rule added earlier.
+    pub fn on(mut self, arg: &str, status: ExecutorStatus, output: &str) -> Self {
+        self.rules.push((arg.into(), status, output.into()));
+        self
+    }
+}
+
+impl<C> Executor<C> for ScriptedDryExecutor {
+    fn execute<'t>(&mut self, context: C, cmd: &'t [String]) -> ExecutorResult<'t> {
+        match self.rules.iter().find(|(arg, _, _)| cmd.contains(arg)) {
+            Some((_, status, output)) => ExecutorResult {
+                cmd,
+                status: status.clone(),
+                combined_output: output.clone(),
+            },
+            None => DryExecutor.execute(context, cmd),
+        }
+    }
+}
+
+/// Whether `cmd` only reads the state: an `*-save` command, or an
+/// iptables command whose command option (the first argument apart
+/// from `-t`/`-w` and their values) is `-C`, `-L`, `-S` or `-V` (or
+/// their long forms), and which doesn't carry any modifying command
+/// option anywhere (to be safe against e.g. option values looking
+/// like command options).
+pub fn is_read_only(cmd: &[String]) -> bool {
+    if cmd.first().is_some_and(|c| c.ends_with("-save")) {
+        return true;
+    }
+    let mut args = cmd.iter().skip(1).map(String::as_str).peekable();
+    let command = loop {
+        match args.next() {
+            Some("-t" | "--table") => {
+                args.next();
+            }
+            Some("-w" | "--wait") => {
+                args.next_if(|value| value.parse::<u32>().is_ok());
+            }
+            other => break other,
+        }
+    };
+    let read_only = matches!(
+        command,
+        Some("-C" | "--check" | "-L" | "--list" | "-S" | "--list-rules" | "-V" | "--version")
+    );
+    read_only
+        && !cmd.iter().skip(1).any(|arg| {
+            matches!(
+                arg.as_str(),
+                "-A" | "--append"
+                    | "-I"
+                    | "--insert"
+                    | "-D"
+                    | "--delete"
+                    | "-R"
+                    | "--replace"
+                    | "-N"
+                    | "--new-chain"
+                    | "-X"
+                    | "--delete-chain"
+                    | "-F"
+                    | "--flush"
+                    | "-P"
+                    | "--policy"
+                    | "-E"
+                    | "--rename-chain"
+                    | "-Z"
+                    | "--zero"
+            )
+        })
+}
+
+/// Really runs the commands that only read the state (see
+/// `is_read_only`) via `inner`, but fakes success (like
+/// `DryExecutor`) for all others, e.g. for a dry run that still shows
+/// accurately what is present.
+#[derive(Clone)]
+pub struct ReadOnlyExecutor<E> {
+    pub inner: E,
+}
+
+impl<C, E: Executor<C>> Executor<C> for ReadOnlyExecutor<E> {
+    fn execute<'t>(&mut self, context: C, cmd: &'t [String]) -> ExecutorResult<'t> {
+        if is_read_only(cmd) {
+            self.inner.execute(context, cmd)
+        } else {
+            DryExecutor.execute(context, cmd)
+        }
+    }
+
+    fn execute_for_rule<'t>(
+        &mut self,
+        context: C,
+        rule: &RuleId,
+        cmd: &'t [String],
+    ) -> ExecutorResult<'t> {
+        if is_read_only(cmd) {
+            self.inner.execute_for_rule(context, rule, cmd)
+        } else {
+            DryExecutor.execute(context, cmd)
+        }
+    }
+
+    fn read_output(&mut self, context: C, cmd: &[String]) -> anyhow::Result<String> {
+        if is_read_only(cmd) {
+            self.inner.read_output(context, cmd)
+        } else {
+            DryExecutor.read_output(context, cmd)
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct RealExecutor;
 impl<C> Executor<C> for RealExecutor {
     fn execute<'t>(&mut self, _context: C, cmd: &'t [String]) -> ExecutorResult<'t> {
@@ -121,7 +302,7 @@ impl<C> Executor<C> for RealExecutor {
                 ExecutorResult {
                     cmd,
                     status,
-                    combined_output: output.combined_string(),
+                    combined_output: output.labeled_combined_string(),
                 }
             }
             Err(e) => ExecutorResult {
@@ -131,4 +312,406 @@ impl<C> Executor<C> for RealExecutor {
             },
         }
     }
+
+    fn read_output(&mut self, _context: C, cmd: &[String]) -> anyhow::Result<String> {
+        let output = Command::new(&cmd[0])
+            .args(&cmd[1..])
+            .output()
+            .with_context(|| format!("running `{}`", shell_quote_many(cmd)))?;
+        if !output.status.success() {
+            bail!(
+                "command `{}` failed ({}): {}",
+                shell_quote_many(cmd),
+                output.status,
+                output.labeled_combined_string()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into())
+    }
+}
+
+/// Fail early with a clear message unless running as root (effective
+/// uid 0), instead of the cryptic exit code 4 errors iptables gives
+/// when run via `RealExecutor` without privileges.
+pub fn ensure_privileged() -> anyhow::Result<()> {
+    // Safe: geteuid has no preconditions and can't fail
+    check_privileged(unsafe { libc::geteuid() })
+}
+
+/// The check done by `ensure_privileged` for the effective uid
+/// `euid`.
+pub fn check_privileged(euid: u32) -> anyhow::Result<()> {
+    if euid != 0 {
+        bail!("must run as root (effective uid is {euid}), or use --dry-run");
+    }
+    Ok(())
+}
+
+/// Runs the commands inside the network namespace `netns`, via `ip
+/// netns exec <netns> <cmd...>`, using the `inner` executor. The
+/// results refer to the original (unwrapped) command.
+#[derive(Clone)]
+pub struct NetnsExecutor<E> {
+    netns: String,
+    pub inner: E,
+}
+
+impl<E> NetnsExecutor<E> {
+    /// Fails if `netns` isn't a valid namespace name (as accepted by
+    /// `ip netns`: non-empty, not "." or "..", without slashes,
+    /// whitespace or control characters, max. 255 bytes).
+    pub fn new(netns: &str, inner: E) -> anyhow::Result<Self> {
+        if netns.is_empty()
+            || netns == "."
+            || netns == ".."
+            || netns.len() > 255
+            || netns
+                .chars()
+                .any(|c| c == '/' || c.is_whitespace() || c.is_control())
+        {
+            bail!("invalid network namespace name {netns:?}");
+        }
+        Ok(Self {
+            netns: netns.into(),
+            inner,
+        })
+    }
+
+    pub fn netns(&self) -> &str {
+        &self.netns
+    }
+
+    /// The command line actually run for `cmd`.
+    pub fn wrap(&self, cmd: &[String]) -> Vec<String> {
+        let mut wrapped: Vec<String> = vec!["ip".into(), "netns".into(), "exec".into()];
+        wrapped.push(self.netns.clone());
+        wrapped.extend_from_slice(cmd);
+        wrapped
+    }
+}
+
+impl<C, E: Executor<C>> Executor<C> for NetnsExecutor<E> {
+    fn execute<'t>(&mut self, context: C, cmd: &'t [String]) -> ExecutorResult<'t> {
+        let wrapped = self.wrap(cmd);
+        let result = self.inner.execute(context, &wrapped);
+        ExecutorResult {
+            cmd,
+            status: result.status,
+            combined_output: result.combined_output,
+        }
+    }
+
+    fn execute_for_rule<'t>(
+        &mut self,
+        context: C,
+        rule: &RuleId,
+        cmd: &'t [String],
+    ) -> ExecutorResult<'t> {
+        let wrapped = self.wrap(cmd);
+        let result = self.inner.execute_for_rule(context, rule, &wrapped);
+        ExecutorResult {
+            cmd,
+            status: result.status,
+            combined_output: result.combined_output,
+        }
+    }
+
+    fn read_output(&mut self, context: C, cmd: &[String]) -> anyhow::Result<String> {
+        self.inner.read_output(context, &self.wrap(cmd))
+    }
+}
+
+/// Runs the commands via `sudo` (non-interactively, i.e. failing
+/// instead of asking for a password), using the `inner` executor. The
+/// results refer to the original (unwrapped) command.
+#[derive(Clone)]
+pub struct SudoExecutor<E> {
+    pub inner: E,
+}
+
+impl<E> SudoExecutor<E> {
+    /// The command line actually run for `cmd`.
+    pub fn wrap(&self, cmd: &[String]) -> Vec<String> {
+        let mut wrapped: Vec<String> = vec!["sudo".into(), "-n".into(), "--".into()];
+        wrapped.extend_from_slice(cmd);
+        wrapped
+    }
+}
+
+impl<C, E: Executor<C>> Executor<C> for SudoExecutor<E> {
+    fn execute<'t>(&mut self, context: C, cmd: &'t [String]) -> ExecutorResult<'t> {
+        let wrapped = self.wrap(cmd);
+        let result = self.inner.execute(context, &wrapped);
+        ExecutorResult {
+            cmd,
+            status: result.status,
+            combined_output: result.combined_output,
+        }
+    }
+
+    fn execute_for_rule<'t>(
+        &mut self,
+        context: C,
+        rule: &RuleId,
+        cmd: &'t [String],
+    ) -> ExecutorResult<'t> {
+        let wrapped = self.wrap(cmd);
+        let result = self.inner.execute_for_rule(context, rule, &wrapped);
+        ExecutorResult {
+            cmd,
+            status: result.status,
+            combined_output: result.combined_output,
+        }
+    }
+
+    fn read_output(&mut self, context: C, cmd: &[String]) -> anyhow::Result<String> {
+        self.inner.read_output(context, &self.wrap(cmd))
+    }
+}
+
+/// Whether `result` is iptables failing because another process holds
+/// the xtables lock (exit code 4), i.e. worth retrying.
+pub fn is_lock_contention(result: &ExecutorResult) -> bool {
+    matches!(result.status, ExecutorStatus::ExitCode(4))
+        && result.combined_output.contains("xtables lock")
+}
+
+/// Retries commands failing due to lock contention (see
+/// `is_lock_contention`) via the `inner` executor, up to `attempts`
+/// times in total, sleeping `delay` in between. Other failures are
+/// returned right away.
+#[derive(Clone)]
+pub struct RetryExecutor<E> {
+    pub inner: E,
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+impl<C: Clone, E: Executor<C>> Executor<C> for RetryExecutor<E> {
+    fn execute<'t>(&mut self, context: C, cmd: &'t [String]) -> ExecutorResult<'t> {
+        let mut attempt = 1;
+        loop {
+            let result = self.inner.execute(context.clone(), cmd);
+            if attempt >= self.attempts || !is_lock_contention(&result) {
+                return result;
+            }
+            attempt += 1;
+            sleep(self.delay);
+        }
+    }
+
+    fn execute_for_rule<'t>(
+        &mut self,
+        context: C,
+        rule: &RuleId,
+        cmd: &'t [String],
+    ) -> ExecutorResult<'t> {
+        let mut attempt = 1;
+        loop {
+            let result = self.inner.execute_for_rule(context.clone(), rule, cmd);
+            if attempt >= self.attempts || !is_lock_contention(&result) {
+                return result;
+            }
+            attempt += 1;
+            sleep(self.delay);
+        }
+    }
+}
+
+type Layer<C> = Box<dyn FnOnce(Box<dyn Executor<C>>) -> Box<dyn Executor<C>>>;
+
+/// Composes decorating executors (like `SudoExecutor`,
+/// `RetryExecutor` or `NetnsExecutor`) around a base executor. The
+/// layers are listed from the outside in: the first one added sees the
+/// commands first, the base executor last.
+pub struct ExecutorStack<C> {
+    layers: Vec<Layer<C>>,
+}
+
+impl<C: 'static> Default for ExecutorStack<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: 'static> ExecutorStack<C> {
+    pub fn new() -> Self {
+        Self { layers: Vec::new() }
+    }
+
+    /// Add a layer inside the ones added so far; `wrap` receives the
+    /// executor for the layers below, e.g. `|inner| SudoExecutor {
+    /// inner }`.
+    pub fn layer<E: Executor<C> + 'static>(
+        mut self,
+        wrap: impl FnOnce(Box<dyn Executor<C>>) -> E + 'static,
+    ) -> Self {
+        self.layers
+            .push(Box::new(move |inner| Box::new(wrap(inner))));
+        self
+    }
+
+    /// Wrap `base` in all layers.
+    pub fn build<E: Executor<C> + 'static>(self, base: E) -> Box<dyn Executor<C>> {
+        let mut executor: Box<dyn Executor<C>> = Box::new(base);
+        for wrap in self.layers.into_iter().rev() {
+            executor = wrap(executor);
+        }
+        executor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the command lines it is given.
+    #[derive(Default)]
+    struct Recorder(Vec<Vec<String>>);
+
+    impl Executor<()> for Recorder {
+        fn execute<'t>(&mut self, context: (), cmd: &'t [String]) -> ExecutorResult<'t> {
+            self.0.push(cmd.to_vec());
+            DryExecutor.execute(context, cmd)
+        }
+    }
+
+    #[test]
+    fn t_read_only_executor() {
+        let cmd = |s: &str| -> Vec<String> { s.split(' ').map(String::from).collect() };
+        let mut executor = ReadOnlyExecutor {
+            inner: Recorder::default(),
+        };
+        for mutating in [
+            "iptables -t filter -A INPUT -m comment --comment -L -j ACCEPT",
+            "iptables -t filter -C INPUT -m comment --comment -A -j ACCEPT",
+            "iptables -w 5 -t filter -F",
+            "iptables -t filter -A INPUT -j ACCEPT",
+            "iptables -t filter -D INPUT -j ACCEPT",
+            "iptables -t filter -N c",
+            "iptables -t filter -F c",
+            "iptables -t filter -X c",
+        ] {
+            assert!(executor.execute((), &cmd(mutating)).is_success());
+        }
+        assert!(executor.inner.0.is_empty());
+        let check = cmd("iptables -t filter -C INPUT -j ACCEPT");
+        executor.execute((), &check);
+        let list = cmd("iptables -w 5 -t nat -L -n");
+        executor.execute((), &list);
+        executor.read_output((), &cmd("ip6tables-save")).unwrap();
+        assert_eq!(executor.inner.0, [check, list, cmd("ip6tables-save")]);
+    }
+
+    #[test]
+    fn t_netns_executor() {
+        let cmd: Vec<String> = vec!["iptables".into(), "-L".into()];
+        let mut executor = NetnsExecutor::new("container1", Recorder::default()).unwrap();
+        assert_eq!(
+            executor.wrap(&cmd),
+            ["ip", "netns", "exec", "container1", "iptables", "-L"]
+        );
+        let result = executor.execute((), &cmd);
+        assert!(result.is_success());
+        assert_eq!(result.cmd, cmd);
+        assert_eq!(executor.inner.0, [executor.wrap(&cmd)]);
+
+        for invalid in ["", ".", "..", "a/b", "a b", "a\n"] {
+            assert!(
+                NetnsExecutor::new(invalid, DryExecutor).is_err(),
+                "{invalid:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn t_executor_stack() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// Records the command lines passing through.
+        struct Recording<E> {
+            inner: E,
+            log: Rc<RefCell<Vec<Vec<String>>>>,
+        }
+        impl<E: Executor<()>> Executor<()> for Recording<E> {
+            fn execute<'t>(&mut self, context: (), cmd: &'t [String]) -> ExecutorResult<'t> {
+                self.log.borrow_mut().push(cmd.to_vec());
+                self.inner.execute(context, cmd)
+            }
+        }
+
+        /// Fails with lock contention for the first `failures` calls.
+        struct Locked {
+            failures: u32,
+        }
+        impl Executor<()> for Locked {
+            fn execute<'t>(&mut self, context: (), cmd: &'t [String]) -> ExecutorResult<'t> {
+                if self.failures == 0 {
+                    return DryExecutor.execute(context, cmd);
+                }
+                self.failures -= 1;
+                ExecutorResult {
+                    cmd,
+                    status: ExecutorStatus::ExitCode(4),
+                    combined_output: "Another app is currently holding the xtables lock.".into(),
+                }
+            }
+        }
+
+        let stack = |attempts: u32, log: &Rc<RefCell<Vec<Vec<String>>>>| {
+            let log = log.clone();
+            ExecutorStack::new()
+                .layer(|inner| SudoExecutor { inner })
+                .layer(move |inner| RetryExecutor {
+                    inner,
+                    attempts,
+                    delay: Duration::ZERO,
+                })
+                .layer(move |inner| Recording { inner, log })
+                .build(Locked { failures: 2 })
+        };
+        let cmd: Vec<String> = vec!["iptables".into(), "-L".into()];
+        let sudo_cmd = ["sudo", "-n", "--", "iptables", "-L"];
+
+        let log = Rc::default();
+        let mut executor = stack(3, &log);
+        let result = executor.execute((), &cmd);
+        assert!(result.is_success());
+        assert_eq!(result.cmd, cmd);
+        assert_eq!(*log.borrow(), [sudo_cmd, sudo_cmd, sudo_cmd]);
+
+        let log = Rc::default();
+        let mut executor = stack(2, &log);
+        let result = executor.execute((), &cmd);
+        assert!(is_lock_contention(&result));
+        assert_eq!(log.borrow().len(), 2);
+    }
+
+    #[test]
+    fn t_check_privileged() {
+        assert!(check_privileged(0).is_ok());
+        assert_eq!(
+            check_privileged(1000).unwrap_err().to_string(),
+            "must run as root (effective uid is 1000), or use --dry-run"
+        );
+    }
+
+    #[test]
+    fn t_scripted_dry_executor() {
+        let cmd = |s: &str| -> Vec<String> { s.split(' ').map(String::from).collect() };
+        let mut executor = ScriptedDryExecutor::new()
+            .on("-X", ExecutorStatus::ExitCode(4), "busy")
+            .on("our-chain", ExecutorStatus::ExitCode(1), "");
+        let delete = cmd("iptables -t filter -X our-chain");
+        let result = executor.execute((), &delete);
+        assert_eq!(result.code(), Some(4));
+        assert_eq!(result.combined_output, "busy");
+        let create = cmd("iptables -t filter -N our-chain");
+        let result = executor.execute((), &create);
+        assert_eq!(result.code(), Some(1));
+        assert!(executor
+            .execute((), &cmd("iptables -t filter -N other"))
+            .is_success());
+    }
 }
@@ -0,0 +1,368 @@
+//! An in-memory model of the iptables tables, usable as an
+//! `Executor`, for testing multi-step applications (e.g. idempotency
+//! of recreation) without string-matching mocks.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::executor::{Executor, ExecutorResult, ExecutorStatus};
+use crate::iptables_save::save_quote_many;
+
+/// The chains of a table, in order of creation, with their rules
+/// (the arguments after the chain name).
+type Chains = Vec<(String, Vec<Vec<String>>)>;
+
+fn builtin_chains(table: &str) -> Option<&'static [&'static str]> {
+    Some(match table {
+        "filter" => &["INPUT", "FORWARD", "OUTPUT"],
+        "nat" => &["PREROUTING", "INPUT", "OUTPUT", "POSTROUTING"],
+        "mangle" => &["PREROUTING", "INPUT", "FORWARD", "OUTPUT", "POSTROUTING"],
+        "raw" => &["PREROUTING", "OUTPUT"],
+        "security" => &["INPUT", "FORWARD", "OUTPUT"],
+        _ => return None,
+    })
+}
+
+/// The chain a rule jumps or goes to, if any.
+fn rule_target(rule: &[String]) -> Option<&str> {
+    rule.windows(2)
+        .find(|w| w[0] == "-j" || w[0] == "-g")
+        .map(|w| w[1].as_str())
+}
+
+/// Fakes `iptables` (and `ip6tables`, sharing the same state) for the
+/// actions `-N`, `-X`, `-F`, `-A`, `-I`, `-D` and `-C`, with
+/// realistic exit codes and messages, and `iptables-save` (any
+/// command ending in `-save`). Clones share the state.
+#[derive(Debug, Clone, Default)]
+pub struct FakeIptables {
+    tables: Arc<Mutex<BTreeMap<String, Chains>>>,
+}
+
+impl FakeIptables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The chain names of `table` (built-in ones first), or None for
+    /// an unknown table.
+    pub fn chains(&self, table: &str) -> Option<Vec<String>> {
+        let mut tables = self.tables.lock().unwrap();
+        let chains = Self::table(&mut tables, table)?;
+        Some(chains.iter().map(|(name, _)| name.clone()).collect())
+    }
+
+    /// The rules of `chain` in `table` (each as the arguments after
+    /// the chain name), None if the chain doesn't exist.
+    pub fn rules(&self, table: &str, chain: &str) -> Option<Vec<Vec<String>>> {
+        let mut tables = self.tables.lock().unwrap();
+        let chains = Self::table(&mut tables, table)?;
+        chains
+            .iter()
+            .find(|(name, _)| name == chain)
+            .map(|(_, rules)| rules.clone())
+    }
+
+    /// The state in `iptables-save` format (only of the tables used
+    /// so far), with the built-in chains having policy ACCEPT.
+    pub fn save(&self) -> String {
+        let tables = self.tables.lock().unwrap();
+        let mut out = String::new();
+        for (table, chains) in tables.iter() {
+            let builtins = builtin_chains(table).unwrap_or_default();
+            out.push_str(&format!("*{table}\n"));
+            for (name, _) in chains {
+                let policy = if builtins.contains(&name.as_str()) {
+                    "ACCEPT"
+                } else {
+                    "-"
+                };
+                out.push_str(&format!(":{name} {policy} [0:0]\n"));
+            }
+            for (name, rules) in chains {
+                for rule in rules {
+                    out.push_str(&format!("-A {name} {}\n", save_quote_many(rule)));
+                }
+            }
+            out.push_str("COMMIT\n");
+        }
+        out
+    }
+
+    fn table<'m>(tables: &'m mut BTreeMap<String, Chains>, table: &str) -> Option<&'m mut Chains> {
+        let builtins = builtin_chains(table)?;
+        Some(tables.entry(table.into()).or_insert_with(|| {
+            builtins
+                .iter()
+                .map(|name| (name.to_string(), Vec::new()))
+                .collect()
+        }))
+    }
+
+    /// Apply the arguments (without the command name); returns the
+    /// exit code and message.
+    fn run(&self, args: &[String]) -> Result<(), (i32, String)> {
+        let err = |code: i32, msg: &str| Err((code, format!("iptables: {msg}")));
+        let (table, args) = match args {
+            [t, table, rest @ ..] if t == "-t" => (table.as_str(), rest),
+            _ => ("filter", args),
+        };
+        let (op, chain, rest) = match args {
+            [op, chain, rest @ ..] => (op.as_str(), chain.as_str(), rest),
+            _ => return err(2, "Bad argument"),
+        };
+        let mut tables = self.tables.lock().unwrap();
+        let Some(chains) = Self::table(&mut tables, table) else {
+            return err(3, &format!("can't initialize iptables table `{table}'"));
+        };
+        let position = chains.iter().position(|(name, _)| name == chain);
+        let no_chain = || err(1, "No chain/target/match by that name.");
+        match op {
+            "-N" => {
+                if position.is_some() {
+                    return err(1, "Chain already exists.");
+                }
+                chains.push((chain.into(), Vec::new()));
+            }
+            "-X" => {
+                let Some(i) = position else {
+                    return no_chain();
+                };
+                let builtin = builtin_chains(table).unwrap_or_default();
+                if builtin.contains(&chain) {
+                    return err(2, "Can't delete built-in chain.");
+                }
+                let referenced = chains
+                    .iter()
+                    .flat_map(|(_, rules)| rules)
+                    .any(|rule| rule_target(rule) == Some(chain));
+                if referenced || !chains[i].1.is_empty() {
+                    return err(4, "CHAIN_DEL failed (Device or resource busy).");
+                }
+                chains.remove(i);
+            }
+            "-F" => {
+                let Some(i) = position else {
+                    return no_chain();
+                };
+                chains[i].1.clear();
+            }
+            "-A" | "-I" | "-D" | "-C" => {
+                let Some(i) = position else {
+                    return no_chain();
+                };
+                let (num, spec) = match rest {
+                    [n, spec @ ..] if op != "-A" && op != "-C" => match n.parse::<usize>() {
+                        Ok(n) => (Some(n), spec),
+                        Err(_) => (None, rest),
+                    },
+                    _ => (None, rest),
+                };
+                if let Some(target) = rule_target(spec) {
+                    let known = ["ACCEPT", "DROP", "RETURN", "REJECT", "LOG"].contains(&target)
+                        || target.chars().all(|c| c.is_ascii_uppercase())
+                        || chains.iter().any(|(name, _)| name == target);
+                    if !known && op != "-D" && op != "-C" {
+                        return err(2, &format!("Couldn't load target `{target}'"));
+                    }
+                }
+                let rules = &mut chains[i].1;
+                let found = rules.iter().position(|rule| rule == spec);
+                match op {
+                    "-A" => rules.push(spec.to_vec()),
+                    "-I" => {
+                        let n = num.unwrap_or(1);
+                        if n == 0 || n > rules.len() + 1 {
+                            return err(1, "Index of insertion too big.");
+                        }
+                        rules.insert(n - 1, spec.to_vec());
+                    }
+                    "-D" => match (num, found) {
+                        (Some(n), _) if spec.is_empty() => {
+                            if n == 0 || n > rules.len() {
+                                return err(1, "Index of deletion too big.");
+                            }
+                            rules.remove(n - 1);
+                        }
+                        (None, Some(j)) => {
+                            rules.remove(j);
+                        }
+                        _ => {
+                            return err(1, "Bad rule (does a matching rule exist in that chain?).")
+                        }
+                    },
+                    _ => {
+                        if found.is_none() {
+                            return err(1, "Bad rule (does a matching rule exist in that chain?).");
+                        }
+                    }
+                }
+            }
+            _ => return err(2, &format!("unknown option \"{op}\"")),
+        }
+        Ok(())
+    }
+}
+
+impl<C> Executor<C> for FakeIptables {
+    fn execute<'t>(&mut self, _context: C, cmd: &'t [String]) -> ExecutorResult<'t> {
+        if cmd[0].ends_with("-save") {
+            return ExecutorResult {
+                cmd,
+                status: ExecutorStatus::Success,
+                combined_output: self.save(),
+            };
+        }
+        let (status, combined_output) = match self.run(&cmd[1..]) {
+            Ok(()) => (ExecutorStatus::Success, String::new()),
+            Err((code, msg)) => (ExecutorStatus::ExitCode(code), msg),
+        };
+        ExecutorResult {
+            cmd,
+            status,
+            combined_output,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iptables::{
+        Action, Effect, Filter, IptablesWriter, Negatable, Protocol, RecreatingMode, Restriction,
+        Rule, RuleAction,
+    };
+
+    fn run(fake: &mut FakeIptables, cmd: &str) -> ExecutorResult<'static> {
+        let cmd: Vec<String> = cmd.split(' ').map(String::from).collect();
+        let cmd: &'static [String] = Vec::leak(cmd);
+        fake.execute((), cmd)
+    }
+
+    #[test]
+    fn t_exit_codes() {
+        let mut fake = FakeIptables::new();
+        assert_eq!(
+            run(&mut fake, "iptables -C INPUT -j ACCEPT").code(),
+            Some(1)
+        );
+        assert!(run(&mut fake, "iptables -t filter -N c").is_success());
+        assert_eq!(run(&mut fake, "iptables -t filter -N c").code(), Some(1));
+        assert!(run(&mut fake, "iptables -t filter -A INPUT -j c").is_success());
+        assert!(run(&mut fake, "iptables -t filter -C INPUT -j c").is_success());
+        let result = run(&mut fake, "iptables -t filter -X c");
+        assert_eq!(result.code(), Some(4));
+        assert!(result
+            .combined_output
+            .contains("CHAIN_DEL failed (Device or resource busy)"));
+        assert_eq!(
+            run(&mut fake, "iptables -t filter -A INPUT -j nope").code(),
+            Some(2)
+        );
+        assert!(run(&mut fake, "iptables -t filter -D INPUT 1").is_success());
+        assert!(run(&mut fake, "iptables -t filter -X c").is_success());
+        assert_eq!(run(&mut fake, "iptables -t filter -F c").code(), Some(1));
+    }
+
+    #[test]
+    fn t_recreation_idempotent() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        let our_chain = Filter::Custom("our-chain".into());
+        iptables.push(
+            Action::NewChain,
+            Rule {
+                chain: our_chain.clone(),
+                restrictions: vec![],
+                rule_action: RuleAction::None,
+            },
+            RecreatingMode::Owned,
+        );
+        iptables.push(
+            Action::Insert(1),
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![],
+                rule_action: RuleAction::Jump(our_chain.clone()),
+            },
+            RecreatingMode::Owned,
+        );
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: our_chain,
+                restrictions: vec![
+                    Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                    Restriction::DestinationPort(Negatable::Is, 22),
+                ],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::Owned,
+        );
+
+        let mut fake = FakeIptables::new();
+        let snapshot = |fake: &FakeIptables| {
+            (
+                fake.chains("filter").unwrap(),
+                fake.rules("filter", "INPUT").unwrap(),
+                fake.rules("filter", "our-chain"),
+            )
+        };
+        iptables
+            .execute(Effect::Recreation, None::<Vec<u8>>, &mut fake)
+            .unwrap();
+        let first = snapshot(&fake);
+        assert_eq!(first.0, ["INPUT", "FORWARD", "OUTPUT", "our-chain"]);
+        assert_eq!(first.1, [["-j", "our-chain"]]);
+        iptables
+            .execute(Effect::Recreation, None::<Vec<u8>>, &mut fake)
+            .unwrap();
+        assert_eq!(snapshot(&fake), first);
+
+        iptables
+            .execute(Effect::Deletion, None::<Vec<u8>>, &mut fake)
+            .unwrap();
+        assert_eq!(
+            fake.chains("filter").unwrap(),
+            ["INPUT", "FORWARD", "OUTPUT"]
+        );
+        assert!(fake.rules("filter", "INPUT").unwrap().is_empty());
+    }
+
+    #[test]
+    fn t_ensure_chain() {
+        let mut fake = FakeIptables::new();
+        assert!(run(&mut fake, "iptables -t filter -N shared").is_success());
+        assert!(run(&mut fake, "iptables -t filter -A INPUT -j shared").is_success());
+
+        let shared = Filter::Custom("shared".into());
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.ensure_chain(shared.clone());
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: shared,
+                restrictions: vec![],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::Owned,
+        );
+        for _ in 0..2 {
+            iptables
+                .execute(Effect::Recreation, None::<Vec<u8>>, &mut fake)
+                .unwrap();
+            assert_eq!(fake.rules("filter", "shared").unwrap(), [["-j", "ACCEPT"]]);
+        }
+
+        // Still referenced from INPUT, thus only flushed (the failure
+        // of the chain deletion is tolerated)
+        iptables
+            .execute(Effect::Deletion, None::<Vec<u8>>, &mut fake)
+            .unwrap();
+        assert!(fake.rules("filter", "shared").unwrap().is_empty());
+        assert!(run(&mut fake, "iptables -t filter -D INPUT -j shared").is_success());
+        iptables
+            .execute(Effect::Deletion, None::<Vec<u8>>, &mut fake)
+            .unwrap();
+        assert_eq!(fake.rules("filter", "shared"), None);
+    }
+}
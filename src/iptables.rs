@@ -1,9 +1,18 @@
-use anyhow::Result;
-use ipnet::Ipv4Net;
+use anyhow::{bail, Context, Result};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use std::io::{self, Write};
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::time::Instant;
 
-use crate::executor::{Executor, ExecutorResult, ExecutorStatus};
+use crate::csv_util::csv_escape;
+use crate::error::FirewallError;
+use crate::executor::{Executor, ExecutorResult, ExecutorStatus, RuleId};
+use crate::iptables_save::{save_quote_many, table_from_name};
 use crate::shell_quote::shell_quote_many;
+use string_enum::ToLcString;
 use string_enum_macro::{lc_string_enum, uc_string_enum};
 
 #[lc_string_enum]
@@ -16,6 +25,29 @@ pub enum Table {
     Security,
 }
 
+impl Table {
+    /// The names of the built-in chains of the table.
+    pub fn builtin_chains(self) -> &'static [&'static str] {
+        match self {
+            Table::Filter => &["INPUT", "FORWARD", "OUTPUT"],
+            Table::Nat => &["PREROUTING", "INPUT", "OUTPUT", "POSTROUTING"],
+            Table::Mangle => &["PREROUTING", "INPUT", "FORWARD", "OUTPUT", "POSTROUTING"],
+            Table::Raw => &["PREROUTING", "OUTPUT"],
+            Table::Security => &["INPUT", "FORWARD", "OUTPUT"],
+        }
+    }
+}
+
+/// The tables in the order in which netfilter processes them (e.g.
+/// `raw` before connection tracking, `security` after `filter`).
+pub const TABLE_ORDER: [Table; 5] = [
+    Table::Raw,
+    Table::Mangle,
+    Table::Nat,
+    Table::Filter,
+    Table::Security,
+];
+
 #[lc_string_enum]
 #[derive(Copy)]
 pub enum Action {
@@ -23,6 +55,11 @@ pub enum Action {
     /// Insert is holding a 1-based index
     Insert(u32),
     NewChain,
+    /// Like `NewChain`, but for a chain that may already exist (e.g.
+    /// shared with other tools): an existing chain is fine, and it is
+    /// only removed for `Effect::Deletion`, not torn down for
+    /// `Effect::Recreation`.
+    EnsureChain,
 }
 
 impl From<Action> for AnyAction {
@@ -35,6 +72,9 @@ impl From<Action> for AnyAction {
 #[derive(Copy)]
 pub enum DeletionAction {
     Delete,
+    /// Delete by the 1-based position of the rule in the chain
+    /// (`-D <chain> <num>`), for chains not fully owned.
+    DeleteByNum(u32),
     DeleteChain,
     Flush,
 }
@@ -65,17 +105,25 @@ impl Action {
                 normal("-I");
                 out.push(format!("{n}"));
             }
-            Action::NewChain => normal("-N"),
+            Action::NewChain | Action::EnsureChain => normal("-N"),
         }
     }
 
+    /// Whether the action creates a chain (`NewChain` or
+    /// `EnsureChain`).
+    pub fn is_chain_creation(&self) -> bool {
+        matches!(self, Action::NewChain | Action::EnsureChain)
+    }
+
     /// The sequence of actions that are *removing* the original
     /// action.
     pub fn deletion_sequence(&self) -> &[DeletionAction] {
         match self {
             Action::Append => &[DeletionAction::Delete],
             Action::Insert(_) => &[DeletionAction::Delete],
-            Action::NewChain => &[DeletionAction::Flush, DeletionAction::DeleteChain],
+            Action::NewChain | Action::EnsureChain => {
+                &[DeletionAction::Flush, DeletionAction::DeleteChain]
+            }
         }
     }
 }
@@ -89,6 +137,10 @@ impl DeletionAction {
         };
         match self {
             DeletionAction::Delete => normal("-D"),
+            DeletionAction::DeleteByNum(n) => {
+                normal("-D");
+                out.push(format!("{n}"));
+            }
             DeletionAction::DeleteChain => normal("-X"),
             DeletionAction::Flush => normal("-F"),
         }
@@ -109,6 +161,31 @@ impl AnyAction {
         }
     }
 
+    /// Whether this is `Action::is_chain_creation`.
+    pub fn is_chain_creation(&self) -> bool {
+        matches!(self, AnyAction::Creation(a) if a.is_chain_creation())
+    }
+
+    /// Parse the rendering of `Display` (e.g. "insert 1").
+    pub fn from_display(s: &str) -> Option<Self> {
+        let (name, n) = match s.split_once(' ') {
+            Some((name, n)) => (name, Some(n.parse().ok()?)),
+            None => (s, None),
+        };
+        Some(match (name, n) {
+            ("check", None) => AnyAction::Check,
+            ("append", None) => Action::Append.into(),
+            ("insert", Some(n)) => Action::Insert(n).into(),
+            ("newchain", None) => Action::NewChain.into(),
+            ("ensurechain", None) => Action::EnsureChain.into(),
+            ("delete", None) => DeletionAction::Delete.into(),
+            ("deletebynum", Some(n)) => DeletionAction::DeleteByNum(n).into(),
+            ("deletechain", None) => DeletionAction::DeleteChain.into(),
+            ("flush", None) => DeletionAction::Flush.into(),
+            _ => return None,
+        })
+    }
+
     fn is_creation(&self) -> bool {
         match self {
             AnyAction::Check => false,
@@ -118,15 +195,36 @@ impl AnyAction {
     }
 }
 
-pub trait TablechainTrait {
+impl std::fmt::Display for AnyAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnyAction::Check => f.write_str("check"),
+            AnyAction::Creation(a @ Action::Insert(n)) => {
+                write!(f, "{} {n}", String::from(a))
+            }
+            AnyAction::Creation(a) => f.write_str(&String::from(a)),
+            AnyAction::Deletion(a @ DeletionAction::DeleteByNum(n)) => {
+                write!(f, "{} {n}", String::from(a))
+            }
+            AnyAction::Deletion(a) => f.write_str(&String::from(a)),
+        }
+    }
+}
+
+pub trait TablechainTrait: Debug + Clone {
     fn chain_name(&self) -> String;
     fn table_and_chain_names(&self) -> (String, String);
+    /// The same chain, with `prefix` prepended to the name if it is
+    /// a custom chain; built-in chains are returned unchanged.
+    fn with_prefix(self, prefix: &str) -> Self
+    where
+        Self: Sized;
 
     /// For collecting the arguments for the iptables command.
     fn push_args(&self, action: AnyAction, out: &mut Vec<String>) {
         let (table_name, chain_name) = self.table_and_chain_names();
         out.push("-t".into());
-        out.push(table_name.into());
+        out.push(table_name);
         action.push_args(chain_name, out);
     }
 }
@@ -148,6 +246,12 @@ macro_rules! def_chain {
                     self.chain_name()
                 )
             }
+            fn with_prefix(self, prefix: &str) -> Self {
+                match self {
+                    $typename::Custom(s) => $typename::Custom(format!("{prefix}{s}")),
+                    _ => self
+                }
+            }
         }
         impl From<$typename> for TablechainEnum {
             fn from(value: $typename) -> Self {
@@ -236,9 +340,44 @@ pub enum Protocol {
     Esp,
     Ah,
     Sctp,
+    Dccp,
     Mh,
 }
 
+impl Protocol {
+    /// The IANA protocol number, `None` for `All`.
+    pub fn number(&self) -> Option<u8> {
+        match self {
+            Protocol::All => None,
+            Protocol::Tcp => Some(6),
+            Protocol::Udp => Some(17),
+            Protocol::Udplite => Some(136),
+            Protocol::Icmp => Some(1),
+            Protocol::Icmpv6 => Some(58),
+            Protocol::Esp => Some(50),
+            Protocol::Ah => Some(51),
+            Protocol::Sctp => Some(132),
+            Protocol::Dccp => Some(33),
+            Protocol::Mh => Some(135),
+        }
+    }
+
+    /// The match module that needs to be loaded explicitly (unlike
+    /// for tcp, udp and udplite) for `--sport`/`--dport`.
+    pub fn port_module(&self) -> Option<&'static str> {
+        match self {
+            Protocol::Sctp => Some("sctp"),
+            Protocol::Dccp => Some("dccp"),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a restriction matches or its inverse. The `!` is always
+/// placed before the option it negates (`! --dport 22`, `-m
+/// multiport ! --dports 22,80`), the form iptables 1.8 accepts for
+/// all options; the older `--dport ! 22` is deprecated and rejected
+/// by some matches.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Negatable {
     Is,
@@ -247,21 +386,314 @@ pub enum Negatable {
 
 impl Negatable {
     fn push_args(&self, out: &mut Vec<String>) {
+        if let Some(token) = self.as_iptables_token() {
+            out.push(token.into());
+        }
+    }
+
+    /// The argument iptables expects before a negated option (`!`),
+    /// None for `Is`.
+    pub fn as_iptables_token(&self) -> Option<&'static str> {
+        match self {
+            Negatable::Is => None,
+            Negatable::IsNot => Some("!"),
+        }
+    }
+
+    /// The relational operator nft expects between a negated
+    /// expression and its value (`!=`, as in `ip saddr != 10.0.0.1`),
+    /// None for `Is` (nft's implicit equality).
+    pub fn as_nft_token(&self) -> Option<&'static str> {
+        match self {
+            Negatable::Is => None,
+            Negatable::IsNot => Some("!="),
+        }
+    }
+
+    /// `IsNot` for `Is` and vice versa.
+    pub fn inverted(self) -> Self {
+        match self {
+            Negatable::Is => Negatable::IsNot,
+            Negatable::IsNot => Negatable::Is,
+        }
+    }
+}
+
+/// An interface name as used by `-i`: either the exact name, or a
+/// prefix matching all interfaces starting with it (iptables' `eth+`
+/// syntax).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum InterfaceSpec {
+    Exact(String),
+    Prefix(String),
+}
+
+impl InterfaceSpec {
+    /// The string as iptables expects it.
+    pub fn to_arg(&self) -> String {
         match self {
-            Negatable::Is => (),
-            Negatable::IsNot => out.push("!".into()),
+            InterfaceSpec::Exact(s) => s.clone(),
+            InterfaceSpec::Prefix(s) => format!("{s}+"),
+        }
+    }
+}
+
+impl From<String> for InterfaceSpec {
+    fn from(value: String) -> Self {
+        InterfaceSpec::Exact(value)
+    }
+}
+
+impl From<&str> for InterfaceSpec {
+    fn from(value: &str) -> Self {
+        InterfaceSpec::Exact(value.into())
+    }
+}
+
+/// Address types for `Restriction::AddrType`, as classified by the
+/// routing tables.
+#[uc_string_enum]
+#[derive(Copy)]
+pub enum AddrType {
+    Unspec,
+    Unicast,
+    Local,
+    Broadcast,
+    Anycast,
+    Multicast,
+    Blackhole,
+    Unreachable,
+    Prohibit,
+    Throw,
+    Nat,
+    Xresolve,
+}
+
+/// The interface `Restriction::AddrType` limits the address type
+/// lookup to.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LimitIface {
+    /// `--limit-iface-in`, the incoming interface (only valid in
+    /// `PREROUTING`, `INPUT` and `FORWARD`).
+    In,
+    /// `--limit-iface-out`, the outgoing interface (only valid in
+    /// `POSTROUTING`, `OUTPUT` and `FORWARD`).
+    Out,
+}
+
+/// The options of `Restriction::AddrType`; at least one of the types
+/// must be given, each matching any of the listed address types.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct AddrTypeMatch {
+    /// `--src-type`
+    pub src_type: Option<(Negatable, Vec<AddrType>)>,
+    /// `--dst-type`
+    pub dst_type: Option<(Negatable, Vec<AddrType>)>,
+    /// Only consider the types the addresses have on the interface
+    /// of the packet (e.g. for `Local` on routers, where an address
+    /// of another interface would otherwise count as local, too).
+    pub limit_iface: Option<LimitIface>,
+}
+
+impl AddrTypeMatch {
+    fn push_args(&self, out: &mut Vec<String>) {
+        out.push("-m".into());
+        out.push("addrtype".into());
+        for (name, types) in [
+            ("--src-type", &self.src_type),
+            ("--dst-type", &self.dst_type),
+        ] {
+            if let Some((neg, types)) = types {
+                neg.push_args(out);
+                out.push(name.into());
+                out.push(types.iter().map(String::from).collect::<Vec<_>>().join(","));
+            }
+        }
+        match self.limit_iface {
+            Some(LimitIface::In) => out.push("--limit-iface-in".into()),
+            Some(LimitIface::Out) => out.push("--limit-iface-out".into()),
+            None => (),
+        }
+    }
+}
+
+/// The options of `Restriction::Conntrack`; unset fields are not
+/// matched on. The addresses must be of the family of the writer
+/// (checked by `IptablesWriter::validate`).
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ConntrackMatch {
+    /// `--ctproto`
+    pub proto: Option<(Negatable, Protocol)>,
+    /// `--ctorigsrc`
+    pub orig_src: Option<(Negatable, IpNet)>,
+    /// `--ctorigdst`
+    pub orig_dst: Option<(Negatable, IpNet)>,
+    /// `--ctreplsrc`
+    pub repl_src: Option<(Negatable, IpNet)>,
+    /// `--ctrepldst`
+    pub repl_dst: Option<(Negatable, IpNet)>,
+    /// `--ctorigsrcport`
+    pub orig_src_port: Option<(Negatable, u16)>,
+    /// `--ctorigdstport`
+    pub orig_dst_port: Option<(Negatable, u16)>,
+    /// `--ctreplsrcport`
+    pub repl_src_port: Option<(Negatable, u16)>,
+    /// `--ctrepldstport`
+    pub repl_dst_port: Option<(Negatable, u16)>,
+    /// `--ctdir`
+    pub dir: Option<CtDir>,
+}
+
+/// The direction of the packet relative to its connection, for
+/// `ConntrackMatch::dir`: `Original` for packets from the initiator,
+/// `Reply` for those back to it.
+#[uc_string_enum]
+#[derive(Copy)]
+pub enum CtDir {
+    Original,
+    Reply,
+}
+
+impl ConntrackMatch {
+    fn push_args(&self, out: &mut Vec<String>) {
+        out.push("-m".into());
+        out.push("conntrack".into());
+        let mut option = |neg: &Negatable, name: &str, value: String| {
+            neg.push_args(out);
+            out.push(name.into());
+            out.push(value);
+        };
+        if let Some((neg, proto)) = &self.proto {
+            option(neg, "--ctproto", proto.into());
+        }
+        for (name, net) in [
+            ("--ctorigsrc", &self.orig_src),
+            ("--ctorigdst", &self.orig_dst),
+            ("--ctreplsrc", &self.repl_src),
+            ("--ctrepldst", &self.repl_dst),
+        ] {
+            if let Some((neg, net)) = net {
+                option(neg, name, net.to_string());
+            }
+        }
+        for (name, port) in [
+            ("--ctorigsrcport", &self.orig_src_port),
+            ("--ctorigdstport", &self.orig_dst_port),
+            ("--ctreplsrcport", &self.repl_src_port),
+            ("--ctrepldstport", &self.repl_dst_port),
+        ] {
+            if let Some((neg, port)) = port {
+                option(neg, name, port.to_string());
+            }
+        }
+        if let Some(dir) = self.dir {
+            out.push("--ctdir".into());
+            out.push(String::from(&dir));
         }
     }
+
+    /// The addresses that are set.
+    pub fn addresses(&self) -> impl Iterator<Item = &IpNet> {
+        [
+            &self.orig_src,
+            &self.orig_dst,
+            &self.repl_src,
+            &self.repl_dst,
+        ]
+        .into_iter()
+        .flatten()
+        .map(|(_, net)| net)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Restriction {
-    Interface(Negatable, String),
+    Interface(Negatable, InterfaceSpec),
     Protocol(Negatable, Protocol),
-    SourceAddress(Negatable, Ipv4Net),
-    DestinationAddress(Negatable, Ipv4Net),
+    SourceAddress(Negatable, IpNet),
+    DestinationAddress(Negatable, IpNet),
     SourcePort(Negatable, u16),
     DestinationPort(Negatable, u16),
+    /// `-f`, matching second and further fragments of fragmented
+    /// packets (`! -f`: unfragmented packets and first fragments).
+    Fragment(Negatable),
+    /// `-m multiport --sports`, at most `MULTIPORT_MAX_PORTS` ports.
+    SourcePorts(Negatable, Vec<u16>),
+    /// `-m multiport --dports`, at most `MULTIPORT_MAX_PORTS` ports.
+    DestinationPorts(Negatable, Vec<u16>),
+    /// `--sport` and/or `--dport` in one restriction; at least one of
+    /// them must be given. `neg` applies to both.
+    Ports {
+        sport: Option<u16>,
+        dport: Option<u16>,
+        neg: Negatable,
+    },
+    /// `--icmp-type`, by name (e.g. "echo-request") or number;
+    /// requires `Protocol(Is, Icmp)`.
+    IcmpType(Negatable, String),
+    /// `--icmpv6-type`, by name (e.g. "packet-too-big") or number;
+    /// requires `Protocol(Is, Icmpv6)`.
+    Icmpv6Type(Negatable, String),
+    /// `-m comment --comment`; see `Restriction::comment` for
+    /// checking the length limit.
+    Comment(String),
+    /// `-m conntrack --ctstate`, matching any of the given states.
+    ConnState(Negatable, Vec<ConnState>),
+    /// `-m conntrack` with protocol and original/reply tuple options.
+    Conntrack(ConntrackMatch),
+    /// `-m bpf --bytecode`, with the bytecode as produced by
+    /// `nfbpf_compile` (e.g. "4,48 0 0 9,21 0 1 6,6 0 0 1,6 0 0 0").
+    Bpf(String),
+    /// `-m u32 --u32`, with the u32 test expression.
+    U32(String),
+    /// `-m limit --limit`, with the rate as understood by iptables
+    /// (e.g. "1/second", "10/minute").
+    Limit(String),
+    /// `-m vlan --vlan-id --vlan-prio`, matching 802.1q tagged
+    /// frames; `id` must be at most `VLAN_ID_MAX`, `prio` at most
+    /// `VLAN_PRIO_MAX`, and at least one of them given. `neg` applies
+    /// to both.
+    Vlan {
+        id: Option<u16>,
+        prio: Option<u8>,
+        neg: Negatable,
+    },
+    /// `-m dscp --dscp`, at most `DSCP_MAX`.
+    Dscp(Negatable, u8),
+    /// `-m tos --tos`, with the value as understood by iptables (a
+    /// name like "Minimize-Delay", or `value[/mask]`).
+    Tos(Negatable, String),
+    /// `-m tcp --tcp-option`, matching packets with the given TCP
+    /// option number set; requires `Protocol(Is, Tcp)`.
+    TcpOption(Negatable, u8),
+    /// `-m connbytes --connbytes from:[to] --connbytes-dir
+    /// --connbytes-mode`, matching connections by the amount
+    /// transferred so far; `to` must not be below `from`.
+    ConnBytes {
+        range: (u64, Option<u64>),
+        dir: ConnBytesDir,
+        mode: ConnBytesMode,
+        neg: Negatable,
+    },
+    /// `-m esp --espspi` or `-m ah --ahspi` (depending on the
+    /// protocol of the rule), matching the IPsec security parameter
+    /// index; requires `Protocol(Is, Esp)` or `Protocol(Is, Ah)`.
+    Spi(Negatable, u32),
+    /// `-m addrtype`, matching the types of the source and/or
+    /// destination address (e.g. `AddrType::Local`).
+    AddrType(AddrTypeMatch),
+    /// `-m mark --mark`, matching the netfilter mark (e.g. set by an
+    /// earlier chain via `-j MARK`).
+    Mark(Negatable, Mark),
+    /// `-m recent`, maintaining or checking the list `name` of
+    /// recently seen source addresses; see
+    /// `IptablesWriter::ssh_bruteforce_protection` for the typical
+    /// use.
+    Recent {
+        name: String,
+        op: RecentOp,
+        neg: Negatable,
+    },
     /// Use as a loophole to cover iptables functionality that isn't
     /// implemented yet; this will go away again once all
     /// functionality is covered, although a crate feature to
@@ -273,35 +705,173 @@ impl Restriction {
     fn push_args(&self, out: &mut Vec<String>) {
         match self {
             Restriction::Interface(neg, s) => {
-                out.push("-i".into());
                 neg.push_args(out);
-                out.push(s.into());
+                out.push("-i".into());
+                out.push(s.to_arg());
             }
             Restriction::Protocol(neg, s) => {
-                out.push("-p".into());
                 neg.push_args(out);
+                out.push("-p".into());
                 out.push(s.into());
             }
             Restriction::SourceAddress(neg, net) => {
-                out.push("-s".into());
                 neg.push_args(out);
+                out.push("-s".into());
                 out.push(net.to_string()); // XX ?
             }
             Restriction::DestinationAddress(neg, net) => {
-                out.push("-d".into());
                 neg.push_args(out);
+                out.push("-d".into());
                 out.push(net.to_string()); // XX ?
             }
             Restriction::SourcePort(neg, n) => {
-                out.push("--sport".into());
                 neg.push_args(out);
+                out.push("--sport".into());
                 out.push(n.to_string());
             }
             Restriction::DestinationPort(neg, n) => {
-                out.push("--dport".into());
                 neg.push_args(out);
+                out.push("--dport".into());
                 out.push(n.to_string());
             }
+            Restriction::Fragment(neg) => {
+                neg.push_args(out);
+                out.push("-f".into());
+            }
+            Restriction::SourcePorts(neg, ports) => {
+                push_multiport(*neg, "--sports", ports, out);
+            }
+            Restriction::DestinationPorts(neg, ports) => {
+                push_multiport(*neg, "--dports", ports, out);
+            }
+            Restriction::Ports { sport, dport, neg } => {
+                for (option, port) in [("--sport", sport), ("--dport", dport)] {
+                    if let Some(port) = port {
+                        neg.push_args(out);
+                        out.push(option.into());
+                        out.push(port.to_string());
+                    }
+                }
+            }
+            Restriction::IcmpType(neg, t) => {
+                neg.push_args(out);
+                out.push("--icmp-type".into());
+                out.push(t.into());
+            }
+            Restriction::Icmpv6Type(neg, t) => {
+                neg.push_args(out);
+                out.push("--icmpv6-type".into());
+                out.push(t.into());
+            }
+            Restriction::Comment(comment) => {
+                out.push("-m".into());
+                out.push("comment".into());
+                out.push("--comment".into());
+                out.push(comment.into());
+            }
+            Restriction::ConnState(neg, states) => {
+                out.push("-m".into());
+                out.push("conntrack".into());
+                neg.push_args(out);
+                out.push("--ctstate".into());
+                out.push(
+                    states
+                        .iter()
+                        .map(String::from)
+                        .collect::<Vec<_>>()
+                        .join(","),
+                );
+            }
+            Restriction::Conntrack(m) => m.push_args(out),
+            Restriction::AddrType(m) => m.push_args(out),
+            Restriction::Bpf(bytecode) => {
+                out.push("-m".into());
+                out.push("bpf".into());
+                out.push("--bytecode".into());
+                out.push(bytecode.into());
+            }
+            Restriction::U32(expression) => {
+                out.push("-m".into());
+                out.push("u32".into());
+                out.push("--u32".into());
+                out.push(expression.into());
+            }
+            Restriction::Limit(rate) => {
+                out.push("-m".into());
+                out.push("limit".into());
+                out.push("--limit".into());
+                out.push(rate.into());
+            }
+            Restriction::Vlan { id, prio, neg } => {
+                out.push("-m".into());
+                out.push("vlan".into());
+                if let Some(id) = id {
+                    neg.push_args(out);
+                    out.push("--vlan-id".into());
+                    out.push(id.to_string());
+                }
+                if let Some(prio) = prio {
+                    neg.push_args(out);
+                    out.push("--vlan-prio".into());
+                    out.push(prio.to_string());
+                }
+            }
+            Restriction::Dscp(neg, dscp) => {
+                out.push("-m".into());
+                out.push("dscp".into());
+                neg.push_args(out);
+                out.push("--dscp".into());
+                out.push(dscp.to_string());
+            }
+            Restriction::TcpOption(neg, option) => {
+                out.push("-m".into());
+                out.push("tcp".into());
+                neg.push_args(out);
+                out.push("--tcp-option".into());
+                out.push(option.to_string());
+            }
+            Restriction::Tos(neg, tos) => {
+                out.push("-m".into());
+                out.push("tos".into());
+                neg.push_args(out);
+                out.push("--tos".into());
+                out.push(tos.into());
+            }
+            Restriction::ConnBytes {
+                range: (from, to),
+                dir,
+                mode,
+                neg,
+            } => {
+                out.push("-m".into());
+                out.push("connbytes".into());
+                neg.push_args(out);
+                out.push("--connbytes".into());
+                out.push(match to {
+                    Some(to) => format!("{from}:{to}"),
+                    None => format!("{from}:"),
+                });
+                out.push("--connbytes-dir".into());
+                out.push(dir.into());
+                out.push("--connbytes-mode".into());
+                out.push(mode.into());
+            }
+            Restriction::Spi(neg, spi) => push_spi(*neg, *spi, &Protocol::Esp, out),
+            Restriction::Recent { name, op, neg } => {
+                out.push("-m".into());
+                out.push("recent".into());
+                neg.push_args(out);
+                op.push_args(out);
+                out.push("--name".into());
+                out.push(name.into());
+            }
+            Restriction::Mark(neg, mark) => {
+                out.push("-m".into());
+                out.push("mark".into());
+                neg.push_args(out);
+                out.push("--mark".into());
+                out.push(mark.to_string());
+            }
             Restriction::Custom(conditions) => {
                 for condition in conditions {
                     out.push(condition.into());
@@ -311,13 +881,296 @@ impl Restriction {
     }
 }
 
+/// What to do with a string that is longer than iptables allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnTooLong {
+    Error,
+    /// Cut off at the limit (at a char boundary).
+    Truncate,
+}
+
+/// Maximum length in bytes of a `--comment` (iptables requires it to
+/// be shorter than 256).
+pub const COMMENT_MAX_LEN: usize = 255;
+
+/// Maximum length in bytes of a `--log-prefix`.
+pub const LOG_PREFIX_MAX_LEN: usize = 29;
+
+/// Maximum length in bytes of an `--nflog-prefix`.
+pub const NFLOG_PREFIX_MAX_LEN: usize = 63;
+
+fn limit_length(s: &str, max_len: usize, on_too_long: OnTooLong, what: &str) -> Result<String> {
+    if s.len() <= max_len {
+        return Ok(s.into());
+    }
+    match on_too_long {
+        OnTooLong::Error => bail!(
+            "{what} is {} bytes long, the maximum is {max_len}: {s:?}",
+            s.len()
+        ),
+        OnTooLong::Truncate => {
+            let mut end = max_len;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            Ok(s[..end].into())
+        }
+    }
+}
+
+impl Restriction {
+    /// A `Comment` restriction, with the length checked against
+    /// `COMMENT_MAX_LEN`.
+    pub fn comment(comment: &str, on_too_long: OnTooLong) -> Result<Self> {
+        Ok(Restriction::Comment(limit_length(
+            comment,
+            COMMENT_MAX_LEN,
+            on_too_long,
+            "comment",
+        )?))
+    }
+}
+
+/// The highest 802.1q VLAN id.
+pub const VLAN_ID_MAX: u16 = 4095;
+
+/// The highest 802.1q priority.
+pub const VLAN_PRIO_MAX: u8 = 7;
+
+/// The highest DSCP value (6 bits).
+pub const DSCP_MAX: u8 = 63;
+
+/// The connection tracking helpers of the Linux kernel, as accepted
+/// by `RuleAction::CtHelper`.
+pub const CT_HELPERS: &[&str] = &[
+    "amanda",
+    "ftp",
+    "irc",
+    "netbios-ns",
+    "pptp",
+    "Q.931",
+    "RAS",
+    "sane",
+    "sip",
+    "snmp",
+    "tftp",
+];
+
+/// The maximum number of ports the multiport match accepts.
+pub const MULTIPORT_MAX_PORTS: usize = 15;
+
+fn push_multiport(neg: Negatable, option: &str, ports: &[u16], out: &mut Vec<String>) {
+    out.push("-m".into());
+    out.push("multiport".into());
+    neg.push_args(out);
+    out.push(option.into());
+    out.push(
+        ports
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+    );
+}
+
+impl Restriction {
+    /// A match on the given destination ports: `DestinationPort` for
+    /// a single port, `DestinationPorts` (multiport) for more.
+    pub fn destination_ports(neg: Negatable, ports: &[u16]) -> Self {
+        match ports {
+            [port] => Restriction::DestinationPort(neg, *port),
+            _ => Restriction::DestinationPorts(neg, ports.to_vec()),
+        }
+    }
+
+    /// A match on the given source ports: `SourcePort` for a single
+    /// port, `SourcePorts` (multiport) for more.
+    pub fn source_ports(neg: Negatable, ports: &[u16]) -> Self {
+        match ports {
+            [port] => Restriction::SourcePort(neg, *port),
+            _ => Restriction::SourcePorts(neg, ports.to_vec()),
+        }
+    }
+
+    /// A conntrack match on just the direction `dir`, e.g. for
+    /// counting the traffic of connections per direction.
+    pub fn ct_dir(dir: CtDir) -> Self {
+        Restriction::Conntrack(ConntrackMatch {
+            dir: Some(dir),
+            ..Default::default()
+        })
+    }
+
+    /// `ct_dir` for `CtDir::Original`.
+    pub fn ct_dir_original() -> Self {
+        Restriction::ct_dir(CtDir::Original)
+    }
+
+    /// `ct_dir` for `CtDir::Reply`.
+    pub fn ct_dir_reply() -> Self {
+        Restriction::ct_dir(CtDir::Reply)
+    }
+
+    /// The position of the restriction in the canonical order. Equal
+    /// for restrictions whose relative order must be kept (match
+    /// modules may depend on each other). `Custom` restrictions are
+    /// never moved, see `Rule::canonicalize`.
+    fn canonical_rank(&self) -> u8 {
+        match self {
+            Restriction::Interface(_, _) => 0,
+            Restriction::Protocol(_, _) => 1,
+            Restriction::SourceAddress(_, _) => 2,
+            Restriction::DestinationAddress(_, _) => 3,
+            Restriction::Fragment(_) => 3,
+            Restriction::SourcePort(_, _) => 4,
+            Restriction::DestinationPort(_, _) => 5,
+            Restriction::SourcePorts(_, _) => 4,
+            Restriction::DestinationPorts(_, _) => 5,
+            Restriction::Ports { .. } => 4,
+            Restriction::IcmpType(_, _) => 6,
+            Restriction::Icmpv6Type(_, _) => 6,
+            Restriction::Comment(_) => 7,
+            Restriction::ConnState(_, _) => 7,
+            Restriction::Conntrack(_) => 7,
+            Restriction::Bpf(_) => 7,
+            Restriction::U32(_) => 7,
+            Restriction::Limit(_) => 7,
+            Restriction::Vlan { .. } => 7,
+            Restriction::Dscp(_, _) => 7,
+            Restriction::Tos(_, _) => 7,
+            Restriction::TcpOption(_, _) => 7,
+            Restriction::ConnBytes { .. } => 7,
+            Restriction::Mark(_, _) => 7,
+            Restriction::Spi(_, _) => 7,
+            Restriction::Recent { .. } => 7,
+            Restriction::AddrType(_) => 7,
+            Restriction::Custom(_) => 7,
+        }
+    }
+
+    /// The same restriction with its `Negatable` flipped (for
+    /// restrictions whose `neg` applies to several options, like
+    /// `Ports`, each of them is negated). Used by `not!` in the
+    /// `restrictions!` macro. `None` for restrictions without a
+    /// `Negatable` (`Comment`, `Conntrack`, `AddrType`, `Bpf`, `U32`,
+    /// `Limit`, `Custom`).
+    pub fn negated(self) -> Option<Self> {
+        Some(match self {
+            Restriction::Interface(neg, s) => Restriction::Interface(neg.inverted(), s),
+            Restriction::Protocol(neg, p) => Restriction::Protocol(neg.inverted(), p),
+            Restriction::SourceAddress(neg, net) => Restriction::SourceAddress(neg.inverted(), net),
+            Restriction::DestinationAddress(neg, net) => {
+                Restriction::DestinationAddress(neg.inverted(), net)
+            }
+            Restriction::SourcePort(neg, n) => Restriction::SourcePort(neg.inverted(), n),
+            Restriction::DestinationPort(neg, n) => Restriction::DestinationPort(neg.inverted(), n),
+            Restriction::Fragment(neg) => Restriction::Fragment(neg.inverted()),
+            Restriction::SourcePorts(neg, ports) => Restriction::SourcePorts(neg.inverted(), ports),
+            Restriction::DestinationPorts(neg, ports) => {
+                Restriction::DestinationPorts(neg.inverted(), ports)
+            }
+            Restriction::Ports { sport, dport, neg } => Restriction::Ports {
+                sport,
+                dport,
+                neg: neg.inverted(),
+            },
+            Restriction::IcmpType(neg, t) => Restriction::IcmpType(neg.inverted(), t),
+            Restriction::Icmpv6Type(neg, t) => Restriction::Icmpv6Type(neg.inverted(), t),
+            Restriction::ConnState(neg, states) => Restriction::ConnState(neg.inverted(), states),
+            Restriction::Vlan { id, prio, neg } => Restriction::Vlan {
+                id,
+                prio,
+                neg: neg.inverted(),
+            },
+            Restriction::Dscp(neg, dscp) => Restriction::Dscp(neg.inverted(), dscp),
+            Restriction::Tos(neg, tos) => Restriction::Tos(neg.inverted(), tos),
+            Restriction::TcpOption(neg, option) => Restriction::TcpOption(neg.inverted(), option),
+            Restriction::ConnBytes {
+                range,
+                dir,
+                mode,
+                neg,
+            } => Restriction::ConnBytes {
+                range,
+                dir,
+                mode,
+                neg: neg.inverted(),
+            },
+            Restriction::Spi(neg, spi) => Restriction::Spi(neg.inverted(), spi),
+            Restriction::Mark(neg, mark) => Restriction::Mark(neg.inverted(), mark),
+            Restriction::Recent { name, op, neg } => Restriction::Recent {
+                name,
+                op,
+                neg: neg.inverted(),
+            },
+            Restriction::Comment(_)
+            | Restriction::Conntrack(_)
+            | Restriction::AddrType(_)
+            | Restriction::Bpf(_)
+            | Restriction::U32(_)
+            | Restriction::Limit(_)
+            | Restriction::Custom(_) => return None,
+        })
+    }
+}
+
+/// The args for `Restriction::Spi` in a rule for `protocol` (Esp or
+/// Ah).
+fn push_spi(neg: Negatable, spi: u32, protocol: &Protocol, out: &mut Vec<String>) {
+    let module = if *protocol == Protocol::Ah {
+        "ah"
+    } else {
+        "esp"
+    };
+    out.push("-m".into());
+    out.push(module.into());
+    neg.push_args(out);
+    out.push(format!("--{module}spi"));
+    out.push(format!("{spi:#x}"));
+}
+
+impl Restriction {
+    /// The `Display` form with `strip_host_prefixes` applied, see
+    /// `IptablesWriter::with_bare_host_addresses`.
+    pub fn to_bare_string(&self) -> String {
+        let mut out = Vec::new();
+        self.push_args(&mut out);
+        strip_host_prefixes(&mut out);
+        shell_quote_many(&out)
+    }
+}
+
+/// Without the context of a rule, e.g. without the port module the
+/// protocol may need, and with `Spi` as an esp match; see
+/// `Rule::restriction_args` for the form used in rules.
+impl std::fmt::Display for Restriction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut out = Vec::new();
+        self.push_args(&mut out);
+        f.write_str(&shell_quote_many(&out))
+    }
+}
+
+/// A `Vec<Restriction>` with the variants of `Restriction`,
+/// `InterfaceSpec`, `Negatable` and `Protocol` in scope, e.g.
+/// `restrictions![Protocol(Is, Tcp), not!(Interface(Is, Exact("eth0".into())))]`;
+/// `not!(r)` is `r.negated()`, panicking if `r` can't be negated.
 #[macro_export]
 macro_rules! restrictions {
     { $($exprs:tt)* } => {
         {
             use firewall::iptables::Restriction::*;
+            use firewall::iptables::InterfaceSpec::*;
             use firewall::iptables::Negatable::*;
             use firewall::iptables::Protocol::*;
+            #[allow(unused_macros)]
+            macro_rules! not {
+                ($restriction:expr) => {
+                    $restriction.negated().unwrap_or_else(|| {
+                        panic!("not!: {} can't be negated", stringify!($restriction))
+                    })
+                };
+            }
             vec![
                 $($exprs)*
             ]
@@ -325,311 +1178,5778 @@ macro_rules! restrictions {
     }
 }
 
+/// What `Restriction::Recent` does with the source address of the
+/// packet. `seconds` limits the check to entries seen within that
+/// time, `hitcount` to entries seen at least that many times.
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub enum RuleAction<C: TablechainTrait> {
-    None,
-    Return,
-    Drop,
-    Reject,
-    Jump(C),
-    Goto(C),
+pub enum RecentOp {
+    /// `--set`: add it to the list (always matches).
+    Set,
+    /// `--rcheck`: whether it is in the list.
+    Rcheck {
+        seconds: Option<u32>,
+        hitcount: Option<u32>,
+    },
+    /// `--update`: like `Rcheck`, also updating the last-seen
+    /// timestamp if it is in the list.
+    Update {
+        seconds: Option<u32>,
+        hitcount: Option<u32>,
+    },
+    /// `--remove`: remove it from the list, if present.
+    Remove,
 }
 
-impl<C: TablechainTrait> RuleAction<C> {
+impl RecentOp {
     fn push_args(&self, out: &mut Vec<String>) {
-        match self {
-            RuleAction::None => {}
-            RuleAction::Return => {
-                out.push("-j".into());
-                out.push("RETURN".into());
-            }
-            RuleAction::Drop => {
-                out.push("-j".into());
-                out.push("DROP".into());
-            }
-            RuleAction::Reject => {
-                out.push("-j".into());
-                out.push("REJECT".into());
-            }
-            RuleAction::Jump(c) => {
-                out.push("-j".into());
-                out.push(c.chain_name());
-            }
-            RuleAction::Goto(c) => {
-                out.push("-g".into());
-                out.push(c.chain_name());
-            }
+        let (option, seconds, hitcount) = match self {
+            RecentOp::Set => ("--set", None, None),
+            RecentOp::Rcheck { seconds, hitcount } => ("--rcheck", *seconds, *hitcount),
+            RecentOp::Update { seconds, hitcount } => ("--update", *seconds, *hitcount),
+            RecentOp::Remove => ("--remove", None, None),
+        };
+        out.push(option.into());
+        if let Some(seconds) = seconds {
+            out.push("--seconds".into());
+            out.push(seconds.to_string());
+        }
+        if let Some(hitcount) = hitcount {
+            out.push("--hitcount".into());
+            out.push(hitcount.to_string());
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct Rule<C: TablechainTrait> {
-    pub chain: C,
-    pub restrictions: Vec<Restriction>,
-    pub rule_action: RuleAction<C>,
+/// Connection tracking states for `Restriction::ConnState`.
+#[uc_string_enum]
+#[derive(Copy)]
+pub enum ConnState {
+    New,
+    Established,
+    Related,
+    Invalid,
+    Untracked,
+    Snat,
+    Dnat,
 }
 
-impl<C: TablechainTrait> Rule<C> {
-    pub fn cmd_args(&self, action: AnyAction) -> Vec<String> {
-        let mut out = Vec::new();
-        self.chain.push_args(action, &mut out);
-        for r in &self.restrictions {
-            r.push_args(&mut out);
-        }
-        self.rule_action.push_args(&mut out);
-        out
-    }
+/// The direction(s) counted by `Restriction::ConnBytes`.
+#[lc_string_enum]
+#[derive(Copy)]
+pub enum ConnBytesDir {
+    Original,
+    Reply,
+    Both,
 }
 
-pub trait RuleTrait {
-    fn cmd_args(&self, action: AnyAction) -> Vec<String>;
-}
-
-impl<C: TablechainTrait> RuleTrait for Rule<C> {
-    fn cmd_args(&self, action: AnyAction) -> Vec<String> {
-        self.cmd_args(action)
-    }
+/// What `Restriction::ConnBytes` counts; `Avgpkt` is the average
+/// packet size in bytes.
+#[lc_string_enum]
+#[derive(Copy)]
+pub enum ConnBytesMode {
+    Packets,
+    Bytes,
+    Avgpkt,
 }
 
+/// A netfilter mark value for `Restriction::Mark`, optionally only
+/// comparing the bits set in a mask. Rendered in hex, e.g. `0x1` or
+/// `0x100/0xff00`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum ResultInterpretation {
-    /// Undoubtable success.
-    Ok,
-    /// Error that happens when a rule that should be deleted doesn't
-    /// exist.
-    OkForDeletions,
-    /// A chain couldn't be deleted because it is in use
-    ChainInUse,
-    /// A chain couldn't be created because it already exists
-    ChainAlreadyExists,
-    /// Unrecoverable error
-    Err,
+pub struct Mark {
+    pub value: u32,
+    pub mask: Option<u32>,
 }
 
-impl<'t> From<&ExecutorResult<'t>> for ResultInterpretation {
-    fn from(result: &ExecutorResult<'t>) -> Self {
-        match result.status {
-            ExecutorStatus::Success => Self::Ok,
-            ExecutorStatus::ExitCode(code) => {
-                if code == 4
-                    && result
-                        .combined_output
-                        .contains("CHAIN_DEL failed (Device or resource busy)")
-                {
-                    Self::ChainInUse
-                } else if code == 1 && result.combined_output.contains("Chain already exists") {
-                    Self::ChainAlreadyExists
-                }
-                // It appears that iptables exits with code 1 *or* 2 when
-                // chain doesn't exist, 1 for rule that doesn't exist, 4 when
-                // not running as root or when "Device or resource busy"
-                // because a chain, while empty, is still referenced.
-                else if code == 1 || code == 2 {
-                    Self::OkForDeletions
-                } else {
-                    Self::Err
-                }
-            }
-            ExecutorStatus::Signal(_) => Self::Err,
-            ExecutorStatus::ExecFailure(_) => Self::Err,
+impl Mark {
+    /// Match all bits of the mark.
+    pub fn exact(value: u32) -> Self {
+        Self { value, mask: None }
+    }
+
+    /// Match only the bits of the mark that are set in `mask`.
+    pub fn with_mask(value: u32, mask: u32) -> Self {
+        Self {
+            value,
+            mask: Some(mask),
         }
     }
 }
 
-pub struct IptablesWriter {
-    iptables_cmd: Vec<String>,
-    actions: Vec<(AnyAction, Box<dyn RuleTrait>, RecreatingMode)>,
+impl std::fmt::Display for Mark {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#x}", self.value)?;
+        if let Some(mask) = self.mask {
+            write!(f, "/{mask:#x}")?;
+        }
+        Ok(())
+    }
 }
 
-/// What end result you want: Deletion inverts the result of an
-/// action. Recreation first deletes then creates. Creation just runs
-/// the originally specified action (rarely what you want).
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Effect {
-    Creation,
-    Recreation,
-    Deletion,
+/// The direction for `RuleAction::ConnSecMark`.
+#[lc_string_enum]
+#[derive(Copy)]
+pub enum ConnSecOp {
+    /// Copy the packet's security mark to the connection.
+    Save,
+    /// Copy the connection's security mark to the packet.
+    Restore,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum RecreatingMode {
-    /// Always create and always delete
-    Owned,
-    /// Try to create but don't given an error if that fails. Always
-    /// delete. Useful when trying to add rules onto a chain by
-    /// another entity that might not exist in all cases.
-    TryCreation,
-    /// Try to create but don't give an error if that fails, never
-    /// delete. Perhaps useful for creating chains that should stay
-    /// around when stopping the firewall, hence subsequent creation
-    /// mustn't fail. This can't be used for creating match rules (but
-    /// only chains) as each time the firewall is restarted it would
-    /// add/insert another copy of it.
-    TryCreationNoDeletion,
+/// The ICMP (or TCP reset) reply for `RuleAction::RejectWith`. The
+/// `Icmp*` types are for iptables, the `Icmp6*` ones for ip6tables;
+/// `IptablesWriter` maps them to the equivalent of its address family
+/// on pushing, so either can be used with both.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RejectWith {
+    IcmpNetUnreachable,
+    IcmpHostUnreachable,
+    IcmpPortUnreachable,
+    IcmpProtoUnreachable,
+    IcmpNetProhibited,
+    IcmpHostProhibited,
+    IcmpAdminProhibited,
+    Icmp6NoRoute,
+    Icmp6AdmProhibited,
+    Icmp6AddrUnreachable,
+    Icmp6PortUnreachable,
+    TcpReset,
 }
 
-impl RecreatingMode {
-    pub fn allows_errors_on_creation(&self) -> bool {
+impl RejectWith {
+    /// The argument for `--reject-with`.
+    pub fn as_str(&self) -> &'static str {
         match self {
-            RecreatingMode::Owned => false,
-            RecreatingMode::TryCreation => true,
-            RecreatingMode::TryCreationNoDeletion => true,
+            RejectWith::IcmpNetUnreachable => "icmp-net-unreachable",
+            RejectWith::IcmpHostUnreachable => "icmp-host-unreachable",
+            RejectWith::IcmpPortUnreachable => "icmp-port-unreachable",
+            RejectWith::IcmpProtoUnreachable => "icmp-proto-unreachable",
+            RejectWith::IcmpNetProhibited => "icmp-net-prohibited",
+            RejectWith::IcmpHostProhibited => "icmp-host-prohibited",
+            RejectWith::IcmpAdminProhibited => "icmp-admin-prohibited",
+            RejectWith::Icmp6NoRoute => "icmp6-no-route",
+            RejectWith::Icmp6AdmProhibited => "icmp6-adm-prohibited",
+            RejectWith::Icmp6AddrUnreachable => "icmp6-addr-unreachable",
+            RejectWith::Icmp6PortUnreachable => "icmp6-port-unreachable",
+            RejectWith::TcpReset => "tcp-reset",
         }
     }
-}
 
-impl IptablesWriter {
-    pub fn new(iptables_cmd: Vec<String>) -> Self {
-        Self {
-            iptables_cmd,
-            actions: Vec::new(),
-        }
+    /// The type for the `--reject-with` argument `s`.
+    pub fn from_arg(s: &str) -> Option<Self> {
+        use RejectWith::*;
+        [
+            IcmpNetUnreachable,
+            IcmpHostUnreachable,
+            IcmpPortUnreachable,
+            IcmpProtoUnreachable,
+            IcmpNetProhibited,
+            IcmpHostProhibited,
+            IcmpAdminProhibited,
+            Icmp6NoRoute,
+            Icmp6AdmProhibited,
+            Icmp6AddrUnreachable,
+            Icmp6PortUnreachable,
+            TcpReset,
+        ]
+        .into_iter()
+        .find(|r| r.as_str() == s)
     }
 
-    /// Pushes the rule with the corresponding action regardless of
-    /// whether the action is creative or other. You usually don't
-    /// want to use this, but rather `push` instead.
-    pub fn _push<T: TablechainTrait + 'static>(
-        &mut self,
-        action: AnyAction,
-        rule: Rule<T>,
-        recreating_mode: RecreatingMode,
-    ) {
-        self.actions.push((action, Box::new(rule), recreating_mode));
+    /// The equivalent type for ip6tables if `ipv6` is true, for
+    /// iptables otherwise. ip6tables has no protocol unreachable
+    /// reply; port unreachable is used instead, and the three
+    /// prohibited variants all map to `Icmp6AdmProhibited`.
+    pub fn for_family(self, ipv6: bool) -> Self {
+        use RejectWith::*;
+        if ipv6 {
+            match self {
+                IcmpNetUnreachable => Icmp6NoRoute,
+                IcmpHostUnreachable => Icmp6AddrUnreachable,
+                IcmpPortUnreachable | IcmpProtoUnreachable => Icmp6PortUnreachable,
+                IcmpNetProhibited | IcmpHostProhibited | IcmpAdminProhibited => Icmp6AdmProhibited,
+                _ => self,
+            }
+        } else {
+            match self {
+                Icmp6NoRoute => IcmpNetUnreachable,
+                Icmp6AddrUnreachable => IcmpHostUnreachable,
+                Icmp6PortUnreachable => IcmpPortUnreachable,
+                Icmp6AdmProhibited => IcmpAdminProhibited,
+                _ => self,
+            }
+        }
     }
+}
 
-    /// Push a rule with creative action. Because deleting actions are
-    /// usually done via running `execute` with an Effect that deletes
-    /// (including recreation), they are automatically derived
-    /// there. Also, deleting `Effect`s lead to the reversal of the
-    /// order of rule application; hence call `push` always in the
-    /// order appropriate for the creation of rules.
-    pub fn push<T: TablechainTrait + 'static>(
-        &mut self,
-        action: Action,
-        rule: Rule<T>,
-        recreating_mode: RecreatingMode,
-    ) {
-        self._push(action.into(), rule, recreating_mode);
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RuleAction<C: TablechainTrait> {
+    None,
+    Accept,
+    Return,
+    Drop,
+    Reject,
+    /// `-j REJECT --reject-with`.
+    RejectWith(RejectWith),
+    /// `-j LOG`, with the optional `--log-prefix`. Non-terminating.
+    Log(Option<String>),
+    /// `-j NFLOG --nflog-group`, passing the packet to the netlink
+    /// group `group` (0-65535) for userspace logging daemons like
+    /// ulogd, with the optional `--nflog-prefix` (at most
+    /// `NFLOG_PREFIX_MAX_LEN` bytes) and `--nflog-range` (the number
+    /// of bytes of the packet to copy). Non-terminating.
+    NfLog {
+        group: u16,
+        prefix: Option<String>,
+        range: Option<u32>,
+    },
+    /// `-j SECMARK --selctx`, setting the SELinux security context
+    /// on the packet (`security` or `mangle` table).
+    SecMark(String),
+    /// `-j CONNSECMARK`, copying the security mark between packet
+    /// and connection.
+    ConnSecMark(ConnSecOp),
+    /// `-j CT --zone`, assigning the connection tracking zone. Only
+    /// valid in the `raw` table (`PREROUTING` and `OUTPUT` chains, or
+    /// custom chains jumped to from them).
+    CtZone(u16),
+    /// `-j CT --notrack`, disabling connection tracking for the
+    /// packet (making it match `ConnState::Untracked`). Only valid in
+    /// the `raw` table.
+    NoTrack,
+    /// `-j CT --helper`, assigning the connection tracking helper
+    /// (one of `CT_HELPERS`, e.g. "ftp") to the connection. Only
+    /// valid in the `raw` table; the corresponding kernel module
+    /// (e.g. `nf_conntrack_ftp`) must be loaded, as the rule doesn't
+    /// load it.
+    CtHelper(String),
+    Jump(C),
+    Goto(C),
+}
+
+impl<C: TablechainTrait> RuleAction<C> {
+    /// Whether matching packets leave the chain (`Goto`, and the
+    /// `ACCEPT`, `RETURN`, `DROP` and `REJECT` targets); `Jump` is
+    /// not terminating as the packet continues after the rule if the
+    /// jumped-to chain returns.
+    pub fn is_terminating(&self) -> bool {
+        match self {
+            RuleAction::Accept
+            | RuleAction::Return
+            | RuleAction::Drop
+            | RuleAction::Reject
+            | RuleAction::RejectWith(_)
+            | RuleAction::Goto(_) => true,
+            RuleAction::None
+            | RuleAction::Log(_)
+            | RuleAction::NfLog { .. }
+            | RuleAction::SecMark(_)
+            | RuleAction::ConnSecMark(_)
+            | RuleAction::CtZone(_)
+            | RuleAction::NoTrack
+            | RuleAction::CtHelper(_)
+            | RuleAction::Jump(_) => false,
+        }
     }
 
-    /// For a dry_run; don't use as shell code, use execute (that can
-    /// use cmd_args directly)! -- todo: needs to be updated with an
-    /// Effect
-    pub fn to_string(&self) -> String {
-        let mut out = String::new();
-        for (action, rule, _) in &self.actions {
-            for arg in rule.cmd_args(*action) {
-                out.push_str(" ");
-                out.push_str(&arg);
+    fn push_args(&self, out: &mut Vec<String>) {
+        match self {
+            RuleAction::None => {}
+            RuleAction::Accept => {
+                out.push("-j".into());
+                out.push("ACCEPT".into());
+            }
+            RuleAction::Return => {
+                out.push("-j".into());
+                out.push("RETURN".into());
+            }
+            RuleAction::Drop => {
+                out.push("-j".into());
+                out.push("DROP".into());
+            }
+            RuleAction::Reject => {
+                out.push("-j".into());
+                out.push("REJECT".into());
+            }
+            RuleAction::RejectWith(with) => {
+                out.push("-j".into());
+                out.push("REJECT".into());
+                out.push("--reject-with".into());
+                out.push(with.as_str().into());
+            }
+            RuleAction::Log(prefix) => {
+                out.push("-j".into());
+                out.push("LOG".into());
+                if let Some(prefix) = prefix {
+                    out.push("--log-prefix".into());
+                    out.push(prefix.into());
+                }
+            }
+            RuleAction::NfLog {
+                group,
+                prefix,
+                range,
+            } => {
+                out.push("-j".into());
+                out.push("NFLOG".into());
+                out.push("--nflog-group".into());
+                out.push(group.to_string());
+                if let Some(prefix) = prefix {
+                    out.push("--nflog-prefix".into());
+                    out.push(prefix.into());
+                }
+                if let Some(range) = range {
+                    out.push("--nflog-range".into());
+                    out.push(range.to_string());
+                }
+            }
+            RuleAction::SecMark(context) => {
+                out.push("-j".into());
+                out.push("SECMARK".into());
+                out.push("--selctx".into());
+                out.push(context.into());
+            }
+            RuleAction::ConnSecMark(op) => {
+                out.push("-j".into());
+                out.push("CONNSECMARK".into());
+                out.push(format!("--{}", op.to_lc_string()));
+            }
+            RuleAction::CtZone(zone) => {
+                out.push("-j".into());
+                out.push("CT".into());
+                out.push("--zone".into());
+                out.push(zone.to_string());
+            }
+            RuleAction::NoTrack => {
+                out.push("-j".into());
+                out.push("CT".into());
+                out.push("--notrack".into());
+            }
+            RuleAction::CtHelper(helper) => {
+                out.push("-j".into());
+                out.push("CT".into());
+                out.push("--helper".into());
+                out.push(helper.into());
+            }
+            RuleAction::Jump(c) => {
+                out.push("-j".into());
+                out.push(c.chain_name());
+            }
+            RuleAction::Goto(c) => {
+                out.push("-g".into());
+                out.push(c.chain_name());
             }
-            out.push_str("\n");
         }
-        out
     }
+}
 
-    /// Turn the pushed rules into rules for actual execution
-    /// according to the wanted Effect. Execute for real if true is
-    /// given.
-    pub fn execute<O: std::io::Write>(
-        &self,
-        want: Effect,
-        mut verbose_output: Option<O>,
-        executor: &mut dyn Executor<AnyAction>,
-    ) -> Result<()> {
-        let mut run = |creation: bool| -> Result<()> {
-            let actions: Box<dyn Iterator<Item = _>> = if creation {
-                Box::new(self.actions.iter())
-            } else {
-                Box::new(self.actions.iter().rev())
-            };
+impl<C: TablechainTrait> RuleAction<C> {
+    /// The same action, with jump and goto targets prefixed via
+    /// `TablechainTrait::with_prefix`.
+    pub fn with_chain_prefix(self, prefix: &str) -> Self {
+        match self {
+            RuleAction::Jump(c) => RuleAction::Jump(c.with_prefix(prefix)),
+            RuleAction::Goto(c) => RuleAction::Goto(c.with_prefix(prefix)),
+            _ => self,
+        }
+    }
 
-            for (action, rule, recreating_mode) in actions {
-                match recreating_mode {
-                    RecreatingMode::Owned => {}
-                    RecreatingMode::TryCreation => {}
-                    RecreatingMode::TryCreationNoDeletion => {
-                        if !creation {
-                            continue;
-                        }
+    /// A `Log` action, with the prefix length checked against
+    /// `LOG_PREFIX_MAX_LEN`.
+    pub fn log(prefix: &str, on_too_long: OnTooLong) -> Result<Self> {
+        Ok(RuleAction::Log(Some(limit_length(
+            prefix,
+            LOG_PREFIX_MAX_LEN,
+            on_too_long,
+            "log prefix",
+        )?)))
+    }
+}
+
+impl<C: TablechainTrait> std::fmt::Display for RuleAction<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut out = Vec::new();
+        self.push_args(&mut out);
+        f.write_str(&shell_quote_many(&out))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Rule<C: TablechainTrait> {
+    pub chain: C,
+    pub restrictions: Vec<Restriction>,
+    pub rule_action: RuleAction<C>,
+}
+
+impl<C: TablechainTrait> Rule<C> {
+    pub fn cmd_args(&self, action: AnyAction) -> Vec<String> {
+        let mut out = Vec::new();
+        self.chain.push_args(action, &mut out);
+        out.extend(self.restriction_args().into_iter().flatten());
+        self.rule_action.push_args(&mut out);
+        out
+    }
+
+    /// The arguments of each restriction as rendered by `cmd_args`,
+    /// i.e. with the port module (`-m sctp`, `-m dccp`) needed by the
+    /// protocol before the first port option, and `Spi` as an esp or
+    /// ah match depending on the protocol.
+    pub fn restriction_args(&self) -> Vec<Vec<String>> {
+        let mut port_module = self.restrictions.iter().find_map(|r| match r {
+            Restriction::Protocol(Negatable::Is, p) => p.port_module(),
+            _ => None,
+        });
+        let protocol = self.restrictions.iter().find_map(|r| match r {
+            Restriction::Protocol(Negatable::Is, p) => Some(p),
+            _ => None,
+        });
+        self.restrictions
+            .iter()
+            .map(|r| {
+                let mut out = Vec::new();
+                if matches!(
+                    r,
+                    Restriction::SourcePort(_, _)
+                        | Restriction::DestinationPort(_, _)
+                        | Restriction::Ports { .. }
+                ) {
+                    if let Some(module) = port_module.take() {
+                        out.push("-m".into());
+                        out.push(module.into());
                     }
                 }
-                let creation_action = match action {
-                    AnyAction::Creation(a) => a,
-                    _ => panic!(
-                        "should not have non-creating actions when using deleting \
-                                 `Effect`s, apparently you used `_push`?",
-                    ),
-                };
-                let actions = if creation {
-                    vec![*action]
-                } else {
-                    creation_action
-                        .deletion_sequence()
-                        .iter()
-                        .copied()
-                        .map(AnyAction::from)
-                        .collect()
-                };
-                for action in actions {
-                    let mut cmd = self.iptables_cmd.clone();
-                    let mut args = rule.cmd_args(action);
-                    cmd.append(&mut args);
-                    let result = executor.execute(action, &cmd);
-                    if let Some(out) = verbose_output.as_mut() {
-                        writeln!(out, "{} {}", result.to_str(), shell_quote_many(&cmd))?;
-                    }
-                    match ResultInterpretation::from(&result) {
-                        ResultInterpretation::Ok => (),
-                        ResultInterpretation::OkForDeletions => {
-                            if action.is_creation() && !recreating_mode.allows_errors_on_creation()
-                            {
-                                result.to_anyhow(Some(&format!(
-                                    "for non-deleting action {action:?}"
-                                )))?
-                            }
-                        }
-                        ResultInterpretation::ChainInUse => {
-                            if action.is_creation() && !recreating_mode.allows_errors_on_creation()
-                            {
-                                result.to_anyhow(Some(&format!(
-                                    "because chain is in use, for non-deleting action {action:?}"
-                                )))?
-                            } else {
-                                // Mark so that error in creation part
-                                // below can be more strictly checked?
-                            }
-                        }
-                        ResultInterpretation::ChainAlreadyExists => {
-                            if action == Action::NewChain.into() {
-                                // Only ignore this error if
-                                // previously there was the ChainInUse
-                                // error above on the same rule?
-                            } else if action.is_creation()
-                                && !recreating_mode.allows_errors_on_creation()
-                            {
-                                result.to_anyhow(Some(&format!(
-                                    "got 'chain already exists' error even though action \
-                                     is not chain creation, but {action:?}"
-                                )))?
-                            }
-                        }
-                        ResultInterpretation::Err => result.to_anyhow(None)?,
+                match (r, protocol) {
+                    (Restriction::Spi(neg, spi), Some(protocol)) => {
+                        push_spi(*neg, *spi, protocol, &mut out)
                     }
+                    _ => r.push_args(&mut out),
                 }
-            }
-            Ok(())
-        };
+                out
+            })
+            .collect()
+    }
 
-        match want {
-            Effect::Creation => run(true)?,
-            Effect::Recreation => {
-                run(false)?;
-                run(true)?;
+    /// Sort the restrictions into a canonical order (interface,
+    /// protocol, addresses, ports, ICMP types, then match modules),
+    /// so that logically equal rules render the same. Only
+    /// commutative matches are moved: the protocol always stays
+    /// before the options depending on it, and the relative order of
+    /// match modules (like `Comment`) is kept. `Custom` restrictions
+    /// are opaque (they may e.g. hold the protocol that a port match
+    /// depends on), hence act as barriers: only the restrictions
+    /// between them are sorted.
+    pub fn canonicalize(&mut self) {
+        for run in self
+            .restrictions
+            .split_mut(|r| matches!(r, Restriction::Custom(_)))
+        {
+            run.sort_by_key(|r| r.canonical_rank());
+        }
+    }
+
+    /// The same rule, with `prefix` prepended to its custom chain
+    /// and to custom jump or goto targets.
+    pub fn with_chain_prefix(self, prefix: &str) -> Self {
+        Rule {
+            chain: self.chain.with_prefix(prefix),
+            restrictions: self.restrictions,
+            rule_action: self.rule_action.with_chain_prefix(prefix),
+        }
+    }
+
+    /// Check for mistakes that iptables would only report when
+    /// running the command: port restrictions (including a `Custom`
+    /// multiport match) require a tcp, udp, udplite, sctp or dccp
+    /// protocol restriction on the same rule, bpf and u32 matches
+    /// need a non-empty expression, ports and addrtype matches a
+    /// source and/or destination port or type, vlan matches a valid
+    /// id and/or priority, dscp matches a value up to `DSCP_MAX`,
+    /// and connbytes matches a non-empty range. `RuleAction::CtHelper`
+    /// must name one of `CT_HELPERS`, and the prefix of
+    /// `RuleAction::NfLog` must not exceed `NFLOG_PREFIX_MAX_LEN`.
+    pub fn validate(&self) -> Result<()> {
+        if let RuleAction::CtHelper(helper) = &self.rule_action {
+            if !CT_HELPERS.contains(&helper.as_str()) {
+                bail!(
+                    "rule in chain {:?}: unknown conntrack helper {helper:?}, \
+                     expecting one of {}",
+                    self.chain.chain_name(),
+                    CT_HELPERS.join(", ")
+                );
             }
-            Effect::Deletion => run(false)?,
         }
-        Ok(())
+        if let RuleAction::NfLog {
+            prefix: Some(prefix),
+            ..
+        } = &self.rule_action
+        {
+            if prefix.len() > NFLOG_PREFIX_MAX_LEN {
+                bail!(
+                    "rule in chain {:?}: nflog prefix {prefix:?} is longer than \
+                     {NFLOG_PREFIX_MAX_LEN} bytes",
+                    self.chain.chain_name()
+                );
+            }
+        }
+        for r in &self.restrictions {
+            if let Restriction::Vlan { id, prio, .. } = r {
+                let chain = self.chain.chain_name();
+                if id.is_none() && prio.is_none() {
+                    bail!("rule in chain {chain:?}: vlan match without id or priority");
+                }
+                if let Some(id) = id.filter(|id| *id > VLAN_ID_MAX) {
+                    bail!("rule in chain {chain:?}: vlan id {id} is above {VLAN_ID_MAX}");
+                }
+                if let Some(prio) = prio.filter(|prio| *prio > VLAN_PRIO_MAX) {
+                    bail!("rule in chain {chain:?}: vlan priority {prio} is above {VLAN_PRIO_MAX}");
+                }
+            }
+            if let Restriction::Ports {
+                sport: None,
+                dport: None,
+                ..
+            } = r
+            {
+                bail!(
+                    "rule in chain {:?}: ports match without source or destination port",
+                    self.chain.chain_name()
+                );
+            }
+            if let Restriction::AddrType(AddrTypeMatch {
+                src_type: None,
+                dst_type: None,
+                ..
+            }) = r
+            {
+                bail!(
+                    "rule in chain {:?}: addrtype match without source or destination type",
+                    self.chain.chain_name()
+                );
+            }
+            if let Restriction::Dscp(_, dscp) = r {
+                if *dscp > DSCP_MAX {
+                    bail!(
+                        "rule in chain {:?}: dscp value {dscp} is above {DSCP_MAX}",
+                        self.chain.chain_name()
+                    );
+                }
+            }
+            if let Restriction::Spi(_, _) = r {
+                if !self.restrictions.iter().any(|r| {
+                    matches!(
+                        r,
+                        Restriction::Protocol(Negatable::Is, Protocol::Esp | Protocol::Ah)
+                    )
+                }) {
+                    bail!(
+                        "rule in chain {:?}: an SPI match requires an esp or ah protocol \
+                         restriction",
+                        self.chain.chain_name()
+                    );
+                }
+            }
+            if let Restriction::TcpOption(_, _) = r {
+                if !self
+                    .restrictions
+                    .contains(&Restriction::Protocol(Negatable::Is, Protocol::Tcp))
+                {
+                    bail!(
+                        "rule in chain {:?}: --tcp-option requires a tcp protocol restriction",
+                        self.chain.chain_name()
+                    );
+                }
+            }
+            if let Restriction::ConnBytes {
+                range: (from, Some(to)),
+                ..
+            } = r
+            {
+                if to < from {
+                    bail!(
+                        "rule in chain {:?}: connbytes range {from}:{to} is empty",
+                        self.chain.chain_name()
+                    );
+                }
+            }
+        }
+        for r in &self.restrictions {
+            let module = match r {
+                Restriction::Bpf(e) if e.trim().is_empty() => "bpf",
+                Restriction::U32(e) if e.trim().is_empty() => "u32",
+                _ => continue,
+            };
+            bail!(
+                "rule in chain {:?}: empty expression for the {module} match",
+                self.chain.chain_name()
+            );
+        }
+        let has_port_protocol = self.restrictions.iter().any(|r| {
+            matches!(
+                r,
+                Restriction::Protocol(
+                    Negatable::Is,
+                    Protocol::Tcp
+                        | Protocol::Udp
+                        | Protocol::Udplite
+                        | Protocol::Sctp
+                        | Protocol::Dccp
+                )
+            )
+        });
+        if has_port_protocol {
+            return Ok(());
+        }
+        for r in &self.restrictions {
+            let option = match r {
+                Restriction::SourcePort(_, _) => "--sport",
+                Restriction::DestinationPort(_, _) => "--dport",
+                Restriction::Ports { .. } => "--sport/--dport",
+                Restriction::SourcePorts(_, _) | Restriction::DestinationPorts(_, _) => {
+                    "-m multiport"
+                }
+                Restriction::Custom(args) if args.iter().any(|a| a == "multiport") => {
+                    "-m multiport"
+                }
+                _ => continue,
+            };
+            bail!(
+                "rule in chain {:?}: {option} requires a tcp, udp, udplite, sctp or \
+                 dccp protocol restriction",
+                self.chain.chain_name()
+            );
+        }
+        Ok(())
+    }
+}
+
+pub trait RuleTrait: Debug {
+    /// For cloning via `Box<dyn RuleTrait>`.
+    fn clone_box(&self) -> Box<dyn RuleTrait>;
+    fn cmd_args(&self, action: AnyAction) -> Vec<String>;
+    fn validate(&self) -> Result<()>;
+    fn table_and_chain_names(&self) -> (String, String);
+    fn restrictions(&self) -> &[Restriction];
+    /// The rule action rendered via its `Display`, empty for
+    /// `RuleAction::None`.
+    fn rule_action_string(&self) -> String;
+    /// The arguments for the rule action, empty for
+    /// `RuleAction::None`.
+    fn rule_action_args(&self) -> Vec<String>;
+    /// `RuleAction::is_terminating`.
+    fn rule_action_is_terminating(&self) -> bool;
+    /// The arguments of each restriction, as in `cmd_args`.
+    fn restriction_args(&self) -> Vec<Vec<String>> {
+        self.restrictions()
+            .iter()
+            .map(|r| {
+                let mut out = Vec::new();
+                r.push_args(&mut out);
+                out
+            })
+            .collect()
+    }
+    /// The restrictions rendered for display, as in `cmd_args`.
+    fn restriction_strings(&self) -> Vec<String> {
+        self.restriction_args()
+            .iter()
+            .map(|args| shell_quote_many(args))
+            .collect()
+    }
+    /// Whether `cmd_args` is a full command line instead of arguments
+    /// to iptables (see `CommandHook`).
+    fn is_command(&self) -> bool {
+        false
+    }
+}
+
+impl Clone for Box<dyn RuleTrait> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+impl<C: TablechainTrait + 'static> RuleTrait for Rule<C> {
+    fn clone_box(&self) -> Box<dyn RuleTrait> {
+        Box::new(self.clone())
+    }
+    fn cmd_args(&self, action: AnyAction) -> Vec<String> {
+        self.cmd_args(action)
+    }
+    fn validate(&self) -> Result<()> {
+        self.validate()
+    }
+    fn table_and_chain_names(&self) -> (String, String) {
+        self.chain.table_and_chain_names()
+    }
+    fn restrictions(&self) -> &[Restriction] {
+        &self.restrictions
+    }
+    fn restriction_args(&self) -> Vec<Vec<String>> {
+        self.restriction_args()
+    }
+    fn rule_action_string(&self) -> String {
+        self.rule_action.to_string()
+    }
+    fn rule_action_args(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        self.rule_action.push_args(&mut out);
+        out
+    }
+    fn rule_action_is_terminating(&self) -> bool {
+        self.rule_action.is_terminating()
+    }
+}
+
+/// An arbitrary command (e.g. `sysctl -w net.ipv4.ip_forward=1`) run
+/// as part of a plan, see `IptablesWriter::push_command`. It runs
+/// `cmd` for creating actions, and `inverse` (if given) for deleting
+/// ones. Its table and chain names are empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandHook {
+    pub cmd: Vec<String>,
+    pub inverse: Option<Vec<String>>,
+}
+
+impl RuleTrait for CommandHook {
+    fn clone_box(&self) -> Box<dyn RuleTrait> {
+        Box::new(self.clone())
+    }
+    fn cmd_args(&self, action: AnyAction) -> Vec<String> {
+        match action {
+            AnyAction::Creation(_) => self.cmd.clone(),
+            AnyAction::Deletion(_) => self.inverse.clone().unwrap_or_default(),
+            AnyAction::Check => Vec::new(),
+        }
+    }
+    fn validate(&self) -> Result<()> {
+        if self.cmd.is_empty() || self.inverse.as_ref().is_some_and(|c| c.is_empty()) {
+            bail!("empty command in {self:?}");
+        }
+        Ok(())
+    }
+    fn table_and_chain_names(&self) -> (String, String) {
+        (String::new(), String::new())
+    }
+    fn restrictions(&self) -> &[Restriction] {
+        &[]
+    }
+    fn rule_action_string(&self) -> String {
+        String::new()
+    }
+    fn rule_action_args(&self) -> Vec<String> {
+        Vec::new()
+    }
+    fn rule_action_is_terminating(&self) -> bool {
+        false
+    }
+    fn is_command(&self) -> bool {
+        true
+    }
+}
+
+/// Which address of a packet to restrict, see
+/// `IptablesWriter::push_for_host`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSide {
+    Source,
+    Destination,
+}
+
+/// Resolve `host` (a name or an address) via the system resolver, to
+/// host networks (`/32` or `/128`) without duplicates, for
+/// `IptablesWriter::push_for_host`.
+pub fn resolve_host(host: &str) -> Result<Vec<IpNet>> {
+    let mut nets = Vec::new();
+    for addr in (host, 0)
+        .to_socket_addrs()
+        .with_context(|| format!("resolving host {host:?}"))?
+    {
+        let net = IpNet::from(addr.ip());
+        if !nets.contains(&net) {
+            nets.push(net);
+        }
+    }
+    Ok(nets)
+}
+
+/// The options taking an address, see `strip_host_prefixes`.
+const ADDRESS_OPTIONS: &[&str] = &[
+    "-s",
+    "--source",
+    "-d",
+    "--destination",
+    "--ctorigsrc",
+    "--ctorigdst",
+    "--ctreplsrc",
+    "--ctrepldst",
+];
+
+/// Remove the redundant `/32` or `/128` suffix from single-host
+/// addresses given to address options, e.g. `-s 10.0.0.1/32` becomes
+/// `-s 10.0.0.1`. Real prefixes are left as they are.
+pub fn strip_host_prefixes(args: &mut [String]) {
+    for i in 1..args.len() {
+        if ADDRESS_OPTIONS.contains(&args[i - 1].as_str()) {
+            if let Ok(net) = args[i].parse::<IpNet>() {
+                if net.prefix_len() == net.max_prefix_len() {
+                    args[i] = net.addr().to_string();
+                }
+            }
+        }
+    }
+}
+
+/// A rule rendered with `strip_host_prefixes` applied, see
+/// `IptablesWriter::with_bare_host_addresses`.
+#[derive(Debug)]
+struct BareHostAddresses(Box<dyn RuleTrait>);
+
+impl RuleTrait for BareHostAddresses {
+    fn clone_box(&self) -> Box<dyn RuleTrait> {
+        Box::new(BareHostAddresses(self.0.clone_box()))
+    }
+    fn cmd_args(&self, action: AnyAction) -> Vec<String> {
+        let mut args = self.0.cmd_args(action);
+        strip_host_prefixes(&mut args);
+        args
+    }
+    fn validate(&self) -> Result<()> {
+        self.0.validate()
+    }
+    fn table_and_chain_names(&self) -> (String, String) {
+        self.0.table_and_chain_names()
+    }
+    fn restrictions(&self) -> &[Restriction] {
+        self.0.restrictions()
+    }
+    fn rule_action_string(&self) -> String {
+        self.0.rule_action_string()
+    }
+    fn rule_action_args(&self) -> Vec<String> {
+        self.0.rule_action_args()
+    }
+    fn rule_action_is_terminating(&self) -> bool {
+        self.0.rule_action_is_terminating()
+    }
+    fn restriction_args(&self) -> Vec<Vec<String>> {
+        let mut all = self.0.restriction_args();
+        for args in &mut all {
+            strip_host_prefixes(args);
+        }
+        all
+    }
+}
+
+/// Statistics about the rules of an `IptablesWriter`, see
+/// `IptablesWriter::plan_summary`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlanSummary {
+    /// The number of rules per table and chain, in the order of
+    /// first appearance.
+    pub rules_per_chain: Vec<((Table, String), usize)>,
+    /// The number of distinct `InterfaceSpec`s matched by the rules.
+    pub interfaces: usize,
+    /// The number of rules with a terminating action (see
+    /// `RuleAction::is_terminating`).
+    pub terminating: usize,
+    /// The number of the other rules.
+    pub non_terminating: usize,
+}
+
+/// Whether a pushed rule is currently present, see
+/// `IptablesWriter::status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleStatus {
+    pub rule: RuleId,
+    /// The check command that was run.
+    pub cmd: Vec<String>,
+    pub present: bool,
+}
+
+impl std::fmt::Display for RuleStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = if self.present { "present" } else { "absent" };
+        write!(f, "{state}: {}", shell_quote_many(&self.cmd))
+    }
+}
+
+/// A `PlannedCommand` as serialized by `IptablesWriter::write_json`
+/// (as part of a `Plan`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanEntry {
+    /// 1-based position in the plan.
+    pub order: usize,
+    pub table: String,
+    pub chain: String,
+    /// The action as rendered by its `Display` (e.g. "insert 1").
+    pub action: String,
+    /// The full command line.
+    pub cmd: Vec<String>,
+    /// See `PlannedCommand::may_fail`.
+    pub may_fail: bool,
+}
+
+/// The version of the JSON plan format written in `Plan::version`;
+/// increased on incompatible changes.
+pub const PLAN_FORMAT_VERSION: u32 = 1;
+
+/// A plan as serialized by `IptablesWriter::write_json`, which can be
+/// loaded via `Plan::from_json` and run via `Plan::execute`, e.g.
+/// for planning on one host and applying on another.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Plan {
+    /// The `PLAN_FORMAT_VERSION` of the writer.
+    pub version: u32,
+    pub commands: Vec<PlanEntry>,
+}
+
+impl Plan {
+    pub fn new(commands: Vec<PlanEntry>) -> Self {
+        Self {
+            version: PLAN_FORMAT_VERSION,
+            commands,
+        }
+    }
+
+    /// Load a plan written by `IptablesWriter::write_json`, failing
+    /// if it has a different format version or unknown actions.
+    pub fn from_json(json: &str) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: u32,
+        }
+        let Versioned { version } = serde_json::from_str(json)?;
+        if version != PLAN_FORMAT_VERSION {
+            bail!("plan has format version {version}, only {PLAN_FORMAT_VERSION} is supported");
+        }
+        let plan: Plan = serde_json::from_str(json)?;
+        for entry in &plan.commands {
+            if AnyAction::from_display(&entry.action).is_none() {
+                bail!(
+                    "plan entry {}: unknown action {:?}",
+                    entry.order,
+                    entry.action
+                );
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Run the commands of the plan as they are, with failures
+    /// tolerated for the entries that `may_fail` (like `execute` of
+    /// the writer that produced the plan).
+    pub fn execute<O: std::io::Write>(
+        &self,
+        mut verbose_output: Option<O>,
+        executor: &mut dyn Executor<AnyAction>,
+    ) -> Result<()> {
+        for entry in &self.commands {
+            let Some(action) = AnyAction::from_display(&entry.action) else {
+                bail!(
+                    "plan entry {}: unknown action {:?}",
+                    entry.order,
+                    entry.action
+                );
+            };
+            let recreating_mode = if entry.may_fail && action.is_creation() {
+                RecreatingMode::TryCreation
+            } else {
+                RecreatingMode::Owned
+            };
+            let prepared = PreparedCommand {
+                action,
+                recreating_mode,
+                rule: RuleId {
+                    index: entry.order.saturating_sub(1),
+                    table: entry.table.clone(),
+                    chain: entry.chain.clone(),
+                },
+                cmd: entry.cmd.clone(),
+                timing: false,
+                skip: None,
+            };
+            run_command(
+                &prepared,
+                verbose_output
+                    .as_mut()
+                    .map(|o| o as &mut dyn std::io::Write),
+                executor,
+                &ExecuteOptions::default(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// One command of a plan: the action to run for a pushed rule.
+pub struct PlannedCommand<'t> {
+    pub action: AnyAction,
+    pub rule: &'t dyn RuleTrait,
+    pub recreating_mode: RecreatingMode,
+    /// The position of the rule in the order of pushing (0-based).
+    pub index: usize,
+}
+
+impl<'t> PlannedCommand<'t> {
+    pub fn rule_id(&self) -> RuleId {
+        let (table, chain) = self.rule.table_and_chain_names();
+        RuleId {
+            index: self.index,
+            table,
+            chain,
+        }
+    }
+
+    /// The iptables arguments (without the iptables command itself).
+    pub fn cmd_args(&self) -> Vec<String> {
+        self.rule.cmd_args(self.action)
+    }
+
+    /// The command line to run: `iptables_cmd` followed by
+    /// `cmd_args`, or just the latter for a `CommandHook`.
+    pub fn full_cmd(&self, iptables_cmd: &[String]) -> Vec<String> {
+        if self.rule.is_command() {
+            return self.cmd_args();
+        }
+        let mut cmd = iptables_cmd.to_vec();
+        cmd.append(&mut self.cmd_args());
+        cmd
+    }
+
+    /// Whether failure of this command is tolerated: true for
+    /// deletions, and for creations in a `RecreatingMode` that allows
+    /// errors.
+    pub fn may_fail(&self) -> bool {
+        !self.action.is_creation() || self.recreating_mode.allows_errors_on_creation()
+    }
+
+    /// The heading of the section of the verbose output the command
+    /// belongs to (see `ExecuteOptions::sections`): "chain setup" for
+    /// creating and deleting chains, "<interface> rules" for rules
+    /// restricted to an interface, "jumps" for rules jumping to a
+    /// custom chain, "commands" for `CommandHook`s, else "<chain>
+    /// rules".
+    pub fn section(&self) -> String {
+        if self.rule.is_command() {
+            return "commands".into();
+        }
+        if self.action.is_chain_creation()
+            || matches!(
+                self.action,
+                AnyAction::Deletion(DeletionAction::Flush | DeletionAction::DeleteChain)
+            )
+        {
+            return "chain setup".into();
+        }
+        let interface = self.rule.restrictions().iter().find_map(|r| match r {
+            Restriction::Interface(_, spec) => Some(spec.to_arg()),
+            _ => None,
+        });
+        if let Some(interface) = interface {
+            return format!("{interface} rules");
+        }
+        match &self.rule.rule_action_args()[..] {
+            // Built-in targets are written in upper case
+            [j, target] if (j == "-j" || j == "-g") && target.to_uppercase() != *target => {
+                "jumps".into()
+            }
+            _ => format!("{} rules", self.rule.table_and_chain_names().1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultInterpretation {
+    /// Undoubtable success.
+    Ok,
+    /// Error that happens when a rule that should be deleted doesn't
+    /// exist.
+    OkForDeletions,
+    /// A chain couldn't be deleted because it is in use
+    ChainInUse,
+    /// A chain couldn't be created because it already exists
+    ChainAlreadyExists,
+    /// Unrecoverable error
+    Err,
+}
+
+/// The exit codes of iptables (from `enum xtables_exittype` in
+/// `xtables.h`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IptablesExitCode {
+    /// 0
+    Success,
+    /// 1: various problems, also a missing rule or (for some
+    /// actions) chain, or a chain that already exists.
+    OtherProblem,
+    /// 2: invalid command line, also used for some missing chains or
+    /// targets.
+    ParameterProblem,
+    /// 3: incompatible kernel/library version, or a table that
+    /// can't be initialized.
+    VersionProblem,
+    /// 4: insufficient resources or permissions (e.g. not running as
+    /// root), or "Device or resource busy" when deleting a chain
+    /// that, while empty, is still referenced.
+    ResourceProblem,
+    /// Any other code.
+    Other(i32),
+}
+
+impl From<i32> for IptablesExitCode {
+    fn from(code: i32) -> Self {
+        match code {
+            0 => Self::Success,
+            1 => Self::OtherProblem,
+            2 => Self::ParameterProblem,
+            3 => Self::VersionProblem,
+            4 => Self::ResourceProblem,
+            _ => Self::Other(code),
+        }
+    }
+}
+
+impl IptablesExitCode {
+    /// What an exit with this code and the given (combined) output
+    /// means for the execution of a plan.
+    pub fn classify(self, output: &str) -> ResultInterpretation {
+        match self {
+            Self::Success => ResultInterpretation::Ok,
+            Self::ResourceProblem
+                if output.contains("CHAIN_DEL failed (Device or resource busy)") =>
+            {
+                ResultInterpretation::ChainInUse
+            }
+            Self::OtherProblem if output.contains("Chain already exists") => {
+                ResultInterpretation::ChainAlreadyExists
+            }
+            // It appears that iptables exits with code 1 *or* 2 when
+            // chain doesn't exist, 1 for rule that doesn't exist.
+            Self::OtherProblem | Self::ParameterProblem => ResultInterpretation::OkForDeletions,
+            Self::VersionProblem | Self::ResourceProblem | Self::Other(_) => {
+                ResultInterpretation::Err
+            }
+        }
+    }
+}
+
+/// `IptablesExitCode::classify` for a numeric exit `code`.
+pub fn classify(code: i32, output: &str) -> ResultInterpretation {
+    IptablesExitCode::from(code).classify(output)
+}
+
+impl<'t> From<&ExecutorResult<'t>> for ResultInterpretation {
+    fn from(result: &ExecutorResult<'t>) -> Self {
+        match result.status {
+            ExecutorStatus::Success => Self::Ok,
+            ExecutorStatus::ExitCode(code) => classify(code, &result.combined_output),
+            ExecutorStatus::Signal(_) => Self::Err,
+            ExecutorStatus::ExecFailure(_) => Self::Err,
+        }
+    }
+}
+
+/// Interpret the result of a check (`-C`) action: whether the rule
+/// is present. iptables exits with code 1 if the rule (or its chain)
+/// doesn't exist; other failures are errors.
+pub fn check_interpretation(result: &ExecutorResult) -> Result<bool> {
+    match result.status {
+        ExecutorStatus::Success => Ok(true),
+        ExecutorStatus::ExitCode(1) => Ok(false),
+        _ => {
+            result.to_anyhow(Some("for check action"))?;
+            unreachable!("to_anyhow returns an error for failures")
+        }
+    }
+}
+
+/// Run the check (`-C`) action for `rule`, returning whether it is
+/// present.
+pub fn rule_exists(
+    executor: &mut dyn Executor<AnyAction>,
+    iptables_cmd: &[String],
+    rule: &dyn RuleTrait,
+) -> Result<bool> {
+    let mut cmd = iptables_cmd.to_vec();
+    cmd.append(&mut rule.cmd_args(AnyAction::Check));
+    check_interpretation(&executor.execute(AnyAction::Check, &cmd))
+}
+
+/// The ICMPv6 types that an IPv6 host must accept for the network to
+/// work: error messages, neighbor and router discovery (NDP), and
+/// multicast listener discovery (MLD, given by number).
+pub const ICMPV6_ESSENTIAL_TYPES: &[&str] = &[
+    "destination-unreachable",
+    "packet-too-big",
+    "time-exceeded",
+    "parameter-problem",
+    "router-solicitation",
+    "router-advertisement",
+    "neighbour-solicitation",
+    "neighbour-advertisement",
+    "130", // MLD query
+    "131", // MLD report
+    "132", // MLD done
+    "143", // MLDv2 report
+];
+
+/// The default port for `IptablesWriter::allow_ssh_from`.
+pub const SSH_PORT: u16 = 22;
+
+/// The `recent` list used by `IptablesWriter::ssh_bruteforce_protection`.
+pub const SSH_BRUTEFORCE_LIST: &str = "SSH";
+
+/// `IptablesWriter::ssh_bruteforce_protection` drops new SSH
+/// connections from addresses that already made
+/// `SSH_BRUTEFORCE_HITCOUNT` of them within `SSH_BRUTEFORCE_SECONDS`.
+pub const SSH_BRUTEFORCE_SECONDS: u32 = 60;
+pub const SSH_BRUTEFORCE_HITCOUNT: u32 = 4;
+
+/// The rate of the LOG rule appended by
+/// `IptablesWriter::finalize_chain` (unless `with_auto_limit_logs` was
+/// used).
+pub const FINALIZE_LOG_RATE: &str = "5/minute";
+
+#[derive(Debug, Clone)]
+pub struct IptablesWriter {
+    iptables_cmd: Vec<String>,
+    actions: Vec<(AnyAction, Box<dyn RuleTrait>, RecreatingMode)>,
+    /// The indices into `actions` of the rules pushed via
+    /// `push_prioritized`, with their priority.
+    priorities: Vec<(usize, i32)>,
+    default_mode: RecreatingMode,
+    canonicalize: bool,
+    bare_host_addresses: bool,
+    source_comments: bool,
+    chain_prefix: String,
+    timing: bool,
+    allow_duplicate_inserts: bool,
+    auto_limit_logs: Option<String>,
+    /// Lookup of the existing interfaces, if rules for missing ones
+    /// should be skipped.
+    skip_missing_interfaces: Option<fn() -> Result<Vec<String>>>,
+    max_rules: Option<usize>,
+    /// If set, rules without a rule action are only accepted in these
+    /// chains.
+    require_action: Option<Vec<(Table, String)>>,
+    /// The chains whose rules `write_iptables_save` sorts.
+    sorted_save_chains: Vec<(Table, String)>,
+}
+
+/// What end result you want: Deletion inverts the result of an
+/// action. Recreation first deletes then creates. Creation just runs
+/// the originally specified action (rarely what you want). Check
+/// only runs `-C` for the rules Creation would add, changing nothing
+/// (see `IptablesWriter::check`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    Creation,
+    Recreation,
+    Deletion,
+    Check,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecreatingMode {
+    /// Always create and always delete
+    Owned,
+    /// Try to create but don't given an error if that fails. Always
+    /// delete. Useful when trying to add rules onto a chain by
+    /// another entity that might not exist in all cases.
+    TryCreation,
+    /// Try to create but don't give an error if that fails, never
+    /// delete. Perhaps useful for creating chains that should stay
+    /// around when stopping the firewall, hence subsequent creation
+    /// mustn't fail. This can't be used for creating match rules (but
+    /// only chains) as each time the firewall is restarted it would
+    /// add/insert another copy of it.
+    TryCreationNoDeletion,
+}
+
+impl RecreatingMode {
+    pub fn allows_errors_on_creation(&self) -> bool {
+        match self {
+            RecreatingMode::Owned => false,
+            RecreatingMode::TryCreation => true,
+            RecreatingMode::TryCreationNoDeletion => true,
+        }
+    }
+}
+
+impl IptablesWriter {
+    pub fn new(iptables_cmd: Vec<String>) -> Self {
+        Self {
+            iptables_cmd,
+            actions: Vec::new(),
+            priorities: Vec::new(),
+            default_mode: RecreatingMode::Owned,
+            canonicalize: false,
+            bare_host_addresses: false,
+            source_comments: false,
+            chain_prefix: String::new(),
+            timing: false,
+            allow_duplicate_inserts: false,
+            auto_limit_logs: None,
+            skip_missing_interfaces: None,
+            max_rules: None,
+            require_action: None,
+            sorted_save_chains: Vec::new(),
+        }
+    }
+
+    /// Prepend `prefix` (e.g. `myapp-`) to the names of all custom
+    /// chains of the rules pushed afterwards, including jump and goto
+    /// targets, to avoid collisions with chains of other tools.
+    /// Built-in chains are left untouched.
+    ///
+    /// The prefix is applied when pushing rather than when rendering:
+    /// `push` erases the chain type of the rule (storing it as a
+    /// `Box<dyn RuleTrait>`), and `TablechainTrait::with_prefix` needs
+    /// that type. Applying it once there also means that everything
+    /// looking at the stored rules (plans, `iptables-save` output,
+    /// verification, summaries, `with_required_action` and
+    /// `with_sorted_save_chains`) sees the same, final chain names.
+    pub fn with_chain_prefix(mut self, prefix: &str) -> Self {
+        self.chain_prefix = prefix.into();
+        self
+    }
+
+    /// Add a `Restriction::Limit` with `rate` to all LOG rules pushed
+    /// afterwards that don't have a limit match yet, to avoid log
+    /// floods.
+    pub fn with_auto_limit_logs(mut self, rate: &str) -> Self {
+        self.auto_limit_logs = Some(rate.into());
+        self
+    }
+
+    /// Refuse to validate or execute if more than `max` rules (or
+    /// commands) were pushed, as a safety valve against runaway
+    /// generation of rules.
+    pub fn with_max_rules(mut self, max: usize) -> Self {
+        self.max_rules = Some(max);
+        self
+    }
+
+    /// Error for the first non-creating action (see `_push`) if
+    /// `want` deletes, since `build_plan` can't derive its deletion.
+    fn check_deletable(&self, want: Effect) -> Result<(), FirewallError> {
+        if !matches!(want, Effect::Recreation | Effect::Deletion) {
+            return Ok(());
+        }
+        for (action, rule, recreating_mode) in &self.actions {
+            if !action.is_creation() && *recreating_mode != RecreatingMode::TryCreationNoDeletion {
+                let (table, chain) = rule.table_and_chain_names();
+                return Err(FirewallError::NonCreatingAction {
+                    action: *action,
+                    table,
+                    chain,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn check_rule_count(&self) -> Result<(), FirewallError> {
+        match self.max_rules {
+            Some(max) if self.actions.len() > max => Err(FirewallError::TooManyRules {
+                count: self.actions.len(),
+                max,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// When executing, skip the rules restricted to an exact interface
+    /// name that isn't among those returned by `lookup` (typically
+    /// `network_interfaces::find_all_network_interfaces`), e.g. for
+    /// VPN interfaces that only exist while the VPN is up. They are
+    /// listed in the verbose output, and by `skipped_commands`.
+    pub fn with_skip_missing_interfaces(mut self, lookup: fn() -> Result<Vec<String>>) -> Self {
+        self.skip_missing_interfaces = Some(lookup);
+        self
+    }
+
+    /// Whether `validate` should accept several `Insert` actions with
+    /// the same index into the same chain (see there).
+    pub fn with_duplicate_inserts_allowed(mut self, allow: bool) -> Self {
+        self.allow_duplicate_inserts = allow;
+        self
+    }
+
+    /// Have `validate` reject appended or inserted rules with
+    /// `RuleAction::None` (which often is a forgotten action), except
+    /// in the given chains, e.g. ones holding pure-match rules for
+    /// counting packets. Chain names are the final ones, i.e.
+    /// including the prefix set via `with_chain_prefix`.
+    pub fn with_required_action(mut self, exempt_chains: Vec<(Table, String)>) -> Self {
+        self.require_action = Some(exempt_chains);
+        self
+    }
+
+    /// Have `write_iptables_save` emit the rules of the given chains
+    /// sorted by their line (bytewise), so that the output doesn't
+    /// depend on the order of pushing, e.g. for keeping it in version
+    /// control. Only for chains whose rules are independent of their
+    /// order (e.g. all accepting disjoint traffic); other chains keep
+    /// the order of pushing. Chain names are the final ones, i.e.
+    /// including the prefix set via `with_chain_prefix`.
+    pub fn with_sorted_save_chains(mut self, chains: Vec<(Table, String)>) -> Self {
+        self.sorted_save_chains = chains;
+        self
+    }
+
+    /// Whether to append the duration of each command (e.g.
+    /// `(12ms)`) to its line in the verbose output of `execute`.
+    pub fn with_timing(mut self, timing: bool) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    /// Whether to render single-host addresses of the rules pushed
+    /// afterwards without the redundant `/32` or `/128` suffix (see
+    /// `strip_host_prefixes`), for more readable scripts. iptables
+    /// accepts both forms.
+    pub fn with_bare_host_addresses(mut self, bare: bool) -> Self {
+        self.bare_host_addresses = bare;
+        self
+    }
+
+    /// `rule` (e.g. from `rules_from_save`, which keeps the host
+    /// prefixes) as an appending `iptables-save` line, with the host
+    /// addresses rendered like this writer does (see
+    /// `with_bare_host_addresses`), for comparing it with the pushed
+    /// rules.
+    pub(crate) fn save_line(&self, rule: &dyn RuleTrait) -> String {
+        let mut args = rule.cmd_args(Action::Append.into());
+        if self.bare_host_addresses {
+            strip_host_prefixes(&mut args);
+        }
+        save_quote_many(&args)
+    }
+
+    /// Whether to add a `Restriction::Comment` with the source
+    /// location (`file:line`) of the `push` call to the rules
+    /// (appended or inserted) pushed afterwards, for tracing rules in
+    /// the kernel back to the code that generated them.
+    pub fn with_source_comments(mut self, source_comments: bool) -> Self {
+        self.source_comments = source_comments;
+        self
+    }
+
+    /// Whether to bring the restrictions of rules into canonical
+    /// order when pushing them (see `Rule::canonicalize`).
+    pub fn with_canonicalization(mut self, canonicalize: bool) -> Self {
+        self.canonicalize = canonicalize;
+        self
+    }
+
+    /// Set the `RecreatingMode` used by `push_default` (`Owned` if
+    /// not set).
+    pub fn with_default_mode(mut self, mode: RecreatingMode) -> Self {
+        self.default_mode = mode;
+        self
+    }
+
+    pub fn iptables_cmd(&self) -> &[String] {
+        &self.iptables_cmd
+    }
+
+    pub fn default_mode(&self) -> RecreatingMode {
+        self.default_mode
+    }
+
+    /// The `RecreatingMode` of the rule pushed as the `index`th
+    /// (0-based).
+    pub fn recreating_mode(&self, index: usize) -> Option<RecreatingMode> {
+        self.actions.get(index).map(|(_, _, mode)| *mode)
+    }
+
+    /// Pushes the rule with the corresponding action regardless of
+    /// whether the action is creative or other. You usually don't
+    /// want to use this, but rather `push` instead.
+    #[track_caller]
+    pub fn _push<T: TablechainTrait + 'static>(
+        &mut self,
+        action: AnyAction,
+        mut rule: Rule<T>,
+        recreating_mode: RecreatingMode,
+    ) {
+        let location = std::panic::Location::caller();
+        if !self.chain_prefix.is_empty() {
+            rule = rule.with_chain_prefix(&self.chain_prefix);
+        }
+        if let RuleAction::RejectWith(with) = rule.rule_action {
+            rule.rule_action = RuleAction::RejectWith(with.for_family(self.is_ipv6()));
+        }
+        if let Some(rate) = &self.auto_limit_logs {
+            let has_limit = rule.restrictions.iter().any(|r| match r {
+                Restriction::Limit(_) => true,
+                Restriction::Custom(args) => args.iter().any(|a| a == "limit"),
+                _ => false,
+            });
+            if matches!(rule.rule_action, RuleAction::Log(_)) && !has_limit {
+                rule.restrictions.push(Restriction::Limit(rate.clone()));
+            }
+        }
+        if self.source_comments
+            && matches!(
+                action,
+                AnyAction::Creation(Action::Append | Action::Insert(_))
+            )
+        {
+            rule.restrictions.push(Restriction::Comment(format!(
+                "{}:{}",
+                location.file(),
+                location.line()
+            )));
+        }
+        if self.canonicalize {
+            rule.canonicalize();
+        }
+        let rule: Box<dyn RuleTrait> = if self.bare_host_addresses {
+            Box::new(BareHostAddresses(Box::new(rule)))
+        } else {
+            Box::new(rule)
+        };
+        self.actions.push((action, rule, recreating_mode));
+    }
+
+    /// Push a deletion of the rule at the 1-based position `num` of
+    /// `chain` (`-D <chain> <num>`). Like other non-creating actions
+    /// pushed via `_push`, this is only run with `Effect::Creation`.
+    #[track_caller]
+    pub fn push_delete_by_num<T: TablechainTrait + 'static>(&mut self, chain: T, num: u32) {
+        self._push(
+            DeletionAction::DeleteByNum(num).into(),
+            Rule {
+                chain,
+                restrictions: vec![],
+                rule_action: RuleAction::None,
+            },
+            RecreatingMode::Owned,
+        );
+    }
+
+    /// Push a rule with creative action. Because deleting actions are
+    /// usually done via running `execute` with an Effect that deletes
+    /// (including recreation), they are automatically derived
+    /// there. Also, deleting `Effect`s lead to the reversal of the
+    /// order of rule application; hence call `push` always in the
+    /// order appropriate for the creation of rules.
+    #[track_caller]
+    pub fn push<T: TablechainTrait + 'static>(
+        &mut self,
+        action: Action,
+        rule: Rule<T>,
+        recreating_mode: RecreatingMode,
+    ) {
+        self._push(action.into(), rule, recreating_mode);
+    }
+
+    /// For a dry_run; don't use as shell code, use execute (that can
+    /// use cmd_args directly)! -- todo: needs to be updated with an
+    /// Effect
+    pub fn to_string(&self) -> String {
+        let mut out = String::new();
+        for (action, rule, _) in &self.actions {
+            for arg in rule.cmd_args(*action) {
+                out.push_str(" ");
+                out.push_str(&arg);
+            }
+            out.push_str("\n");
+        }
+        out
+    }
+
+    /// `push` with the writer's default `RecreatingMode` (see
+    /// `with_default_mode`).
+    #[track_caller]
+    pub fn push_default<T: TablechainTrait + 'static>(&mut self, action: Action, rule: Rule<T>) {
+        self.push(action, rule, self.default_mode);
+    }
+
+    /// Append rules accepting each of `ICMPV6_ESSENTIAL_TYPES` on
+    /// `chain` (for an ip6tables writer).
+    #[track_caller]
+    pub fn allow_icmpv6_essentials<T: TablechainTrait + Clone + 'static>(&mut self, chain: T) {
+        for icmpv6_type in ICMPV6_ESSENTIAL_TYPES {
+            self.push(
+                Action::Append,
+                Rule {
+                    chain: chain.clone(),
+                    restrictions: vec![
+                        Restriction::Protocol(Negatable::Is, Protocol::Icmpv6),
+                        Restriction::Icmpv6Type(Negatable::Is, icmpv6_type.to_string()),
+                    ],
+                    rule_action: RuleAction::Accept,
+                },
+                RecreatingMode::Owned,
+            );
+        }
+    }
+
+    /// Insert a rule at position 1 of `chain` dropping packets with
+    /// conntrack state INVALID. Being pushed like any other rule, it
+    /// is deleted in reverse order on teardown like the rest.
+    #[track_caller]
+    pub fn drop_invalid_first<T: TablechainTrait + 'static>(&mut self, chain: T) {
+        self.push(
+            Action::Insert(1),
+            Rule {
+                chain,
+                restrictions: vec![Restriction::ConnState(
+                    Negatable::Is,
+                    vec![ConnState::Invalid],
+                )],
+                rule_action: RuleAction::Drop,
+            },
+            RecreatingMode::Owned,
+        );
+    }
+
+    /// Whether the writer runs `ip6tables` (judged by the file name
+    /// of the command or any of its wrapper arguments).
+    pub(crate) fn is_ipv6(&self) -> bool {
+        self.iptables_cmd.iter().any(|arg| {
+            Path::new(arg)
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("ip6tables"))
+        })
+    }
+
+    /// Append a rule accepting ICMP echo requests ("ping") at most at
+    /// `rate` (e.g. "1/second", see `Restriction::Limit`); uses
+    /// icmpv6 for an ip6tables writer, icmp otherwise.
+    #[track_caller]
+    pub fn allow_limited_ping<T: TablechainTrait + 'static>(&mut self, chain: T, rate: &str) {
+        let (protocol, icmp_type) = if self.is_ipv6() {
+            (
+                Protocol::Icmpv6,
+                Restriction::Icmpv6Type(Negatable::Is, "echo-request".into()),
+            )
+        } else {
+            (
+                Protocol::Icmp,
+                Restriction::IcmpType(Negatable::Is, "echo-request".into()),
+            )
+        };
+        self.push(
+            Action::Append,
+            Rule {
+                chain,
+                restrictions: vec![
+                    Restriction::Protocol(Negatable::Is, protocol),
+                    icmp_type,
+                    Restriction::Limit(rate.into()),
+                ],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::Owned,
+        );
+    }
+
+    /// Append a rule accepting new SSH connections from `src` on
+    /// `port` (default 22), on any interface; e.g. as the bootstrap
+    /// rule before locking down a new server. Fails if `src` is not
+    /// of the writer's address family.
+    #[track_caller]
+    pub fn allow_ssh_from<T: TablechainTrait + 'static>(
+        &mut self,
+        chain: T,
+        src: IpNet,
+        port: Option<u16>,
+    ) -> Result<()> {
+        if matches!(src, IpNet::V6(_)) != self.is_ipv6() {
+            bail!(
+                "address {src} does not match the address family of {:?}",
+                self.iptables_cmd.join(" ")
+            );
+        }
+        self.push(
+            Action::Append,
+            Rule {
+                chain,
+                restrictions: vec![
+                    Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                    Restriction::DestinationPort(Negatable::Is, port.unwrap_or(SSH_PORT)),
+                    Restriction::SourceAddress(Negatable::Is, src),
+                    Restriction::ConnState(Negatable::Is, vec![ConnState::New]),
+                ],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::Owned,
+        );
+        Ok(())
+    }
+
+    /// Append the two rules of the usual `-m recent` idiom against
+    /// SSH brute-forcing: recording the source of each new connection
+    /// to port 22 in `SSH_BRUTEFORCE_LIST`, then dropping it if the
+    /// source was seen `SSH_BRUTEFORCE_HITCOUNT` times within
+    /// `SSH_BRUTEFORCE_SECONDS`. Push rules accepting SSH after these.
+    #[track_caller]
+    pub fn ssh_bruteforce_protection<T: TablechainTrait + 'static>(&mut self, chain: T) {
+        let rule = |op, rule_action| Rule {
+            chain: chain.clone(),
+            restrictions: vec![
+                Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                Restriction::DestinationPort(Negatable::Is, SSH_PORT),
+                Restriction::ConnState(Negatable::Is, vec![ConnState::New]),
+                Restriction::Recent {
+                    name: SSH_BRUTEFORCE_LIST.into(),
+                    op,
+                    neg: Negatable::Is,
+                },
+            ],
+            rule_action,
+        };
+        self.push(
+            Action::Append,
+            rule(RecentOp::Set, RuleAction::None),
+            RecreatingMode::Owned,
+        );
+        self.push(
+            Action::Append,
+            rule(
+                RecentOp::Update {
+                    seconds: Some(SSH_BRUTEFORCE_SECONDS),
+                    hitcount: Some(SSH_BRUTEFORCE_HITCOUNT),
+                },
+                RuleAction::Drop,
+            ),
+            RecreatingMode::Owned,
+        );
+    }
+
+    /// Likely mistakes that don't make the rules invalid: matches of
+    /// `ConnState::Untracked` without any rule disabling connection
+    /// tracking in the `raw` table (`RuleAction::NoTrack`, or `-j
+    /// NOTRACK`), which never match, and vice versa.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut untracked = Vec::new();
+        let mut notrack = Vec::new();
+        for (action, rule, _) in &self.actions {
+            if rule.is_command() || action.is_chain_creation() {
+                continue;
+            }
+            let (table, chain) = rule.table_and_chain_names();
+            if rule.restrictions().iter().any(|r| {
+                matches!(r, Restriction::ConnState(Negatable::Is, states)
+                         if states.contains(&ConnState::Untracked))
+            }) {
+                untracked.push((table.clone(), chain.clone()));
+            }
+            let disables_tracking = match &rule.rule_action_args()[..] {
+                [j, ct, notrack] => j == "-j" && ct == "CT" && notrack == "--notrack",
+                [j, target] => j == "-j" && target == "NOTRACK",
+                _ => false,
+            };
+            if table == "raw" && disables_tracking {
+                notrack.push(chain);
+            }
+        }
+        let mut warnings = Vec::new();
+        if notrack.is_empty() {
+            for (table, chain) in untracked {
+                warnings.push(format!(
+                    "rule in chain {chain:?} of table {table} matches UNTRACKED \
+                     connections, but no rule in the raw table disables tracking"
+                ));
+            }
+        } else if untracked.is_empty() {
+            for chain in notrack {
+                warnings.push(format!(
+                    "rule in chain {chain:?} of table raw disables tracking, but no \
+                     rule matches UNTRACKED connections"
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// Validate all pushed rules (see `Rule::validate`), reporting
+    /// the first problem found. Also checks the limit set via
+    /// `with_max_rules` and, if enabled via `with_required_action`,
+    /// for missing rule actions. Also reports source, destination and
+    /// conntrack addresses not matching the writer's address family,
+    /// and several `Insert`s with
+    /// the same index into the same chain, unless allowed via
+    /// `with_duplicate_inserts_allowed`.
+    pub fn validate(&self) -> Result<()> {
+        self.check_rule_count()?;
+        for (_, rule, _) in &self.actions {
+            rule.validate()?;
+        }
+        let ipv6 = self.is_ipv6();
+        for (_, rule, _) in &self.actions {
+            for r in rule.restrictions() {
+                let (kind, nets): (_, Vec<&IpNet>) = match r {
+                    Restriction::SourceAddress(_, net) => ("source", vec![net]),
+                    Restriction::DestinationAddress(_, net) => ("destination", vec![net]),
+                    Restriction::Conntrack(m) => ("conntrack", m.addresses().collect()),
+                    _ => continue,
+                };
+                if let Some(net) = nets
+                    .into_iter()
+                    .find(|net| matches!(net, IpNet::V6(_)) != ipv6)
+                {
+                    let (table, chain) = rule.table_and_chain_names();
+                    bail!(
+                        "rule in chain {chain:?} of table {table}: {kind} address \
+                         {net} does not match the address family of {:?}",
+                        self.iptables_cmd.join(" ")
+                    );
+                }
+            }
+        }
+        if let Some(exempt_chains) = &self.require_action {
+            for (action, rule, _) in &self.actions {
+                if rule.is_command() || action.is_chain_creation() {
+                    continue;
+                }
+                if let AnyAction::Creation(_) = action {
+                    if rule.rule_action_args().is_empty() {
+                        let (table, chain) = rule.table_and_chain_names();
+                        let tablechain = (
+                            table_from_name(&table).expect("names generated by def_chain"),
+                            chain,
+                        );
+                        if !exempt_chains.contains(&tablechain) {
+                            let (_, chain) = tablechain;
+                            bail!(
+                                "rule in chain {chain:?} of table {table} has no rule \
+                                 action: {:?}",
+                                rule.cmd_args(*action).join(" ")
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        if !self.allow_duplicate_inserts {
+            let mut inserts: Vec<(String, String, u32)> = Vec::new();
+            for (action, rule, _) in &self.actions {
+                if let AnyAction::Creation(Action::Insert(n)) = action {
+                    let (table, chain) = rule.table_and_chain_names();
+                    let key = (table, chain, *n);
+                    if inserts.contains(&key) {
+                        let (table, chain, n) = key;
+                        bail!(
+                            "multiple rules inserted at index {n} into chain {chain:?} \
+                             of table {table} (the later ones end up before the earlier \
+                             ones); use `with_duplicate_inserts_allowed` if intended"
+                        );
+                    }
+                    inserts.push(key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Append a LOG rule with the given log prefix followed by a DROP
+    /// rule, both with the same restrictions, so that they can't get
+    /// out of sync.
+    #[track_caller]
+    pub fn push_logged_drop<T: TablechainTrait + Clone + 'static>(
+        &mut self,
+        chain: T,
+        restrictions: Vec<Restriction>,
+        prefix: &str,
+    ) {
+        self.push(
+            Action::Append,
+            Rule {
+                chain: chain.clone(),
+                restrictions: restrictions.clone(),
+                rule_action: RuleAction::Log(Some(prefix.into())),
+            },
+            RecreatingMode::Owned,
+        );
+        self.push(
+            Action::Append,
+            Rule {
+                chain,
+                restrictions,
+                rule_action: RuleAction::Drop,
+            },
+            RecreatingMode::Owned,
+        );
+    }
+
+    /// Create `chain` unless it exists already (see
+    /// `Action::EnsureChain`).
+    #[track_caller]
+    pub fn ensure_chain<T: TablechainTrait + 'static>(&mut self, chain: T) {
+        self.push(
+            Action::EnsureChain,
+            Rule {
+                chain,
+                restrictions: vec![],
+                rule_action: RuleAction::None,
+            },
+            RecreatingMode::Owned,
+        );
+    }
+
+    /// Run `cmd` (any command, e.g. a `sysctl` call, not iptables
+    /// arguments) at this point of the plan when creating, and
+    /// `inverse` (if given) when deleting, through the same executor
+    /// and verbose output as the rules.
+    pub fn push_command(&mut self, cmd: Vec<String>, inverse: Option<Vec<String>>) {
+        self.actions.push((
+            Action::Append.into(),
+            Box::new(CommandHook { cmd, inverse }),
+            RecreatingMode::Owned,
+        ));
+    }
+
+    /// Append one rule per protocol in `protocols` (since a rule can
+    /// only match one protocol), each with a `Protocol` restriction
+    /// followed by `restrictions`, e.g. for allowing DNS over tcp and
+    /// udp.
+    #[track_caller]
+    pub fn push_for_protocols<T: TablechainTrait + 'static>(
+        &mut self,
+        chain: T,
+        protocols: &[Protocol],
+        restrictions: Vec<Restriction>,
+        rule_action: RuleAction<T>,
+    ) {
+        for protocol in protocols {
+            let mut all = vec![Restriction::Protocol(Negatable::Is, protocol.clone())];
+            all.extend(restrictions.iter().cloned());
+            self.push(
+                Action::Append,
+                Rule {
+                    chain: chain.clone(),
+                    restrictions: all,
+                    rule_action: rule_action.clone(),
+                },
+                RecreatingMode::Owned,
+            );
+        }
+    }
+
+    /// Append one rule per address of `host` (as returned by
+    /// `resolve`, typically `resolve_host`) of the writer's address
+    /// family, restricting the source or destination (per `side`) to
+    /// it, followed by `restrictions`. Fails if there is no such
+    /// address. Beware that the name is resolved only now, when
+    /// building the rules: later changes of its DNS records are not
+    /// followed, and a spoofed or unavailable DNS response leads to
+    /// wrong rules (or none), so prefer addresses for anything
+    /// security relevant.
+    #[track_caller]
+    pub fn push_for_host<T: TablechainTrait + 'static>(
+        &mut self,
+        chain: T,
+        host: &str,
+        side: AddressSide,
+        resolve: impl FnOnce(&str) -> Result<Vec<IpNet>>,
+        restrictions: Vec<Restriction>,
+        rule_action: RuleAction<T>,
+    ) -> Result<()> {
+        let is_ipv6 = self.is_ipv6();
+        let nets: Vec<IpNet> = resolve(host)?
+            .into_iter()
+            .filter(|net| matches!(net, IpNet::V6(_)) == is_ipv6)
+            .collect();
+        if nets.is_empty() {
+            bail!(
+                "host {host:?} has no {} address",
+                if is_ipv6 { "IPv6" } else { "IPv4" }
+            );
+        }
+        for net in nets {
+            let address = match side {
+                AddressSide::Source => Restriction::SourceAddress(Negatable::Is, net),
+                AddressSide::Destination => Restriction::DestinationAddress(Negatable::Is, net),
+            };
+            let mut all = vec![address];
+            all.extend(restrictions.iter().cloned());
+            self.push(
+                Action::Append,
+                Rule {
+                    chain: chain.clone(),
+                    restrictions: all,
+                    rule_action: rule_action.clone(),
+                },
+                RecreatingMode::Owned,
+            );
+        }
+        Ok(())
+    }
+
+    /// Complete `chain` with a "log and drop everything else": append
+    /// a LOG rule with `log_prefix`, limited to the rate given via
+    /// `with_auto_limit_logs` or else `FINALIZE_LOG_RATE`, and a DROP
+    /// rule, both without further matches.
+    #[track_caller]
+    pub fn finalize_chain<T: TablechainTrait + 'static>(&mut self, chain: T, log_prefix: &str) {
+        let rate = self
+            .auto_limit_logs
+            .clone()
+            .unwrap_or_else(|| FINALIZE_LOG_RATE.into());
+        self.push(
+            Action::Append,
+            Rule {
+                chain: chain.clone(),
+                restrictions: vec![Restriction::Limit(rate)],
+                rule_action: RuleAction::Log(Some(log_prefix.into())),
+            },
+            RecreatingMode::Owned,
+        );
+        self.push(
+            Action::Append,
+            Rule {
+                chain,
+                restrictions: vec![],
+                rule_action: RuleAction::Drop,
+            },
+            RecreatingMode::Owned,
+        );
+    }
+
+    /// Append `rule` to its chain, ordered by `priority` relative to
+    /// the other rules pushed via this method into the same chain:
+    /// `build_plan` (stably) sorts them by descending priority, so
+    /// that higher-priority rules end up earlier in the chain,
+    /// regardless of the order of pushing. They keep the positions
+    /// relative to the other rules, though.
+    #[track_caller]
+    pub fn push_prioritized<T: TablechainTrait + 'static>(
+        &mut self,
+        priority: i32,
+        rule: Rule<T>,
+        recreating_mode: RecreatingMode,
+    ) {
+        self.priorities.push((self.actions.len(), priority));
+        self.push(Action::Append, rule, recreating_mode);
+    }
+
+    /// The indices into `actions` in the order of creation: the order
+    /// of pushing, except for the rules pushed via `push_prioritized`,
+    /// which are sorted by priority among their positions in their
+    /// chain.
+    fn creation_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.actions.len()).collect();
+        let tablechain = |index: usize| self.actions[index].1.table_and_chain_names();
+        let mut tablechains = Vec::new();
+        for (index, _) in &self.priorities {
+            let tablechain = tablechain(*index);
+            if !tablechains.contains(&tablechain) {
+                tablechains.push(tablechain);
+            }
+        }
+        for current in tablechains {
+            let mut rules: Vec<(usize, i32)> = self
+                .priorities
+                .iter()
+                .filter(|(index, _)| tablechain(*index) == current)
+                .copied()
+                .collect();
+            let positions: Vec<usize> = rules.iter().map(|(index, _)| *index).collect();
+            rules.sort_by_key(|(_, priority)| std::cmp::Reverse(*priority));
+            for (position, (index, _)) in positions.into_iter().zip(rules) {
+                order[position] = index;
+            }
+        }
+        order
+    }
+
+    /// The sequence of commands that `execute` runs for the wanted
+    /// Effect, in order. Non-creating actions pushed via `_push` (like
+    /// `push_delete_by_num`) have no inverse, hence are left out of
+    /// the deleting part (`execute` refuses to run such a plan, see
+    /// `FirewallError::NonCreatingAction`).
+    pub fn build_plan(&self, want: Effect) -> Vec<PlannedCommand<'_>> {
+        let mut plan = Vec::new();
+        let order = self.creation_order();
+        let mut run = |creation: bool| {
+            let indices: Box<dyn Iterator<Item = &usize>> = if creation {
+                Box::new(order.iter())
+            } else {
+                Box::new(order.iter().rev())
+            };
+            let actions = indices.map(|index| (*index, &self.actions[*index]));
+
+            for (index, (action, rule, recreating_mode)) in actions {
+                match recreating_mode {
+                    RecreatingMode::Owned => {}
+                    RecreatingMode::TryCreation => {}
+                    RecreatingMode::TryCreationNoDeletion => {
+                        if !creation {
+                            continue;
+                        }
+                    }
+                }
+                if !creation && want == Effect::Recreation && *action == Action::EnsureChain.into()
+                {
+                    continue;
+                }
+                let actions = if creation {
+                    vec![*action]
+                } else {
+                    let AnyAction::Creation(creation_action) = action else {
+                        continue;
+                    };
+                    creation_action
+                        .deletion_sequence()
+                        .iter()
+                        .copied()
+                        .map(AnyAction::from)
+                        .collect()
+                };
+                for action in actions {
+                    if rule.is_command() && rule.cmd_args(action).is_empty() {
+                        // CommandHook without inverse
+                        continue;
+                    }
+                    plan.push(PlannedCommand {
+                        action,
+                        rule: &**rule,
+                        recreating_mode: *recreating_mode,
+                        index,
+                    });
+                }
+            }
+        };
+
+        match want {
+            Effect::Creation => run(true),
+            Effect::Recreation => {
+                run(false);
+                run(true);
+            }
+            Effect::Deletion => run(false),
+            Effect::Check => {
+                for index in order {
+                    let (action, rule, recreating_mode) = &self.actions[index];
+                    // Chain creations can't be checked with `-C`
+                    if !action.is_creation() || action.is_chain_creation() || rule.is_command() {
+                        continue;
+                    }
+                    plan.push(PlannedCommand {
+                        action: AnyAction::Check,
+                        rule: &**rule,
+                        recreating_mode: *recreating_mode,
+                        index,
+                    });
+                }
+            }
+        }
+        plan
+    }
+
+    /// The commands undoing a successful `Effect::Creation`: the
+    /// deletion sequences of the pushed rules in reverse order of
+    /// pushing (the same as `build_plan(Effect::Deletion)`, and the
+    /// first part of `Effect::Recreation`). Rules pushed with
+    /// `RecreatingMode::TryCreationNoDeletion` are left in place.
+    pub fn inverse_plan(&self) -> Vec<PlannedCommand<'_>> {
+        self.build_plan(Effect::Deletion)
+    }
+
+    /// The tables and chains the pushed rules touch, in the order of
+    /// first appearance and without duplicates, e.g. for taking
+    /// locks or coordinating with other tools without executing
+    /// anything. Commands (see `push_command`) are not included.
+    pub fn affected_tablechains(&self) -> Vec<(Table, String)> {
+        let mut tablechains = Vec::new();
+        for (_, rule, _) in &self.actions {
+            if rule.is_command() {
+                continue;
+            }
+            let (table, chain) = rule.table_and_chain_names();
+            let table = table_from_name(&table).expect("names generated by def_chain");
+            let tablechain = (table, chain);
+            if !tablechains.contains(&tablechain) {
+                tablechains.push(tablechain);
+            }
+        }
+        tablechains
+    }
+
+    /// Statistics about the pushed rules (not counting chain
+    /// creations and commands), e.g. for noticing an unexpected
+    /// growth in the number of rules before applying them.
+    pub fn plan_summary(&self) -> PlanSummary {
+        let mut summary = PlanSummary::default();
+        let mut interfaces = Vec::new();
+        for (action, rule, _) in &self.actions {
+            if rule.is_command() || action.is_chain_creation() {
+                continue;
+            }
+            let (table, chain) = rule.table_and_chain_names();
+            let table = table_from_name(&table).expect("names generated by def_chain");
+            let tablechain = (table, chain);
+            match summary
+                .rules_per_chain
+                .iter_mut()
+                .find(|(tc, _)| *tc == tablechain)
+            {
+                Some((_, count)) => *count += 1,
+                None => summary.rules_per_chain.push((tablechain, 1)),
+            }
+            for r in rule.restrictions() {
+                if let Restriction::Interface(_, spec) = r {
+                    if !interfaces.contains(spec) {
+                        interfaces.push(spec.clone());
+                    }
+                }
+            }
+            if rule.rule_action_is_terminating() {
+                summary.terminating += 1;
+            } else {
+                summary.non_terminating += 1;
+            }
+        }
+        summary.interfaces = interfaces.len();
+        summary
+    }
+
+    /// Check that `rule` ends up at the 1-based `position` of its
+    /// chain when creating the rules (following `build_plan`, i.e.
+    /// taking `Insert` actions and priorities into account), e.g. that
+    /// a jump is the first rule of a built-in chain. Only the rules of
+    /// this writer are considered, not ones already present. `rule`
+    /// is compared as stored, i.e. after the transformations done by
+    /// `push` (like `with_chain_prefix`).
+    pub fn assert_position<T: TablechainTrait>(&self, rule: &Rule<T>, position: u32) -> Result<()> {
+        let wanted = rule.cmd_args(AnyAction::Check);
+        let mut chain = Vec::new();
+        for planned in self.build_plan(Effect::Creation) {
+            if planned.rule.is_command()
+                || planned.rule.table_and_chain_names() != rule.chain.table_and_chain_names()
+            {
+                continue;
+            }
+            let args = planned.rule.cmd_args(AnyAction::Check);
+            match planned.action {
+                AnyAction::Creation(Action::Append) => chain.push(args),
+                AnyAction::Creation(Action::Insert(n)) => {
+                    let i = (n as usize).saturating_sub(1).min(chain.len());
+                    chain.insert(i, args);
+                }
+                _ => (),
+            }
+        }
+        let (table, chain_name) = rule.chain.table_and_chain_names();
+        let cmd = shell_quote_many(&wanted);
+        match chain.iter().position(|args| *args == wanted) {
+            Some(i) if i + 1 == position as usize => Ok(()),
+            Some(i) => bail!(
+                "rule `{cmd}` in chain {chain_name:?} of table {table} is at position {}, \
+                 expected {position}",
+                i + 1
+            ),
+            None => bail!("rule `{cmd}` is not in chain {chain_name:?} of table {table}"),
+        }
+    }
+
+    /// A writer for resetting the tables used by the pushed rules
+    /// (see `affected_tablechains`) to their boot state, e.g. for
+    /// recovering from a broken ruleset: executing it (with
+    /// `Effect::Creation`) sets the policies of the built-in chains of
+    /// each table to `ACCEPT`, then flushes all chains (`-F`) and
+    /// deletes all custom chains (`-X`), in `TABLE_ORDER`. Opening
+    /// the policies first means that a `DROP` policy never applies to
+    /// emptied chains, e.g. cutting off the SSH session doing the
+    /// reset. This affects the rules of other tools in these tables,
+    /// too. Running it again is harmless.
+    pub fn flush_all(&self) -> IptablesWriter {
+        let tables: Vec<Table> = self
+            .affected_tablechains()
+            .into_iter()
+            .map(|(table, _)| table)
+            .collect();
+        let mut writer = IptablesWriter::new(self.iptables_cmd.clone());
+        for table in TABLE_ORDER.iter().filter(|t| tables.contains(t)) {
+            let command = |args: &[&str]| {
+                let mut cmd = self.iptables_cmd.clone();
+                cmd.push("-t".into());
+                cmd.push(table.into());
+                cmd.extend(args.iter().map(|s| s.to_string()));
+                cmd
+            };
+            for chain in table.builtin_chains() {
+                writer.push_command(command(&["-P", chain, "ACCEPT"]), None);
+            }
+            writer.push_command(command(&["-F"]), None);
+            writer.push_command(command(&["-X"]), None);
+        }
+        writer
+    }
+
+    /// The plan for the wanted Effect as a Bash script. Commands
+    /// whose failure `execute` would tolerate (deletions, and
+    /// creations in a `RecreatingMode` that allows errors) are
+    /// suffixed with `|| true`.
+    pub fn write_script<W: Write>(&self, w: &mut W, want: Effect) -> io::Result<()> {
+        writeln!(w, "#!/bin/bash")?;
+        writeln!(w, "set -euo pipefail")?;
+        for planned in self.build_plan(want) {
+            let cmd = planned.full_cmd(&self.iptables_cmd);
+            if planned.may_fail() {
+                writeln!(w, "{} || true", shell_quote_many(&cmd))?;
+            } else {
+                writeln!(w, "{}", shell_quote_many(&cmd))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_script(&self, want: Effect) -> String {
+        to_string_via(|out| self.write_script(out, want))
+    }
+
+    /// Just the "stop" commands as a script: `to_script` for
+    /// `Effect::Deletion`, i.e. the deletions in reverse order of
+    /// creation, all suffixed with `|| true`.
+    pub fn teardown_script(&self) -> String {
+        self.to_script(Effect::Deletion)
+    }
+
+    /// The plan for the wanted Effect as CSV, for reviewing in a
+    /// spreadsheet. Columns: order, table, chain, action, match
+    /// summary, target.
+    pub fn write_csv<W: Write>(&self, w: &mut W, want: Effect) -> io::Result<()> {
+        writeln!(w, "order,table,chain,action,match,target")?;
+        for (i, planned) in self.build_plan(want).iter().enumerate() {
+            let (table, chain) = planned.rule.table_and_chain_names();
+            let matches = planned.rule.restriction_strings();
+            let fields = [
+                (i + 1).to_string(),
+                table,
+                chain,
+                planned.action.to_string(),
+                matches.join(" "),
+                planned.rule.rule_action_string(),
+            ];
+            let fields: Vec<_> = fields.iter().map(|f| csv_escape(f)).collect();
+            writeln!(w, "{}", fields.join(","))?;
+        }
+        Ok(())
+    }
+
+    /// The plan for the wanted Effect as a JSON `Plan` object, e.g.
+    /// for assertions in CI or for running it elsewhere.
+    pub fn write_json<W: Write>(&self, w: &mut W, want: Effect) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *w, &Plan::new(self.plan_entries(want)))?;
+        writeln!(w)
+    }
+
+    /// The plan for the wanted Effect as `PlanEntry`s (as used by
+    /// `write_json`).
+    pub fn plan_entries(&self, want: Effect) -> Vec<PlanEntry> {
+        self.build_plan(want)
+            .iter()
+            .enumerate()
+            .map(|(i, planned)| {
+                let (table, chain) = planned.rule.table_and_chain_names();
+                let cmd = planned.full_cmd(&self.iptables_cmd);
+                PlanEntry {
+                    order: i + 1,
+                    table,
+                    chain,
+                    action: planned.action.to_string(),
+                    cmd,
+                    may_fail: planned.may_fail(),
+                }
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self, want: Effect) -> String {
+        to_string_via(|out| self.write_json(out, want))
+    }
+
+    pub fn to_csv(&self, want: Effect) -> String {
+        to_string_via(|out| self.write_csv(out, want))
+    }
+
+    /// The pushed rules in `iptables-save` format, e.g. for
+    /// `iptables-restore --noflush`. Tables appear in the order in
+    /// which netfilter processes them (see `TABLE_ORDER`), each closed
+    /// with `COMMIT` before the next one; chains created via
+    /// `Action::NewChain` are declared in the header of their table.
+    /// `CommandHook`s are left out. The rules of the chains given to
+    /// `with_sorted_save_chains` are sorted.
+    pub fn write_iptables_save<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.write_iptables_save_counted(w, |_| None)
+    }
+
+    /// `write_iptables_save`, prefixing the rule lines with the
+    /// `[packets:bytes]` counters returned by `counters`, if any.
+    pub(crate) fn write_iptables_save_counted<W: Write>(
+        &self,
+        w: &mut W,
+        mut counters: impl FnMut(&dyn RuleTrait) -> Option<(u64, u64)>,
+    ) -> io::Result<()> {
+        // (table name, chain declarations, (chain name, rule line))
+        type SaveTable = (String, Vec<String>, Vec<(String, String)>);
+        let mut tables: Vec<SaveTable> = Vec::new();
+        for planned in self.build_plan(Effect::Creation) {
+            if planned.rule.is_command() {
+                continue;
+            }
+            let (table_name, chain_name) = planned.rule.table_and_chain_names();
+            let i = match tables.iter().position(|(t, _, _)| *t == table_name) {
+                Some(i) => i,
+                None => {
+                    tables.push((table_name, Vec::new(), Vec::new()));
+                    tables.len() - 1
+                }
+            };
+            let (_, chains, rules) = &mut tables[i];
+            if planned.action.is_chain_creation() {
+                chains.push(format!(":{chain_name} - [0:0]"));
+            } else {
+                // Drop the "-t <table>" part, the table is given by
+                // the header.
+                let args = planned.cmd_args();
+                let line = save_quote_many(&args[2..]);
+                let line = match counters(planned.rule) {
+                    Some((packets, bytes)) => format!("[{packets}:{bytes}] {line}"),
+                    None => line,
+                };
+                rules.push((chain_name, line));
+            }
+        }
+
+        tables.sort_by_key(|(table_name, _, _)| {
+            TABLE_ORDER
+                .iter()
+                .position(|table| String::from(table) == *table_name)
+        });
+        for (table_name, chains, mut rules) in tables {
+            let table = table_from_name(&table_name).expect("names generated by def_chain");
+            for (sorted_table, sorted_chain) in &self.sorted_save_chains {
+                if *sorted_table != table {
+                    continue;
+                }
+                // Sort the lines of the chain within the positions
+                // they occupy; sort by the line without counters.
+                let positions: Vec<usize> = (0..rules.len())
+                    .filter(|i| rules[*i].0 == *sorted_chain)
+                    .collect();
+                let mut lines: Vec<(String, String)> =
+                    positions.iter().map(|i| rules[*i].clone()).collect();
+                lines.sort_by(|(_, a), (_, b)| strip_counters(a).cmp(strip_counters(b)));
+                for (i, line) in positions.into_iter().zip(lines) {
+                    rules[i] = line;
+                }
+            }
+            writeln!(w, "*{table_name}")?;
+            for line in chains.iter().chain(rules.iter().map(|(_, line)| line)) {
+                writeln!(w, "{line}")?;
+            }
+            writeln!(w, "COMMIT")?;
+        }
+        Ok(())
+    }
+
+    pub fn to_iptables_save(&self) -> String {
+        to_string_via(|out| self.write_iptables_save(out))
+    }
+
+    /// The existing interfaces if `with_skip_missing_interfaces` was
+    /// used.
+    fn existing_interfaces(&self) -> Result<Option<Vec<String>>, FirewallError> {
+        self.skip_missing_interfaces
+            .map(|lookup| lookup().map_err(|e| FirewallError::InterfaceLookup(format!("{e:#}"))))
+            .transpose()
+    }
+
+    fn prepare(&self, planned: &PlannedCommand, interfaces: Option<&[String]>) -> PreparedCommand {
+        let cmd = planned.full_cmd(&self.iptables_cmd);
+        let skip = interfaces.and_then(|existing| {
+            planned.rule.restrictions().iter().find_map(|r| match r {
+                Restriction::Interface(Negatable::Is, InterfaceSpec::Exact(name))
+                    if !existing.contains(name) =>
+                {
+                    Some(format!("interface {name:?} does not exist"))
+                }
+                _ => None,
+            })
+        });
+        PreparedCommand {
+            action: planned.action,
+            recreating_mode: planned.recreating_mode,
+            rule: planned.rule_id(),
+            cmd,
+            timing: self.timing,
+            skip,
+        }
+    }
+
+    /// Check for each pushed rule (except for chain creations, which
+    /// can't be checked with `-C`, and `CommandHook`s) whether it is
+    /// present, in the order of pushing (i.e. runs the plan for
+    /// `Effect::Check`).
+    pub fn status(&self, executor: &mut dyn Executor<AnyAction>) -> Result<Vec<RuleStatus>> {
+        let mut statuses = Vec::new();
+        for planned in self.build_plan(Effect::Check) {
+            let rule = planned.rule_id();
+            let cmd = planned.full_cmd(&self.iptables_cmd);
+            let present =
+                check_interpretation(&executor.execute_for_rule(AnyAction::Check, &rule, &cmd))?;
+            statuses.push(RuleStatus { rule, cmd, present });
+        }
+        Ok(statuses)
+    }
+
+    /// `status`, but if `require_all` is true, failing if any of the
+    /// rules is absent.
+    pub fn check(
+        &self,
+        executor: &mut dyn Executor<AnyAction>,
+        require_all: bool,
+    ) -> Result<Vec<RuleStatus>> {
+        let statuses = self.status(executor)?;
+        let absent: Vec<String> = statuses
+            .iter()
+            .filter(|s| !s.present)
+            .map(|s| shell_quote_many(&s.cmd))
+            .collect();
+        if require_all && !absent.is_empty() {
+            bail!(
+                "{} of {} rules are absent: {}",
+                absent.len(),
+                statuses.len(),
+                absent.join("; ")
+            );
+        }
+        Ok(statuses)
+    }
+
+    /// Turn the pushed rules into rules for actual execution
+    /// according to the wanted Effect. Execute for real if true is
+    /// given.
+    pub fn execute<O: std::io::Write>(
+        &self,
+        want: Effect,
+        verbose_output: Option<O>,
+        executor: &mut dyn Executor<AnyAction>,
+    ) -> Result<(), FirewallError> {
+        self.execute_with(want, verbose_output, executor, &ExecuteOptions::default())
+    }
+
+    /// `execute` with the given options.
+    pub fn execute_with<O: std::io::Write>(
+        &self,
+        want: Effect,
+        verbose_output: Option<O>,
+        executor: &mut dyn Executor<AnyAction>,
+        options: &ExecuteOptions,
+    ) -> Result<(), FirewallError> {
+        self.check_deletable(want)?;
+        self.run_plan(self.build_plan(want), verbose_output, executor, options)
+    }
+
+    /// Like `execute`, but only runs the commands for the rules in
+    /// the tables and chains for which `predicate` returns true, e.g.
+    /// for recreating a single chain while iterating on it; the
+    /// order of the commands is kept. Commands (see `push_command`)
+    /// are skipped. Rules jumping to chains that are not selected are
+    /// run as usual (note that deleting a chain still referenced by
+    /// an unselected rule fails).
+    pub fn execute_filtered<O: std::io::Write>(
+        &self,
+        want: Effect,
+        predicate: impl Fn(&(Table, String)) -> bool,
+        verbose_output: Option<O>,
+        executor: &mut dyn Executor<AnyAction>,
+    ) -> Result<(), FirewallError> {
+        self.check_deletable(want)?;
+        let plan = self
+            .build_plan(want)
+            .into_iter()
+            .filter(|planned| {
+                if planned.rule.is_command() {
+                    return false;
+                }
+                let (table, chain) = planned.rule.table_and_chain_names();
+                let table = table_from_name(&table).expect("names generated by def_chain");
+                predicate(&(table, chain))
+            })
+            .collect();
+        self.run_plan(plan, verbose_output, executor, &ExecuteOptions::default())
+    }
+
+    /// The commands of the plan for `want` that executing would skip
+    /// (see `with_skip_missing_interfaces`), shell-quoted, with the
+    /// reason. The verbose output lists them, too; this is for
+    /// reporting them when running without it.
+    pub fn skipped_commands(&self, want: Effect) -> Result<Vec<(String, String)>, FirewallError> {
+        let interfaces = self.existing_interfaces()?;
+        Ok(self
+            .build_plan(want)
+            .iter()
+            .filter_map(|planned| {
+                let prepared = self.prepare(planned, interfaces.as_deref());
+                let cmd = shell_quote_many(&prepared.cmd);
+                prepared.skip.map(|reason| (cmd, reason))
+            })
+            .collect())
+    }
+
+    /// Run the given plan (from `build_plan`), see `execute_with`.
+    fn run_plan<O: std::io::Write>(
+        &self,
+        plan: Vec<PlannedCommand<'_>>,
+        mut verbose_output: Option<O>,
+        executor: &mut dyn Executor<AnyAction>,
+        options: &ExecuteOptions,
+    ) -> Result<(), FirewallError> {
+        self.check_rule_count()?;
+        let interfaces = self.existing_interfaces()?;
+        let mut section = None;
+        for planned in plan {
+            if let (true, Some(out)) = (options.sections, verbose_output.as_mut()) {
+                write_section_heading(out, &mut section, planned.section())?;
+            }
+            run_command(
+                &self.prepare(&planned, interfaces.as_deref()),
+                verbose_output
+                    .as_mut()
+                    .map(|o| o as &mut dyn std::io::Write),
+                executor,
+                options,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Like `execute_with`, but runs the commands for each table on
+    /// a separate thread (with its own clone of `executor`). This is
+    /// safe since rules can only refer to chains in the same table;
+    /// the order of the commands within a table is preserved.
+    /// `CommandHook`s act as barriers: they run on their own, at
+    /// their position in the plan, after the rules before them and
+    /// before the ones after them. Verbose output is collected per
+    /// table and written in the order of first use of the tables
+    /// (between hooks). Errors from all tables are reported together
+    /// (as `FirewallError::Tables`); nothing after them is run.
+    pub fn execute_parallel<O: std::io::Write, E: Executor<AnyAction> + Clone + Send>(
+        &self,
+        want: Effect,
+        mut verbose_output: Option<O>,
+        executor: &E,
+        options: &ExecuteOptions,
+    ) -> Result<(), FirewallError> {
+        self.check_deletable(want)?;
+        self.check_rule_count()?;
+        let interfaces = self.existing_interfaces()?;
+        let verbose = verbose_output.is_some();
+        let mut section = None;
+        // The verbose output of each command, with its section heading
+        let mut write_outputs = |outputs: Vec<(String, Vec<u8>)>| -> io::Result<()> {
+            if let Some(out) = verbose_output.as_mut() {
+                for (heading, output) in outputs {
+                    if options.sections {
+                        write_section_heading(out, &mut section, heading)?;
+                    }
+                    out.write_all(&output)?;
+                }
+            }
+            Ok(())
+        };
+
+        let plan = self.build_plan(want);
+        let mut serial_executor = executor.clone();
+        for segment in plan.chunk_by(|a, b| a.rule.is_command() == b.rule.is_command()) {
+            if segment[0].rule.is_command() {
+                for planned in segment {
+                    let mut output = Vec::new();
+                    let result = run_command(
+                        &self.prepare(planned, interfaces.as_deref()),
+                        if verbose { Some(&mut output) } else { None },
+                        &mut serial_executor,
+                        options,
+                    );
+                    write_outputs(vec![(planned.section(), output)])?;
+                    result?;
+                }
+                continue;
+            }
+
+            let mut tables: Vec<(String, Vec<(String, PreparedCommand)>)> = Vec::new();
+            for planned in segment {
+                let prepared = self.prepare(planned, interfaces.as_deref());
+                let table = prepared.rule.table.clone();
+                let command = (planned.section(), prepared);
+                match tables.iter_mut().find(|(t, _)| *t == table) {
+                    Some((_, cmds)) => cmds.push(command),
+                    None => tables.push((table, vec![command])),
+                }
+            }
+            type TableResult = (Vec<(String, Vec<u8>)>, Result<(), FirewallError>);
+            let results: Vec<TableResult> = std::thread::scope(|scope| {
+                let handles: Vec<_> = tables
+                    .iter()
+                    .map(|(_, cmds)| {
+                        let mut executor = executor.clone();
+                        scope.spawn(move || {
+                            let mut outputs = Vec::new();
+                            let result = cmds.iter().try_for_each(|(heading, prepared)| {
+                                let mut output = Vec::new();
+                                let result = run_command(
+                                    prepared,
+                                    if verbose { Some(&mut output) } else { None },
+                                    &mut executor,
+                                    options,
+                                );
+                                outputs.push((heading.clone(), output));
+                                result
+                            });
+                            (outputs, result)
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().expect("executor thread panicked"))
+                    .collect()
+            });
+
+            let mut errors = Vec::new();
+            for ((table_name, _), (outputs, result)) in tables.iter().zip(results) {
+                write_outputs(outputs)?;
+                if let Err(e) = result {
+                    errors.push((table_name.clone(), e));
+                }
+            }
+            if !errors.is_empty() {
+                return Err(FirewallError::Tables(errors));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Start a new section of the verbose output (see
+/// `ExecuteOptions::sections`) with a "# <heading>" line, unless
+/// `heading` is the one of the current `section`.
+fn write_section_heading(
+    out: &mut dyn std::io::Write,
+    section: &mut Option<String>,
+    heading: String,
+) -> io::Result<()> {
+    if section.as_ref() != Some(&heading) {
+        if section.is_some() {
+            writeln!(out)?;
+        }
+        writeln!(out, "# {heading}")?;
+        *section = Some(heading);
+    }
+    Ok(())
+}
+
+/// An `iptables-save` rule line without its `[packets:bytes]` prefix.
+fn strip_counters(line: &str) -> &str {
+    match line.strip_prefix('[') {
+        Some(rest) => rest.split_once("] ").map_or(line, |(_, rest)| rest),
+        None => line,
+    }
+}
+
+/// Collect the output of one of the `write_*` methods.
+fn to_string_via(write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> String {
+    let mut out = Vec::new();
+    write(&mut out).expect("writing to a Vec can't fail");
+    String::from_utf8(out).expect("the writers only write strings")
+}
+
+/// The markers at the start of the lines of the verbose output,
+/// showing the outcome of each command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerboseMarkers {
+    pub success: String,
+    /// Exit with an error code.
+    pub exit_code: String,
+    /// Killed by a signal.
+    pub signal: String,
+    /// Could not be started.
+    pub exec_failure: String,
+    /// Not run (see `IptablesWriter::with_skip_missing_interfaces`).
+    pub skipped: String,
+}
+
+impl Default for VerboseMarkers {
+    /// The same as `ExecutorStatus::to_str`, and "W" for skipped
+    /// commands.
+    fn default() -> Self {
+        Self {
+            success: ExecutorStatus::Success.to_str().into(),
+            exit_code: ExecutorStatus::ExitCode(1).to_str().into(),
+            signal: ExecutorStatus::Signal(9).to_str().into(),
+            exec_failure: ExecutorStatus::ExecFailure(String::new()).to_str().into(),
+            skipped: "W".into(),
+        }
+    }
+}
+
+impl VerboseMarkers {
+    pub fn for_status(&self, status: &ExecutorStatus) -> &str {
+        match status {
+            ExecutorStatus::Success => &self.success,
+            ExecutorStatus::ExitCode(_) => &self.exit_code,
+            ExecutorStatus::Signal(_) => &self.signal,
+            ExecutorStatus::ExecFailure(_) => &self.exec_failure,
+        }
+    }
+}
+
+/// Options for `IptablesWriter::execute_with`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecuteOptions {
+    pub markers: VerboseMarkers,
+    /// Group the verbose output into blank-line-separated sections,
+    /// each starting with a "# <heading>" line (see
+    /// `PlannedCommand::section`).
+    pub sections: bool,
+    /// Show the marker and command of the lines of the verbose output
+    /// in green for successful commands and in red for failed ones,
+    /// via ANSI escape codes (e.g. if writing to a terminal).
+    pub color: bool,
+    /// Append the `ResultInterpretation` of the result to the marker
+    /// in the verbose output (e.g. `E[OkForDeletions]`), showing why
+    /// a failed command was or wasn't accepted.
+    pub interpretations: bool,
+}
+
+const ANSI_GREEN: &str = "\x1b[32m";
+const ANSI_RED: &str = "\x1b[31m";
+const ANSI_RESET: &str = "\x1b[0m";
+
+/// A `PlannedCommand` with the full command line, independent of the
+/// writer.
+struct PreparedCommand {
+    action: AnyAction,
+    recreating_mode: RecreatingMode,
+    rule: RuleId,
+    cmd: Vec<String>,
+    /// Whether to report the duration in the verbose output.
+    timing: bool,
+    /// Why the command is not to be run, if so.
+    skip: Option<String>,
+}
+
+/// Run a single command of a plan, interpreting the result according
+/// to the action and recreating mode.
+fn run_command(
+    prepared: &PreparedCommand,
+    verbose_output: Option<&mut dyn std::io::Write>,
+    executor: &mut dyn Executor<AnyAction>,
+    options: &ExecuteOptions,
+) -> Result<(), FirewallError> {
+    let markers = &options.markers;
+    let PreparedCommand {
+        action,
+        recreating_mode,
+        rule,
+        cmd,
+        timing,
+        skip,
+    } = prepared;
+    if let Some(reason) = skip {
+        // (Without verbose output, the caller can report these via
+        // `IptablesWriter::skipped_commands`.)
+        if let Some(out) = verbose_output {
+            writeln!(
+                out,
+                "{} {} ({reason}, skipped)",
+                markers.skipped,
+                shell_quote_many(cmd)
+            )?;
+        }
+        return Ok(());
+    }
+    let (action, recreating_mode) = (*action, *recreating_mode);
+    let start = Instant::now();
+    let result = executor.execute_for_rule(action, rule, cmd);
+    let elapsed = start.elapsed();
+    let interpretation = ResultInterpretation::from(&result);
+    if let Some(out) = verbose_output {
+        let (color, reset) = match (options.color, result.is_success()) {
+            (false, _) => ("", ""),
+            (true, true) => (ANSI_GREEN, ANSI_RESET),
+            (true, false) => (ANSI_RED, ANSI_RESET),
+        };
+        let marker = markers.for_status(&result.status);
+        let marker = if options.interpretations {
+            format!("{marker}[{interpretation:?}]")
+        } else {
+            marker.to_string()
+        };
+        write!(out, "{color}{marker} {}{reset}", shell_quote_many(cmd))?;
+        if *timing {
+            write!(out, " ({}ms)", elapsed.as_millis())?;
+        }
+        writeln!(out)?;
+    }
+    let strict = action.is_creation() && !recreating_mode.allows_errors_on_creation();
+    let (cmd, code, output) = (
+        shell_quote_many(cmd),
+        result.code().unwrap_or_default(),
+        result.combined_output.clone(),
+    );
+    match interpretation {
+        ResultInterpretation::Ok => (),
+        ResultInterpretation::OkForDeletions => {
+            if strict {
+                return Err(FirewallError::NotFound {
+                    cmd,
+                    code,
+                    action,
+                    output,
+                });
+            }
+        }
+        ResultInterpretation::ChainInUse => {
+            if strict {
+                return Err(FirewallError::ChainInUse {
+                    cmd,
+                    code,
+                    action,
+                    output,
+                });
+            } else {
+                // Mark so that error in creation part
+                // below can be more strictly checked?
+            }
+        }
+        ResultInterpretation::ChainAlreadyExists => {
+            if action.is_chain_creation() {
+                // Only ignore this error if
+                // previously there was the ChainInUse
+                // error above on the same rule?
+            } else if strict {
+                return Err(FirewallError::ChainAlreadyExists {
+                    cmd,
+                    code,
+                    action,
+                    output,
+                });
+            }
+        }
+        ResultInterpretation::Err => {
+            if let Some(e) = FirewallError::from_status(&result) {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::DryExecutor;
+    use std::sync::{Arc, Mutex};
+
+    /// Records all commands, failing those containing `fail_on`.
+    #[derive(Clone)]
+    struct RecordingExecutor {
+        log: Arc<Mutex<Vec<String>>>,
+        fail_on: Option<&'static str>,
+    }
+
+    impl Executor<AnyAction> for RecordingExecutor {
+        fn execute<'t>(&mut self, _action: AnyAction, cmd: &'t [String]) -> ExecutorResult<'t> {
+            let line = cmd.join(" ");
+            let fail = self.fail_on.is_some_and(|s| line.contains(s));
+            self.log.lock().unwrap().push(line);
+            ExecutorResult {
+                cmd,
+                status: if fail {
+                    ExecutorStatus::ExitCode(3)
+                } else {
+                    ExecutorStatus::Success
+                },
+                combined_output: "".into(),
+            }
+        }
+    }
+
+    /// Returns the given status and output for every command.
+    struct MockStatus(ExecutorStatus, &'static str);
+
+    impl Executor<AnyAction> for MockStatus {
+        fn execute<'t>(&mut self, _action: AnyAction, cmd: &'t [String]) -> ExecutorResult<'t> {
+            ExecutorResult {
+                cmd,
+                status: self.0.clone(),
+                combined_output: self.1.into(),
+            }
+        }
+    }
+
+    fn multi_table_writer() -> IptablesWriter {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push(
+            Action::NewChain,
+            Rule {
+                chain: Filter::Custom("f-chain".into()),
+                restrictions: vec![],
+                rule_action: RuleAction::None,
+            },
+            RecreatingMode::Owned,
+        );
+        iptables.push(
+            Action::NewChain,
+            Rule {
+                chain: Nat::Custom("n-chain".into()),
+                restrictions: vec![],
+                rule_action: RuleAction::None,
+            },
+            RecreatingMode::Owned,
+        );
+        for port in [22, 80] {
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Filter::Custom("f-chain".into()),
+                    restrictions: vec![
+                        Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                        Restriction::DestinationPort(Negatable::Is, port),
+                    ],
+                    rule_action: RuleAction::Drop,
+                },
+                RecreatingMode::Owned,
+            );
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Nat::Custom("n-chain".into()),
+                    restrictions: vec![
+                        Restriction::Protocol(Negatable::Is, Protocol::Udp),
+                        Restriction::DestinationPort(Negatable::Is, port),
+                    ],
+                    rule_action: RuleAction::Return,
+                },
+                RecreatingMode::Owned,
+            );
+        }
+        iptables
+    }
+
+    #[test]
+    fn t_protocol_number() {
+        use Protocol::*;
+        let numbers: Vec<_> = [
+            All, Tcp, Udp, Udplite, Icmp, Icmpv6, Esp, Ah, Sctp, Dccp, Mh,
+        ]
+        .iter()
+        .map(|p| p.number())
+        .collect();
+        assert_eq!(
+            numbers,
+            [
+                None,
+                Some(6),
+                Some(17),
+                Some(136),
+                Some(1),
+                Some(58),
+                Some(50),
+                Some(51),
+                Some(132),
+                Some(33),
+                Some(135)
+            ]
+        );
+    }
+
+    #[test]
+    fn t_validate() {
+        let rule = |restrictions| Rule {
+            chain: Filter::INPUT,
+            restrictions,
+            rule_action: RuleAction::Accept,
+        };
+        let valid = rule(vec![
+            Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+            Restriction::DestinationPort(Negatable::Is, 22),
+        ]);
+        assert!(valid.validate().is_ok());
+        let missing = rule(vec![Restriction::DestinationPort(Negatable::Is, 22)]);
+        assert_eq!(
+            missing.validate().unwrap_err().to_string(),
+            "rule in chain \"INPUT\": --dport requires a tcp, udp, udplite, sctp or \
+             dccp protocol restriction"
+        );
+        let negated = rule(vec![
+            Restriction::Protocol(Negatable::IsNot, Protocol::Udp),
+            Restriction::SourcePort(Negatable::Is, 53),
+        ]);
+        assert!(negated.validate().is_err());
+
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push(Action::Append, valid, RecreatingMode::Owned);
+        assert!(iptables.validate().is_ok());
+        iptables.push(Action::Append, missing, RecreatingMode::Owned);
+        assert!(iptables.validate().is_err());
+    }
+
+    #[test]
+    fn t_streaming_writers() {
+        let iptables = multi_table_writer();
+        let mut out = Vec::new();
+        iptables.write_script(&mut out, Effect::Recreation).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            iptables.to_script(Effect::Recreation)
+        );
+        let mut out = Vec::new();
+        iptables.write_csv(&mut out, Effect::Deletion).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            iptables.to_csv(Effect::Deletion)
+        );
+        let mut out = Vec::new();
+        iptables.write_iptables_save(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), iptables.to_iptables_save());
+    }
+
+    #[test]
+    fn t_to_script() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: Filter::Custom("someone's chain".into()),
+                restrictions: vec![],
+                rule_action: RuleAction::Drop,
+            },
+            RecreatingMode::TryCreation,
+        );
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::Owned,
+        );
+        assert_eq!(
+            iptables.to_script(Effect::Recreation),
+            "#!/bin/bash\n\
+             set -euo pipefail\n\
+             iptables -t filter -D INPUT -j ACCEPT || true\n\
+             iptables -t filter -D someone\\''s chain' -j DROP || true\n\
+             iptables -t filter -A someone\\''s chain' -j DROP || true\n\
+             iptables -t filter -A INPUT -j ACCEPT\n"
+        );
+    }
+
+    #[test]
+    fn t_push_default() {
+        let rule = || Rule {
+            chain: Filter::Custom("kept".into()),
+            restrictions: vec![],
+            rule_action: RuleAction::None,
+        };
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        assert_eq!(iptables.default_mode(), RecreatingMode::Owned);
+        iptables.push_default(Action::NewChain, rule());
+
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()])
+            .with_default_mode(RecreatingMode::TryCreationNoDeletion);
+        iptables.push_default(Action::NewChain, rule());
+        iptables.push(Action::Append, rule(), RecreatingMode::Owned);
+        assert_eq!(
+            iptables.recreating_mode(0),
+            Some(RecreatingMode::TryCreationNoDeletion)
+        );
+        assert_eq!(iptables.recreating_mode(1), Some(RecreatingMode::Owned));
+        assert_eq!(iptables.recreating_mode(2), None);
+    }
+
+    #[test]
+    fn t_allow_icmpv6_essentials() {
+        let mut iptables = IptablesWriter::new(vec!["ip6tables".into()]);
+        iptables.allow_icmpv6_essentials(Filter::INPUT);
+        let types: Vec<String> = iptables
+            .build_plan(Effect::Creation)
+            .iter()
+            .map(|p| {
+                let args = p.cmd_args();
+                assert_eq!(
+                    args[..7],
+                    [
+                        "-t",
+                        "filter",
+                        "-A",
+                        "INPUT",
+                        "-p",
+                        "icmpv6",
+                        "--icmpv6-type"
+                    ]
+                );
+                assert_eq!(args[8..], ["-j", "ACCEPT"]);
+                args[7].clone()
+            })
+            .collect();
+        assert_eq!(
+            types,
+            [
+                "destination-unreachable",
+                "packet-too-big",
+                "time-exceeded",
+                "parameter-problem",
+                "router-solicitation",
+                "router-advertisement",
+                "neighbour-solicitation",
+                "neighbour-advertisement",
+                "130",
+                "131",
+                "132",
+                "143"
+            ]
+        );
+    }
+
+    #[test]
+    fn t_on_too_long() {
+        let long = "x".repeat(300);
+        assert!(Restriction::comment(&long, OnTooLong::Error).is_err());
+        assert_eq!(
+            Restriction::comment(&long, OnTooLong::Truncate).unwrap(),
+            Restriction::Comment("x".repeat(255))
+        );
+        assert_eq!(
+            Restriction::comment("short", OnTooLong::Error).unwrap(),
+            Restriction::Comment("short".into())
+        );
+
+        assert_eq!(
+            RuleAction::<Filter>::log("dropped by the firewall (ssh)", OnTooLong::Error).unwrap(),
+            RuleAction::Log(Some("dropped by the firewall (ssh)".into()))
+        );
+        assert_eq!(
+            RuleAction::<Filter>::log("dropped by the firewall (ssh): ", OnTooLong::Error)
+                .unwrap_err()
+                .to_string(),
+            "log prefix is 31 bytes long, the maximum is 29: \"dropped by the firewall (ssh): \""
+        );
+        // 'ä' is 2 bytes, the limit of 29 would cut the 15th in half
+        assert_eq!(
+            RuleAction::<Filter>::log(&"ä".repeat(20), OnTooLong::Truncate).unwrap(),
+            RuleAction::Log(Some("ä".repeat(14)))
+        );
+    }
+
+    #[test]
+    fn t_execute_for_rule() {
+        struct RuleLogger(Vec<String>);
+        impl Executor<AnyAction> for RuleLogger {
+            fn execute<'t>(&mut self, _action: AnyAction, cmd: &'t [String]) -> ExecutorResult<'t> {
+                DryExecutor.execute((), cmd)
+            }
+            fn execute_for_rule<'t>(
+                &mut self,
+                action: AnyAction,
+                rule: &RuleId,
+                cmd: &'t [String],
+            ) -> ExecutorResult<'t> {
+                self.0.push(format!(
+                    "{action} rule #{} for chain {} in table {}",
+                    rule.index, rule.chain, rule.table
+                ));
+                self.execute(action, cmd)
+            }
+        }
+
+        let iptables = multi_table_writer();
+        let mut executor = RuleLogger(Vec::new());
+        iptables
+            .execute(Effect::Recreation, None::<Vec<u8>>, &mut executor)
+            .unwrap();
+        assert_eq!(
+            executor.0[..5],
+            [
+                "delete rule #5 for chain n-chain in table nat",
+                "delete rule #4 for chain f-chain in table filter",
+                "delete rule #3 for chain n-chain in table nat",
+                "delete rule #2 for chain f-chain in table filter",
+                "flush rule #1 for chain n-chain in table nat",
+            ]
+        );
+        assert_eq!(
+            executor.0.last().unwrap(),
+            "append rule #5 for chain n-chain in table nat"
+        );
+    }
+
+    #[test]
+    fn t_secmark() {
+        let rule = |chain, rule_action| Rule {
+            chain,
+            restrictions: vec![
+                Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                Restriction::DestinationPort(Negatable::Is, 80),
+            ],
+            rule_action,
+        };
+        assert_eq!(
+            rule(
+                Security::INPUT,
+                RuleAction::SecMark("system_u:object_r:http_packet_t:s0".into())
+            )
+            .cmd_args(Action::Append.into()),
+            [
+                "-t",
+                "security",
+                "-A",
+                "INPUT",
+                "-p",
+                "tcp",
+                "--dport",
+                "80",
+                "-j",
+                "SECMARK",
+                "--selctx",
+                "system_u:object_r:http_packet_t:s0"
+            ]
+        );
+        assert_eq!(
+            rule(Security::INPUT, RuleAction::ConnSecMark(ConnSecOp::Save))
+                .cmd_args(Action::Append.into())[8..],
+            ["-j", "CONNSECMARK", "--save"]
+        );
+        assert_eq!(
+            rule(
+                Security::OUTPUT,
+                RuleAction::ConnSecMark(ConnSecOp::Restore)
+            )
+            .cmd_args(Action::Append.into())[..4],
+            ["-t", "security", "-A", "OUTPUT"]
+        );
+        assert_eq!(
+            rule(
+                Security::OUTPUT,
+                RuleAction::ConnSecMark(ConnSecOp::Restore)
+            )
+            .cmd_args(Action::Append.into())[8..],
+            ["-j", "CONNSECMARK", "--restore"]
+        );
+    }
+
+    #[test]
+    fn t_canonicalize() {
+        let custom =
+            |args: &[&str]| Restriction::Custom(args.iter().map(|s| s.to_string()).collect());
+        let mut a = Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![
+                Restriction::ConnState(Negatable::Is, vec![ConnState::New]),
+                Restriction::DestinationPort(Negatable::Is, 22),
+                Restriction::Comment("ssh".into()),
+                Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                Restriction::Interface(Negatable::Is, InterfaceSpec::Exact("eth0".into())),
+            ],
+            rule_action: RuleAction::Accept,
+        };
+        let mut b = Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![
+                Restriction::Interface(Negatable::Is, InterfaceSpec::Exact("eth0".into())),
+                Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                Restriction::ConnState(Negatable::Is, vec![ConnState::New]),
+                Restriction::DestinationPort(Negatable::Is, 22),
+                Restriction::Comment("ssh".into()),
+            ],
+            rule_action: RuleAction::Accept,
+        };
+        assert_ne!(a, b);
+        a.canonicalize();
+        b.canonicalize();
+        assert_eq!(a, b);
+        assert_eq!(
+            a.cmd_args(AnyAction::Check).join(" "),
+            "-t filter -C INPUT -i eth0 -p tcp --dport 22 -m conntrack --ctstate NEW \
+             -m comment --comment ssh -j ACCEPT"
+        );
+
+        // Match modules keep their relative order
+        let mut c = b.clone();
+        c.restrictions.swap(3, 4);
+        c.canonicalize();
+        assert_ne!(b, c);
+
+        // Custom restrictions are barriers
+        let mut d = Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![
+                custom(&["-p", "tcp"]),
+                Restriction::DestinationPort(Negatable::Is, 22),
+            ],
+            rule_action: RuleAction::Accept,
+        };
+        d.canonicalize();
+        assert_eq!(
+            d.cmd_args(AnyAction::Check).join(" "),
+            "-t filter -C INPUT -p tcp --dport 22 -j ACCEPT"
+        );
+        let mut e = Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![
+                Restriction::DestinationPort(Negatable::Is, 22),
+                Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                custom(&["-m", "foo"]),
+                Restriction::Comment("x".into()),
+                Restriction::Interface(Negatable::Is, InterfaceSpec::Exact("eth0".into())),
+            ],
+            rule_action: RuleAction::Accept,
+        };
+        e.canonicalize();
+        assert_eq!(
+            e.cmd_args(AnyAction::Check).join(" "),
+            "-t filter -C INPUT -p tcp --dport 22 -m foo -i eth0 -m comment --comment x \
+             -j ACCEPT"
+        );
+
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]).with_canonicalization(true);
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![
+                    Restriction::DestinationPort(Negatable::Is, 22),
+                    Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                ],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::Owned,
+        );
+        assert_eq!(
+            iptables.build_plan(Effect::Creation)[0]
+                .cmd_args()
+                .join(" "),
+            "-t filter -A INPUT -p tcp --dport 22 -j ACCEPT"
+        );
+    }
+
+    #[test]
+    fn t_rule_exists() {
+        let rule = Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![Restriction::Protocol(Negatable::Is, Protocol::Icmp)],
+            rule_action: RuleAction::Accept,
+        };
+        let iptables_cmd = ["iptables".to_string()];
+        let run = |fail_on| {
+            let mut executor = RecordingExecutor {
+                log: Default::default(),
+                fail_on,
+            };
+            let result = rule_exists(&mut executor, &iptables_cmd, &rule);
+            assert_eq!(
+                *executor.log.lock().unwrap(),
+                ["iptables -t filter -C INPUT -p icmp -j ACCEPT"]
+            );
+            result
+        };
+        assert!(run(None).unwrap());
+
+        let mut executor = MockStatus(ExecutorStatus::ExitCode(1), "");
+        assert!(!rule_exists(&mut executor, &iptables_cmd, &rule).unwrap());
+        let mut executor = MockStatus(ExecutorStatus::ExitCode(4), "");
+        assert_eq!(
+            rule_exists(&mut executor, &iptables_cmd, &rule)
+                .unwrap_err()
+                .to_string(),
+            "command `iptables -t filter -C INPUT -p icmp -j ACCEPT` exited with code 4 \
+             for check action: "
+        );
+        // RecordingExecutor fails with code 3
+        assert!(run(Some("-C")).is_err());
+    }
+
+    #[test]
+    fn t_ct_helper() {
+        // Only has an effect with the nf_conntrack_ftp module loaded
+        let rule = |helper: &str| Rule {
+            chain: Raw::PREROUTING,
+            restrictions: vec![
+                Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                Restriction::DestinationPort(Negatable::Is, 21),
+            ],
+            rule_action: RuleAction::CtHelper(helper.into()),
+        };
+        let ftp = rule("ftp");
+        ftp.validate().unwrap();
+        assert_eq!(
+            ftp.cmd_args(Action::Append.into()).join(" "),
+            "-t raw -A PREROUTING -p tcp --dport 21 -j CT --helper ftp"
+        );
+        assert!(rule("")
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .starts_with("rule in chain \"PREROUTING\": unknown conntrack helper \"\", expecting one of amanda, ftp,"));
+    }
+
+    #[test]
+    fn t_ct_zone() {
+        let rule = Rule {
+            chain: Raw::PREROUTING,
+            restrictions: vec![Restriction::Interface(
+                Negatable::Is,
+                InterfaceSpec::Exact("tenant5".into()),
+            )],
+            rule_action: RuleAction::CtZone(5),
+        };
+        assert_eq!(
+            rule.cmd_args(Action::Append.into()),
+            [
+                "-t",
+                "raw",
+                "-A",
+                "PREROUTING",
+                "-i",
+                "tenant5",
+                "-j",
+                "CT",
+                "--zone",
+                "5"
+            ]
+        );
+    }
+
+    #[test]
+    fn t_auto_limit_logs() {
+        let mut iptables =
+            IptablesWriter::new(vec!["iptables".into()]).with_auto_limit_logs("5/minute");
+        for restrictions in [
+            vec![Restriction::Protocol(Negatable::Is, Protocol::Tcp)],
+            vec![Restriction::Limit("1/second".into())],
+        ] {
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Filter::INPUT,
+                    restrictions,
+                    rule_action: RuleAction::Log(None),
+                },
+                RecreatingMode::Owned,
+            );
+        }
+        iptables.push_logged_drop(Filter::INPUT, vec![], "dropped");
+        assert_eq!(
+            iptables.to_string(),
+            " -t filter -A INPUT -p tcp -m limit --limit 5/minute -j LOG\n\
+             \x20-t filter -A INPUT -m limit --limit 1/second -j LOG\n\
+             \x20-t filter -A INPUT -m limit --limit 5/minute -j LOG --log-prefix dropped\n\
+             \x20-t filter -A INPUT -j DROP\n"
+        );
+    }
+
+    #[test]
+    fn t_clone_writer() {
+        let base = multi_table_writer();
+        let mut host = base.clone();
+        let plan = |iptables: &IptablesWriter| {
+            let executor = RecordingExecutor {
+                log: Default::default(),
+                fail_on: None,
+            };
+            iptables
+                .execute(Effect::Recreation, None::<Vec<u8>>, &mut executor.clone())
+                .unwrap();
+            let log = executor.log.lock().unwrap().clone();
+            log
+        };
+        assert_eq!(plan(&base), plan(&host));
+
+        host.push_delete_by_num(Filter::INPUT, 1);
+        host.drop_invalid_first(Filter::INPUT);
+        assert_eq!(plan(&base), plan(&multi_table_writer()));
+        assert_eq!(host.to_string().lines().count(), 8);
+        assert!(format!("{base:?}").contains("f-chain"));
+    }
+
+    #[test]
+    fn t_status() {
+        let iptables = multi_table_writer();
+        let mut executor = RecordingExecutor {
+            log: Default::default(),
+            fail_on: None,
+        };
+        let statuses = iptables.status(&mut executor).unwrap();
+        assert_eq!(statuses.len(), 4);
+        assert!(statuses.iter().all(|s| s.present));
+        assert_eq!(
+            statuses[0].to_string(),
+            "present: iptables -t filter -C f-chain -p tcp --dport 22 -j DROP"
+        );
+        assert_eq!(statuses[1].rule.index, 3);
+
+        let mut executor = MockStatus(ExecutorStatus::ExitCode(1), "");
+        let statuses = iptables.status(&mut executor).unwrap();
+        assert!(statuses.iter().all(|s| !s.present));
+        assert!(statuses[1]
+            .to_string()
+            .starts_with("absent: iptables -t nat -C n-chain"));
+
+        let mut executor = MockStatus(ExecutorStatus::ExitCode(4), "");
+        assert!(iptables.status(&mut executor).is_err());
+    }
+
+    #[test]
+    fn t_duplicate_inserts() {
+        let build = |iptables: IptablesWriter| {
+            let mut iptables = iptables;
+            for (chain, action) in [
+                (Filter::INPUT, RuleAction::Accept),
+                (Filter::FORWARD, RuleAction::Accept),
+                (Filter::INPUT, RuleAction::Drop),
+            ] {
+                iptables.push(
+                    Action::Insert(1),
+                    Rule {
+                        chain,
+                        restrictions: vec![],
+                        rule_action: action,
+                    },
+                    RecreatingMode::Owned,
+                );
+            }
+            iptables
+        };
+        let iptables = build(IptablesWriter::new(vec!["iptables".into()]));
+        assert_eq!(
+            iptables.validate().unwrap_err().to_string(),
+            "multiple rules inserted at index 1 into chain \"INPUT\" of table filter \
+             (the later ones end up before the earlier ones); use \
+             `with_duplicate_inserts_allowed` if intended"
+        );
+        let iptables = build(
+            IptablesWriter::new(vec!["iptables".into()]).with_duplicate_inserts_allowed(true),
+        );
+        assert!(iptables.validate().is_ok());
+    }
+
+    #[test]
+    fn t_timing() {
+        struct SlowExecutor;
+        impl Executor<AnyAction> for SlowExecutor {
+            fn execute<'t>(&mut self, action: AnyAction, cmd: &'t [String]) -> ExecutorResult<'t> {
+                std::thread::sleep(std::time::Duration::from_millis(15));
+                DryExecutor.execute(action, cmd)
+            }
+        }
+        let run = |timing| {
+            let mut output = Vec::new();
+            multi_table_writer()
+                .with_timing(timing)
+                .execute(Effect::Creation, Some(&mut output), &mut SlowExecutor)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let output = run(true);
+        for line in output.lines() {
+            let (_, ms) = line.rsplit_once(" (").unwrap();
+            let ms: u128 = ms.strip_suffix("ms)").unwrap().parse().unwrap();
+            assert!(ms >= 15, "{line}");
+        }
+        assert!(!run(false).contains("ms)"));
+    }
+
+    #[test]
+    fn t_delete_by_num() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push_delete_by_num(Filter::INPUT, 3);
+        let plan = iptables.build_plan(Effect::Creation);
+        assert_eq!(plan[0].cmd_args(), ["-t", "filter", "-D", "INPUT", "3"]);
+        assert_eq!(plan[0].action.to_string(), "deletebynum 3");
+        assert!(plan[0].may_fail());
+    }
+
+    #[test]
+    fn t_drop_invalid_first() {
+        let mut iptables = multi_table_writer();
+        iptables.drop_invalid_first(Filter::INPUT);
+        let plan = |want| -> Vec<String> {
+            iptables
+                .build_plan(want)
+                .iter()
+                .map(|p| p.cmd_args().join(" "))
+                .collect()
+        };
+        assert_eq!(
+            plan(Effect::Creation).last().unwrap(),
+            "-t filter -I INPUT 1 -m conntrack --ctstate INVALID -j DROP"
+        );
+        assert_eq!(
+            plan(Effect::Deletion)[0],
+            "-t filter -D INPUT -m conntrack --ctstate INVALID -j DROP"
+        );
+
+        let mut args = Vec::new();
+        Restriction::ConnState(
+            Negatable::IsNot,
+            vec![ConnState::Established, ConnState::Related],
+        )
+        .push_args(&mut args);
+        assert_eq!(
+            args,
+            ["-m", "conntrack", "!", "--ctstate", "ESTABLISHED,RELATED"]
+        );
+    }
+
+    #[test]
+    fn t_firewall_error_variants() {
+        let run = |action: Action, status: ExecutorStatus, output: &'static str| {
+            let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+            iptables.push(
+                action,
+                Rule {
+                    chain: Filter::Custom("c".into()),
+                    restrictions: vec![],
+                    rule_action: RuleAction::None,
+                },
+                RecreatingMode::Owned,
+            );
+            iptables
+                .execute(
+                    Effect::Creation,
+                    None::<Vec<u8>>,
+                    &mut MockStatus(status, output),
+                )
+                .unwrap_err()
+        };
+        assert!(matches!(
+            run(Action::Append, ExecutorStatus::ExitCode(3), ""),
+            FirewallError::ExitCode { code: 3, .. }
+        ));
+        assert!(matches!(
+            run(Action::Append, ExecutorStatus::Signal(9), ""),
+            FirewallError::Signal { signal: 9, .. }
+        ));
+        assert!(matches!(
+            run(
+                Action::Append,
+                ExecutorStatus::ExecFailure("ENOENT".into()),
+                ""
+            ),
+            FirewallError::ExecFailure { .. }
+        ));
+        assert!(matches!(
+            run(Action::Append, ExecutorStatus::ExitCode(1), ""),
+            FirewallError::NotFound {
+                code: 1,
+                action: AnyAction::Creation(Action::Append),
+                ..
+            }
+        ));
+        assert!(matches!(
+            run(
+                Action::NewChain,
+                ExecutorStatus::ExitCode(4),
+                "CHAIN_DEL failed (Device or resource busy)"
+            ),
+            FirewallError::ChainInUse { .. }
+        ));
+        let e = run(
+            Action::Append,
+            ExecutorStatus::ExitCode(1),
+            "iptables: Chain already exists.",
+        );
+        assert!(matches!(e, FirewallError::ChainAlreadyExists { .. }));
+        // Still usable as an anyhow error
+        let e: anyhow::Error = e.into();
+        assert!(e
+            .to_string()
+            .starts_with("command `iptables -t filter -A c` exited with code 1"));
+    }
+
+    #[test]
+    fn t_allow_limited_ping() {
+        let args = |cmd: &str| {
+            let mut iptables = IptablesWriter::new(vec!["sudo".into(), cmd.into()]);
+            iptables.allow_limited_ping(Filter::INPUT, "1/second");
+            iptables.build_plan(Effect::Creation)[0]
+                .cmd_args()
+                .join(" ")
+        };
+        assert_eq!(
+            args("iptables"),
+            "-t filter -A INPUT -p icmp --icmp-type echo-request \
+             -m limit --limit 1/second -j ACCEPT"
+        );
+        assert_eq!(
+            args("/usr/sbin/ip6tables"),
+            "-t filter -A INPUT -p icmpv6 --icmpv6-type echo-request \
+             -m limit --limit 1/second -j ACCEPT"
+        );
+    }
+
+    #[test]
+    fn t_chain_prefix() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]).with_chain_prefix("myapp-");
+        let custom = Filter::Custom("our-chain".into());
+        iptables.push(
+            Action::NewChain,
+            Rule {
+                chain: custom.clone(),
+                restrictions: vec![],
+                rule_action: RuleAction::None,
+            },
+            RecreatingMode::Owned,
+        );
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: custom.clone(),
+                restrictions: vec![],
+                rule_action: RuleAction::Goto(Filter::Custom("other".into())),
+            },
+            RecreatingMode::Owned,
+        );
+        iptables.push(
+            Action::Insert(1),
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![],
+                rule_action: RuleAction::Jump(custom),
+            },
+            RecreatingMode::Owned,
+        );
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: Nat::Custom("n-chain".into()),
+                restrictions: vec![],
+                rule_action: RuleAction::Jump(Nat::POSTROUTING),
+            },
+            RecreatingMode::Owned,
+        );
+        assert_eq!(
+            iptables.to_string(),
+            " -t filter -N myapp-our-chain\n\
+             \x20-t filter -A myapp-our-chain -g myapp-other\n\
+             \x20-t filter -I INPUT 1 -j myapp-our-chain\n\
+             \x20-t nat -A myapp-n-chain -j POSTROUTING\n"
+        );
+    }
+
+    #[test]
+    fn t_conntrack_tuple() {
+        let m = ConntrackMatch {
+            orig_dst: Some((Negatable::Is, "10.0.0.1/32".parse().unwrap())),
+            orig_dst_port: Some((Negatable::IsNot, 8080)),
+            ..Default::default()
+        };
+        let mut args = Vec::new();
+        Restriction::Conntrack(m.clone()).push_args(&mut args);
+        assert_eq!(
+            args,
+            [
+                "-m",
+                "conntrack",
+                "--ctorigdst",
+                "10.0.0.1/32",
+                "!",
+                "--ctorigdstport",
+                "8080"
+            ]
+        );
+
+        let writer = |cmd: &str| {
+            let mut iptables = IptablesWriter::new(vec![cmd.into()]);
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Nat::PREROUTING,
+                    restrictions: vec![Restriction::Conntrack(m.clone())],
+                    rule_action: RuleAction::Accept,
+                },
+                RecreatingMode::Owned,
+            );
+            iptables
+        };
+        assert!(writer("iptables").validate().is_ok());
+        assert_eq!(
+            writer("ip6tables").validate().unwrap_err().to_string(),
+            "rule in chain \"PREROUTING\" of table nat: conntrack address 10.0.0.1/32 \
+             does not match the address family of \"ip6tables\""
+        );
+
+        let mut ip6tables = IptablesWriter::new(vec!["ip6tables".into()]);
+        ip6tables.push(
+            Action::Append,
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![Restriction::DestinationAddress(
+                    Negatable::Is,
+                    "10.0.0.1/32".parse().unwrap(),
+                )],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::Owned,
+        );
+        assert_eq!(
+            ip6tables.validate().unwrap_err().to_string(),
+            "rule in chain \"INPUT\" of table filter: destination address 10.0.0.1/32 \
+             does not match the address family of \"ip6tables\""
+        );
+    }
+
+    #[test]
+    fn t_bpf_u32() {
+        let rule = |r: Restriction| Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![r],
+            rule_action: RuleAction::Drop,
+        };
+        let bpf = rule(Restriction::Bpf(
+            "4,48 0 0 9,21 0 1 6,6 0 0 1,6 0 0 0".into(),
+        ));
+        assert_eq!(
+            bpf.cmd_args(Action::Append.into())[4..],
+            [
+                "-m",
+                "bpf",
+                "--bytecode",
+                "4,48 0 0 9,21 0 1 6,6 0 0 1,6 0 0 0",
+                "-j",
+                "DROP"
+            ]
+        );
+        assert!(bpf.validate().is_ok());
+        let u32 = Restriction::U32("0>>22&0x3C@ 12>>26&0x3C@ 0=0x16030100".into());
+        assert_eq!(
+            u32.to_string(),
+            "-m u32 --u32 '0>>22&0x3C@ 12>>26&0x3C@ 0=0x16030100'"
+        );
+        assert!(rule(u32).validate().is_ok());
+        assert_eq!(
+            rule(Restriction::U32(" ".into()))
+                .validate()
+                .unwrap_err()
+                .to_string(),
+            "rule in chain \"INPUT\": empty expression for the u32 match"
+        );
+        assert!(rule(Restriction::Bpf("".into())).validate().is_err());
+    }
+
+    #[test]
+    fn t_fragment() {
+        let args = |r: Restriction| {
+            let mut out = Vec::new();
+            r.push_args(&mut out);
+            out
+        };
+        assert_eq!(args(Restriction::Fragment(Negatable::Is)), ["-f"]);
+        assert_eq!(args(Restriction::Fragment(Negatable::IsNot)), ["!", "-f"]);
+        assert_eq!(
+            Restriction::Fragment(Negatable::IsNot).to_string(),
+            "'!' -f"
+        );
+    }
+
+    #[test]
+    fn t_multiport() {
+        let args = |r: Restriction| {
+            let mut out = Vec::new();
+            r.push_args(&mut out);
+            out
+        };
+        assert_eq!(
+            args(Restriction::destination_ports(
+                Negatable::Is,
+                &[22, 80, 9080]
+            )),
+            ["-m", "multiport", "--dports", "22,80,9080"]
+        );
+        assert_eq!(
+            args(Restriction::destination_ports(Negatable::Is, &[22])),
+            ["--dport", "22"]
+        );
+        assert_eq!(
+            args(Restriction::source_ports(Negatable::IsNot, &[53, 123])),
+            ["-m", "multiport", "!", "--sports", "53,123"]
+        );
+        assert!(Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![Restriction::DestinationPorts(Negatable::Is, vec![1, 2])],
+            rule_action: RuleAction::Accept,
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn t_push_logged_drop() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push_logged_drop(
+            Filter::INPUT,
+            vec![
+                Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                Restriction::DestinationPort(Negatable::Is, 23),
+            ],
+            "telnet: ",
+        );
+        let plan: Vec<_> = iptables
+            .build_plan(Effect::Creation)
+            .iter()
+            .map(|p| p.cmd_args())
+            .collect();
+        assert_eq!(
+            plan,
+            [
+                vec![
+                    "-t",
+                    "filter",
+                    "-A",
+                    "INPUT",
+                    "-p",
+                    "tcp",
+                    "--dport",
+                    "23",
+                    "-j",
+                    "LOG",
+                    "--log-prefix",
+                    "telnet: "
+                ],
+                vec!["-t", "filter", "-A", "INPUT", "-p", "tcp", "--dport", "23", "-j", "DROP"],
+            ]
+        );
+    }
+
+    #[test]
+    fn t_execute_parallel() {
+        let iptables = multi_table_writer();
+        let serial: Vec<String> = iptables
+            .build_plan(Effect::Recreation)
+            .iter()
+            .map(|p| format!("iptables {}", p.cmd_args().join(" ")))
+            .collect();
+        let in_table = |lines: &[String], table: &str| -> Vec<String> {
+            lines
+                .iter()
+                .filter(|l| l.contains(&format!("-t {table} ")))
+                .cloned()
+                .collect()
+        };
+
+        let executor = RecordingExecutor {
+            log: Default::default(),
+            fail_on: None,
+        };
+        let mut output = Vec::new();
+        iptables
+            .execute_parallel(
+                Effect::Recreation,
+                Some(&mut output),
+                &executor,
+                &ExecuteOptions::default(),
+            )
+            .unwrap();
+        let log = executor.log.lock().unwrap().clone();
+        assert_eq!(log.len(), serial.len());
+        for table in ["filter", "nat"] {
+            assert_eq!(in_table(&log, table), in_table(&serial, table));
+        }
+        // Verbose output is grouped per table, in order of first use
+        // (the deletions run in reverse, hence nat comes first)
+        let output = String::from_utf8(output).unwrap();
+        let mut expected = in_table(&serial, "nat");
+        expected.append(&mut in_table(&serial, "filter"));
+        let expected: Vec<String> = expected.iter().map(|l| format!("+ {l}")).collect();
+        assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+
+        let executor = RecordingExecutor {
+            log: Default::default(),
+            fail_on: Some("-A n-chain -p udp --dport 22"),
+        };
+        let err = iptables
+            .execute_parallel(
+                Effect::Creation,
+                None::<Vec<u8>>,
+                &executor,
+                &ExecuteOptions::default(),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "table nat: command `iptables -t nat -A n-chain -p udp --dport 22 -j RETURN` \
+             exited with code 3: "
+        );
+        // The filter table was still fully applied, the nat table up
+        // to the error
+        let log = executor.log.lock().unwrap().clone();
+        assert_eq!(in_table(&log, "filter").len(), 3);
+        assert_eq!(in_table(&log, "nat").len(), 2);
+    }
+
+    #[test]
+    fn t_execute_parallel_with_command() {
+        let restrictions = |port| {
+            vec![
+                Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                Restriction::DestinationPort(Negatable::Is, port),
+            ]
+        };
+        let push_rules = |iptables: &mut IptablesWriter, filter_port, nat_port| {
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Filter::INPUT,
+                    restrictions: restrictions(filter_port),
+                    rule_action: RuleAction::Accept,
+                },
+                RecreatingMode::Owned,
+            );
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Nat::PREROUTING,
+                    restrictions: restrictions(nat_port),
+                    rule_action: RuleAction::Return,
+                },
+                RecreatingMode::Owned,
+            );
+        };
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        push_rules(&mut iptables, 1, 2);
+        iptables.push_command(
+            vec!["sysctl".into(), "-w".into(), "net.ipv4.ip_forward=1".into()],
+            Some(vec![
+                "sysctl".into(),
+                "-w".into(),
+                "net.ipv4.ip_forward=0".into(),
+            ]),
+        );
+        push_rules(&mut iptables, 3, 4);
+
+        let run = |want, options: &ExecuteOptions| {
+            let executor = RecordingExecutor {
+                log: Default::default(),
+                fail_on: None,
+            };
+            let mut output = Vec::new();
+            iptables
+                .execute_parallel(want, Some(&mut output), &executor, options)
+                .unwrap();
+            let log = executor.log.lock().unwrap().clone();
+            (log, String::from_utf8(output).unwrap())
+        };
+        let position = |log: &[String], part: &str| log.iter().position(|l| l.contains(part));
+
+        // The hook runs after the rules pushed before it, and before
+        // the ones pushed after it
+        let (log, _) = run(Effect::Creation, &ExecuteOptions::default());
+        assert_eq!(log.len(), 5);
+        assert_eq!(position(&log, "ip_forward=1"), Some(2));
+        assert!(position(&log, "--dport 1").unwrap() < 2);
+        assert!(position(&log, "--dport 2").unwrap() < 2);
+        // When deleting, its inverse runs after the later rules are
+        // removed
+        let (log, _) = run(Effect::Deletion, &ExecuteOptions::default());
+        assert_eq!(position(&log, "ip_forward=0"), Some(2));
+        assert!(position(&log, "--dport 3").unwrap() < 2);
+        assert!(position(&log, "--dport 4").unwrap() < 2);
+
+        let (_, output) = run(
+            Effect::Creation,
+            &ExecuteOptions {
+                sections: true,
+                interpretations: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            output,
+            "# INPUT rules\n\
+             +[Ok] iptables -t filter -A INPUT -p tcp --dport 1 -j ACCEPT\n\
+             \n\
+             # PREROUTING rules\n\
+             +[Ok] iptables -t nat -A PREROUTING -p tcp --dport 2 -j RETURN\n\
+             \n\
+             # commands\n\
+             +[Ok] sysctl -w net.ipv4.ip_forward=1\n\
+             \n\
+             # INPUT rules\n\
+             +[Ok] iptables -t filter -A INPUT -p tcp --dport 3 -j ACCEPT\n\
+             \n\
+             # PREROUTING rules\n\
+             +[Ok] iptables -t nat -A PREROUTING -p tcp --dport 4 -j RETURN\n"
+        );
+    }
+
+    #[test]
+    fn t_interface_spec() {
+        assert_eq!(InterfaceSpec::Prefix("wg".into()).to_arg(), "wg+");
+        assert_eq!(InterfaceSpec::Exact("eth0".into()).to_arg(), "eth0");
+
+        let mut out = Vec::new();
+        Restriction::Interface(Negatable::IsNot, InterfaceSpec::Prefix("wg".into()))
+            .push_args(&mut out);
+        assert_eq!(out, ["!", "-i", "wg+"]);
+    }
+
+    #[test]
+    fn t_skip_missing_interfaces() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()])
+            .with_skip_missing_interfaces(|| Ok(vec!["lo".into(), "eth0".into()]));
+        for interface in ["eth0", "tun0"] {
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Filter::INPUT,
+                    restrictions: vec![Restriction::Interface(
+                        Negatable::Is,
+                        InterfaceSpec::Exact(interface.into()),
+                    )],
+                    rule_action: RuleAction::Accept,
+                },
+                RecreatingMode::Owned,
+            );
+        }
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![],
+                rule_action: RuleAction::Drop,
+            },
+            RecreatingMode::Owned,
+        );
+        let mut executor = RecordingExecutor {
+            log: Default::default(),
+            fail_on: None,
+        };
+        let mut output = Vec::new();
+        iptables
+            .execute(Effect::Creation, Some(&mut output), &mut executor)
+            .unwrap();
+        assert_eq!(
+            *executor.log.lock().unwrap(),
+            [
+                "iptables -t filter -A INPUT -i eth0 -j ACCEPT",
+                "iptables -t filter -A INPUT -j DROP",
+            ]
+        );
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "+ iptables -t filter -A INPUT -i eth0 -j ACCEPT\n\
+             W iptables -t filter -A INPUT -i tun0 -j ACCEPT \
+             (interface \"tun0\" does not exist, skipped)\n\
+             + iptables -t filter -A INPUT -j DROP\n"
+        );
+        assert_eq!(
+            iptables.skipped_commands(Effect::Creation).unwrap(),
+            [(
+                "iptables -t filter -A INPUT -i tun0 -j ACCEPT".to_string(),
+                "interface \"tun0\" does not exist".to_string()
+            )]
+        );
+        assert!(IptablesWriter::new(vec!["iptables".into()])
+            .skipped_commands(Effect::Creation)
+            .unwrap()
+            .is_empty());
+
+        let failing = iptables.with_skip_missing_interfaces(|| bail!("no netlink"));
+        let err = failing
+            .execute(Effect::Creation, None::<Vec<u8>>, &mut DryExecutor)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "looking up network interfaces: no netlink");
+    }
+
+    #[test]
+    fn t_classify_exit_codes() {
+        use ResultInterpretation::*;
+        assert_eq!(
+            IptablesExitCode::from(2),
+            IptablesExitCode::ParameterProblem
+        );
+        assert_eq!(IptablesExitCode::from(9), IptablesExitCode::Other(9));
+        assert_eq!(classify(0, ""), Ok);
+        assert_eq!(
+            classify(
+                1,
+                "iptables: Bad rule (does a matching rule exist in that chain?)."
+            ),
+            OkForDeletions
+        );
+        assert_eq!(
+            classify(2, "iptables: No chain/target/match by that name."),
+            OkForDeletions
+        );
+        assert_eq!(
+            classify(1, "iptables: Chain already exists."),
+            ChainAlreadyExists
+        );
+        assert_eq!(
+            classify(4, "iptables: CHAIN_DEL failed (Device or resource busy)."),
+            ChainInUse
+        );
+        assert_eq!(classify(4, "Permission denied (you must be root)"), Err);
+        assert_eq!(classify(3, "can't initialize iptables table `foo'"), Err);
+        assert_eq!(classify(127, ""), Err);
+    }
+
+    #[test]
+    fn t_finalize_chain() {
+        let plan = |mut iptables: IptablesWriter| -> Vec<String> {
+            iptables.finalize_chain(Filter::Custom("services".into()), "services drop: ");
+            iptables
+                .build_plan(Effect::Creation)
+                .iter()
+                .map(|p| p.cmd_args().join(" "))
+                .collect()
+        };
+        assert_eq!(
+            plan(IptablesWriter::new(vec!["iptables".into()])),
+            [
+                "-t filter -A services -m limit --limit 5/minute -j LOG --log-prefix services drop: ",
+                "-t filter -A services -j DROP",
+            ]
+        );
+        assert_eq!(
+            plan(IptablesWriter::new(vec!["iptables".into()]).with_auto_limit_logs("1/second"))[0],
+            "-t filter -A services -m limit --limit 1/second -j LOG --log-prefix services drop: "
+        );
+    }
+
+    #[test]
+    fn t_vlan() {
+        let rule = |id, prio| Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![Restriction::Vlan {
+                id,
+                prio,
+                neg: Negatable::Is,
+            }],
+            rule_action: RuleAction::Accept,
+        };
+        let args = |r: Rule<Filter>| r.cmd_args(Action::Append.into())[4..].join(" ");
+        assert_eq!(
+            args(rule(Some(100), None)),
+            "-m vlan --vlan-id 100 -j ACCEPT"
+        );
+        assert_eq!(args(rule(None, Some(5))), "-m vlan --vlan-prio 5 -j ACCEPT");
+        assert_eq!(
+            args(rule(Some(4095), Some(7))),
+            "-m vlan --vlan-id 4095 --vlan-prio 7 -j ACCEPT"
+        );
+        assert_eq!(
+            Restriction::Vlan {
+                id: Some(1),
+                prio: None,
+                neg: Negatable::IsNot
+            }
+            .to_string(),
+            "-m vlan '!' --vlan-id 1"
+        );
+        assert!(rule(Some(4095), Some(7)).validate().is_ok());
+        assert_eq!(
+            rule(Some(4096), None).validate().unwrap_err().to_string(),
+            "rule in chain \"INPUT\": vlan id 4096 is above 4095"
+        );
+        assert!(rule(None, Some(8)).validate().is_err());
+        assert!(rule(None, None).validate().is_err());
+    }
+
+    #[test]
+    fn t_custom_verbose_markers() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        for port in [22, 80] {
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Filter::INPUT,
+                    restrictions: vec![
+                        Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                        Restriction::DestinationPort(Negatable::Is, port),
+                    ],
+                    rule_action: RuleAction::Accept,
+                },
+                RecreatingMode::Owned,
+            );
+        }
+        let options = ExecuteOptions {
+            markers: VerboseMarkers {
+                success: "OK".into(),
+                exit_code: "FAIL".into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut executor = RecordingExecutor {
+            log: Default::default(),
+            fail_on: Some("80"),
+        };
+        let mut output = Vec::new();
+        iptables
+            .execute_with(Effect::Creation, Some(&mut output), &mut executor, &options)
+            .unwrap_err();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "OK iptables -t filter -A INPUT -p tcp --dport 22 -j ACCEPT\n\
+             FAIL iptables -t filter -A INPUT -p tcp --dport 80 -j ACCEPT\n"
+        );
+    }
+
+    #[test]
+    fn t_sctp_dccp_ports() {
+        let rule = |protocol, restrictions: Vec<Restriction>| Rule {
+            chain: Filter::INPUT,
+            restrictions: [
+                vec![Restriction::Protocol(Negatable::Is, protocol)],
+                restrictions,
+            ]
+            .concat(),
+            rule_action: RuleAction::Accept,
+        };
+        let args = |r: Rule<Filter>| r.cmd_args(Action::Append.into())[4..].join(" ");
+        assert_eq!(
+            args(rule(
+                Protocol::Sctp,
+                vec![Restriction::DestinationPort(Negatable::Is, 3868)]
+            )),
+            "-p sctp -m sctp --dport 3868 -j ACCEPT"
+        );
+        assert_eq!(
+            args(rule(
+                Protocol::Dccp,
+                vec![
+                    Restriction::SourcePort(Negatable::Is, 1000),
+                    Restriction::DestinationPort(Negatable::IsNot, 5004),
+                ]
+            )),
+            "-p dccp -m dccp --sport 1000 ! --dport 5004 -j ACCEPT"
+        );
+        assert_eq!(
+            args(rule(
+                Protocol::Tcp,
+                vec![Restriction::DestinationPort(Negatable::Is, 22)]
+            )),
+            "-p tcp --dport 22 -j ACCEPT"
+        );
+        assert!(rule(
+            Protocol::Dccp,
+            vec![Restriction::DestinationPort(Negatable::Is, 5004)]
+        )
+        .validate()
+        .is_ok());
+
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push(
+            Action::Append,
+            rule(
+                Protocol::Sctp,
+                vec![Restriction::DestinationPort(Negatable::Is, 3868)],
+            ),
+            RecreatingMode::Owned,
+        );
+        assert_eq!(
+            iptables.to_csv(Effect::Creation).lines().nth(1).unwrap(),
+            "1,filter,INPUT,append,-p sctp -m sctp --dport 3868,-j ACCEPT"
+        );
+    }
+
+    #[test]
+    fn t_reject_with_family() {
+        let reject = |cmd: &str, with| {
+            let mut iptables = IptablesWriter::new(vec![cmd.into()]);
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Filter::INPUT,
+                    restrictions: vec![],
+                    rule_action: RuleAction::RejectWith(with),
+                },
+                RecreatingMode::Owned,
+            );
+            iptables.build_plan(Effect::Creation)[0]
+                .cmd_args()
+                .join(" ")
+        };
+        assert_eq!(
+            reject("iptables", RejectWith::IcmpAdminProhibited),
+            "-t filter -A INPUT -j REJECT --reject-with icmp-admin-prohibited"
+        );
+        assert_eq!(
+            reject("ip6tables", RejectWith::IcmpAdminProhibited),
+            "-t filter -A INPUT -j REJECT --reject-with icmp6-adm-prohibited"
+        );
+        assert_eq!(
+            reject("iptables", RejectWith::Icmp6NoRoute),
+            "-t filter -A INPUT -j REJECT --reject-with icmp-net-unreachable"
+        );
+        assert_eq!(
+            reject("/sbin/ip6tables", RejectWith::TcpReset),
+            "-t filter -A INPUT -j REJECT --reject-with tcp-reset"
+        );
+        assert_eq!(
+            RejectWith::from_arg("icmp6-port-unreachable"),
+            Some(RejectWith::Icmp6PortUnreachable)
+        );
+        assert_eq!(RejectWith::from_arg("icmp-foo"), None);
+    }
+
+    #[test]
+    fn t_push_for_protocols() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push_for_protocols(
+            Filter::INPUT,
+            &[Protocol::Tcp, Protocol::Udp],
+            vec![Restriction::DestinationPort(Negatable::Is, 53)],
+            RuleAction::Accept,
+        );
+        let plan: Vec<String> = iptables
+            .build_plan(Effect::Creation)
+            .iter()
+            .map(|p| p.cmd_args().join(" "))
+            .collect();
+        assert_eq!(
+            plan,
+            [
+                "-t filter -A INPUT -p tcp --dport 53 -j ACCEPT",
+                "-t filter -A INPUT -p udp --dport 53 -j ACCEPT",
+            ]
+        );
+    }
+
+    #[test]
+    fn t_push_command() {
+        let sysctl = |value: &str| -> Vec<String> {
+            vec![
+                "sysctl".into(),
+                "-w".into(),
+                format!("net.ipv4.ip_forward={value}"),
+            ]
+        };
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push_command(sysctl("1"), Some(sysctl("0")));
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: Filter::FORWARD,
+                restrictions: vec![],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::Owned,
+        );
+        iptables.push_command(vec!["true".into()], None);
+        assert!(iptables.validate().is_ok());
+        let plan = |want| -> Vec<String> {
+            iptables
+                .build_plan(want)
+                .iter()
+                .map(|p| p.full_cmd(iptables.iptables_cmd()).join(" "))
+                .collect()
+        };
+        assert_eq!(
+            plan(Effect::Creation),
+            [
+                "sysctl -w net.ipv4.ip_forward=1",
+                "iptables -t filter -A FORWARD -j ACCEPT",
+                "true",
+            ]
+        );
+
+        let mut executor = RecordingExecutor {
+            log: Default::default(),
+            fail_on: None,
+        };
+        iptables
+            .execute(Effect::Deletion, None::<Vec<u8>>, &mut executor)
+            .unwrap();
+        assert_eq!(
+            *executor.log.lock().unwrap(),
+            [
+                "iptables -t filter -D FORWARD -j ACCEPT",
+                "sysctl -w net.ipv4.ip_forward=0",
+            ]
+        );
+        assert_eq!(
+            iptables.to_iptables_save(),
+            "*filter\n-A FORWARD -j ACCEPT\nCOMMIT\n"
+        );
+    }
+
+    #[test]
+    fn t_negation_placement() {
+        use Negatable::IsNot;
+        let args = |r: Restriction| {
+            let mut out = Vec::new();
+            r.push_args(&mut out);
+            out
+        };
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        let cases: Vec<(Restriction, &[&str])> = vec![
+            (
+                Restriction::Interface(IsNot, InterfaceSpec::Exact("eth0".into())),
+                &["!", "-i", "eth0"],
+            ),
+            (
+                Restriction::Protocol(IsNot, Protocol::Tcp),
+                &["!", "-p", "tcp"],
+            ),
+            (
+                Restriction::SourceAddress(IsNot, net),
+                &["!", "-s", "10.0.0.0/8"],
+            ),
+            (
+                Restriction::DestinationAddress(IsNot, net),
+                &["!", "-d", "10.0.0.0/8"],
+            ),
+            (Restriction::SourcePort(IsNot, 53), &["!", "--sport", "53"]),
+            (
+                Restriction::DestinationPort(IsNot, 22),
+                &["!", "--dport", "22"],
+            ),
+            (Restriction::Fragment(IsNot), &["!", "-f"]),
+            (
+                Restriction::SourcePorts(IsNot, vec![1, 2]),
+                &["-m", "multiport", "!", "--sports", "1,2"],
+            ),
+            (
+                Restriction::DestinationPorts(IsNot, vec![1, 2]),
+                &["-m", "multiport", "!", "--dports", "1,2"],
+            ),
+            (
+                Restriction::IcmpType(IsNot, "echo-request".into()),
+                &["!", "--icmp-type", "echo-request"],
+            ),
+            (
+                Restriction::Icmpv6Type(IsNot, "echo-request".into()),
+                &["!", "--icmpv6-type", "echo-request"],
+            ),
+            (
+                Restriction::ConnState(IsNot, vec![ConnState::New]),
+                &["-m", "conntrack", "!", "--ctstate", "NEW"],
+            ),
+            (
+                Restriction::Conntrack(ConntrackMatch {
+                    proto: Some((IsNot, Protocol::Udp)),
+                    ..Default::default()
+                }),
+                &["-m", "conntrack", "!", "--ctproto", "udp"],
+            ),
+            (
+                Restriction::Vlan {
+                    id: Some(7),
+                    prio: None,
+                    neg: IsNot,
+                },
+                &["-m", "vlan", "!", "--vlan-id", "7"],
+            ),
+        ];
+        for (restriction, expected) in cases {
+            assert_eq!(args(restriction), expected);
+        }
+    }
+
+    #[test]
+    fn t_max_rules() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]).with_max_rules(2);
+        for port in [22, 80, 443] {
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Filter::INPUT,
+                    restrictions: vec![
+                        Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                        Restriction::DestinationPort(Negatable::Is, port),
+                    ],
+                    rule_action: RuleAction::Accept,
+                },
+                RecreatingMode::Owned,
+            );
+            if port == 80 {
+                assert!(iptables.validate().is_ok());
+            }
+        }
+        assert_eq!(
+            iptables.validate().unwrap_err().to_string(),
+            "3 rules were pushed, more than the maximum of 2"
+        );
+        let mut executor = RecordingExecutor {
+            log: Default::default(),
+            fail_on: None,
+        };
+        assert!(matches!(
+            iptables.execute(Effect::Creation, None::<Vec<u8>>, &mut executor),
+            Err(FirewallError::TooManyRules { count: 3, max: 2 })
+        ));
+        assert!(executor.log.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn t_connbytes() {
+        let rule = |range| Rule {
+            chain: Mangle::FORWARD,
+            restrictions: vec![Restriction::ConnBytes {
+                range,
+                dir: ConnBytesDir::Both,
+                mode: ConnBytesMode::Bytes,
+                neg: Negatable::Is,
+            }],
+            rule_action: RuleAction::Jump(Mangle::Custom("bulk".into())),
+        };
+        let large = rule((10000, None));
+        assert_eq!(
+            large.cmd_args(Action::Append.into())[4..].join(" "),
+            "-m connbytes --connbytes 10000: --connbytes-dir both --connbytes-mode bytes -j bulk"
+        );
+        assert!(large.validate().is_ok());
+        assert_eq!(
+            Restriction::ConnBytes {
+                range: (0, Some(10)),
+                dir: ConnBytesDir::Original,
+                mode: ConnBytesMode::Packets,
+                neg: Negatable::IsNot,
+            }
+            .to_string(),
+            "-m connbytes '!' --connbytes 0:10 --connbytes-dir original --connbytes-mode packets"
+        );
+        assert!(rule((10, Some(10))).validate().is_ok());
+        assert_eq!(
+            rule((10, Some(9))).validate().unwrap_err().to_string(),
+            "rule in chain \"FORWARD\": connbytes range 10:9 is empty"
+        );
+    }
+
+    #[test]
+    fn t_dscp_tos() {
+        let rule = |r| Rule {
+            chain: Mangle::PREROUTING,
+            restrictions: vec![r],
+            rule_action: RuleAction::Accept,
+        };
+        let args = |r: Rule<Mangle>| r.cmd_args(Action::Append.into())[4..].join(" ");
+        assert_eq!(
+            args(rule(Restriction::Dscp(Negatable::Is, 46))),
+            "-m dscp --dscp 46 -j ACCEPT"
+        );
+        assert_eq!(
+            args(rule(Restriction::Dscp(Negatable::IsNot, 0))),
+            "-m dscp ! --dscp 0 -j ACCEPT"
+        );
+        assert_eq!(
+            args(rule(Restriction::Tos(
+                Negatable::Is,
+                "Minimize-Delay".into()
+            ))),
+            "-m tos --tos Minimize-Delay -j ACCEPT"
+        );
+        assert_eq!(
+            args(rule(Restriction::Tos(Negatable::IsNot, "0x10/0x3f".into()))),
+            "-m tos ! --tos 0x10/0x3f -j ACCEPT"
+        );
+        assert!(rule(Restriction::Dscp(Negatable::Is, 63))
+            .validate()
+            .is_ok());
+        assert_eq!(
+            rule(Restriction::Dscp(Negatable::Is, 64))
+                .validate()
+                .unwrap_err()
+                .to_string(),
+            "rule in chain \"PREROUTING\": dscp value 64 is above 63"
+        );
+    }
+
+    #[test]
+    fn t_string_enum_conversions() {
+        assert_eq!(String::from(Protocol::Tcp), "tcp");
+        assert_eq!(String::from(&Protocol::Tcp), "tcp");
+        let state: String = ConnState::Established.into();
+        assert_eq!(state, "ESTABLISHED");
+        assert_eq!(String::from(&ConnState::Established), "ESTABLISHED");
+        assert_eq!(String::from(Filter::INPUT), "INPUT");
+        assert_eq!(String::from(Filter::Custom("x".into())), "x");
+        assert_eq!(String::from(&Filter::Custom("x".into())), "x");
+        assert_eq!(String::from(Nat::Custom("my-nat".into())), "my-nat");
+    }
+
+    #[test]
+    fn t_allow_ssh_from() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables
+            .allow_ssh_from(Filter::INPUT, "192.168.1.0/24".parse().unwrap(), None)
+            .unwrap();
+        iptables
+            .allow_ssh_from(Filter::INPUT, "10.0.0.0/8".parse().unwrap(), Some(2222))
+            .unwrap();
+        let plan: Vec<Vec<String>> = iptables
+            .build_plan(Effect::Creation)
+            .iter()
+            .map(|p| p.cmd_args()[4..].to_vec())
+            .collect();
+        assert_eq!(
+            plan[0],
+            [
+                "-p",
+                "tcp",
+                "--dport",
+                "22",
+                "-s",
+                "192.168.1.0/24",
+                "-m",
+                "conntrack",
+                "--ctstate",
+                "NEW",
+                "-j",
+                "ACCEPT"
+            ]
+        );
+        assert_eq!(plan[1][3], "2222");
+
+        let mut ip6tables = IptablesWriter::new(vec!["ip6tables".into()]);
+        ip6tables
+            .allow_ssh_from(Filter::INPUT, "fd00::/64".parse().unwrap(), None)
+            .unwrap();
+        assert_eq!(
+            ip6tables.build_plan(Effect::Creation)[0].cmd_args()[4..].join(" "),
+            "-p tcp --dport 22 -s fd00::/64 -m conntrack --ctstate NEW -j ACCEPT"
+        );
+
+        assert_eq!(
+            ip6tables
+                .allow_ssh_from(Filter::INPUT, "10.0.0.0/8".parse().unwrap(), None)
+                .unwrap_err()
+                .to_string(),
+            "address 10.0.0.0/8 does not match the address family of \"ip6tables\""
+        );
+        assert_eq!(ip6tables.build_plan(Effect::Creation).len(), 1);
+        assert_eq!(
+            iptables
+                .allow_ssh_from(Filter::INPUT, "fd00::/64".parse().unwrap(), None)
+                .unwrap_err()
+                .to_string(),
+            "address fd00::/64 does not match the address family of \"iptables\""
+        );
+    }
+
+    #[test]
+    fn t_required_action() {
+        let counting = Filter::Custom("count-ssh".into());
+        let build = |iptables: IptablesWriter, chain: Filter| {
+            let mut iptables = iptables;
+            iptables.push(
+                Action::NewChain,
+                Rule {
+                    chain: counting.clone(),
+                    restrictions: vec![],
+                    rule_action: RuleAction::None,
+                },
+                RecreatingMode::Owned,
+            );
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain,
+                    restrictions: vec![
+                        Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                        Restriction::DestinationPort(Negatable::Is, 22),
+                    ],
+                    rule_action: RuleAction::None,
+                },
+                RecreatingMode::Owned,
+            );
+            iptables
+        };
+        let new = || IptablesWriter::new(vec!["iptables".into()]);
+
+        // Not opted in
+        build(new(), Filter::INPUT).validate().unwrap();
+
+        let exempt = || vec![(Table::Filter, "count-ssh".into())];
+        build(new().with_required_action(exempt()), counting.clone())
+            .validate()
+            .unwrap();
+        assert_eq!(
+            build(new().with_required_action(exempt()), Filter::INPUT)
+                .validate()
+                .unwrap_err()
+                .to_string(),
+            "rule in chain \"INPUT\" of table filter has no rule action: \
+             \"-t filter -A INPUT -p tcp --dport 22\""
+        );
+        assert!(build(new().with_required_action(vec![]), counting.clone())
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn t_bare_host_addresses() {
+        let build = |iptables: IptablesWriter| {
+            let mut iptables = iptables;
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Filter::INPUT,
+                    restrictions: vec![
+                        Restriction::SourceAddress(Negatable::Is, "10.0.0.1/32".parse().unwrap()),
+                        Restriction::DestinationAddress(
+                            Negatable::IsNot,
+                            "10.1.0.0/16".parse().unwrap(),
+                        ),
+                    ],
+                    rule_action: RuleAction::Accept,
+                },
+                RecreatingMode::Owned,
+            );
+            iptables
+        };
+        let new = || IptablesWriter::new(vec!["iptables".into()]);
+        assert_eq!(
+            build(new()).to_string(),
+            " -t filter -A INPUT -s 10.0.0.1/32 ! -d 10.1.0.0/16 -j ACCEPT\n"
+        );
+        let bare = build(new().with_bare_host_addresses(true));
+        assert_eq!(
+            bare.to_string(),
+            " -t filter -A INPUT -s 10.0.0.1 ! -d 10.1.0.0/16 -j ACCEPT\n"
+        );
+        assert_eq!(
+            bare.build_plan(Effect::Deletion)[0].cmd_args()[4..6],
+            ["-s", "10.0.0.1"]
+        );
+        assert_eq!(
+            bare.to_csv(Effect::Creation).lines().nth(1).unwrap(),
+            "1,filter,INPUT,append,-s 10.0.0.1 '!' -d 10.1.0.0/16,-j ACCEPT"
+        );
+        assert_eq!(
+            Restriction::SourceAddress(Negatable::Is, "10.0.0.1/32".parse().unwrap())
+                .to_bare_string(),
+            "-s 10.0.0.1"
+        );
+
+        let mut args: Vec<String> = ["-s", "fd00::1/128", "--ctorigdst", "fd00::/64"]
+            .map(String::from)
+            .to_vec();
+        strip_host_prefixes(&mut args);
+        assert_eq!(args, ["-s", "fd00::1", "--ctorigdst", "fd00::/64"]);
+    }
+
+    #[test]
+    fn t_tcp_option() {
+        let rule = |restrictions| Rule {
+            chain: Filter::INPUT,
+            restrictions,
+            rule_action: RuleAction::Drop,
+        };
+        let tcp = Restriction::Protocol(Negatable::Is, Protocol::Tcp);
+        let args = |r: Rule<Filter>| r.cmd_args(Action::Append.into())[4..].join(" ");
+        let plain = rule(vec![tcp.clone(), Restriction::TcpOption(Negatable::Is, 8)]);
+        plain.validate().unwrap();
+        assert_eq!(args(plain), "-p tcp -m tcp --tcp-option 8 -j DROP");
+        let negated = rule(vec![tcp, Restriction::TcpOption(Negatable::IsNot, 2)]);
+        negated.validate().unwrap();
+        assert_eq!(args(negated), "-p tcp -m tcp ! --tcp-option 2 -j DROP");
+        assert_eq!(
+            rule(vec![
+                Restriction::Protocol(Negatable::Is, Protocol::Udp),
+                Restriction::TcpOption(Negatable::Is, 8)
+            ])
+            .validate()
+            .unwrap_err()
+            .to_string(),
+            "rule in chain \"INPUT\": --tcp-option requires a tcp protocol restriction"
+        );
+    }
+
+    #[test]
+    fn t_mark() {
+        assert_eq!(Mark::exact(1).to_string(), "0x1");
+        assert_eq!(Mark::with_mask(0x100, 0xff00).to_string(), "0x100/0xff00");
+        assert_eq!(
+            Restriction::Mark(Negatable::Is, Mark::exact(42)).to_string(),
+            "-m mark --mark 0x2a"
+        );
+        assert_eq!(
+            Restriction::Mark(Negatable::IsNot, Mark::with_mask(1, 1)).to_string(),
+            "-m mark '!' --mark 0x1/0x1"
+        );
+    }
+
+    #[test]
+    fn t_iptables_save_table_order() {
+        fn accept<C: TablechainTrait + 'static>(iptables: &mut IptablesWriter, chain: C) {
+            let rule = Rule {
+                chain,
+                restrictions: vec![],
+                rule_action: RuleAction::Accept,
+            };
+            iptables.push(Action::Append, rule, RecreatingMode::Owned);
+        }
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        accept(&mut iptables, Filter::INPUT);
+        accept(&mut iptables, Nat::POSTROUTING);
+        accept(&mut iptables, Raw::PREROUTING);
+        accept(&mut iptables, Security::INPUT);
+        accept(&mut iptables, Mangle::FORWARD);
+        accept(&mut iptables, Filter::OUTPUT);
+        assert_eq!(
+            iptables.to_iptables_save(),
+            "*raw\n-A PREROUTING -j ACCEPT\nCOMMIT\n\
+             *mangle\n-A FORWARD -j ACCEPT\nCOMMIT\n\
+             *nat\n-A POSTROUTING -j ACCEPT\nCOMMIT\n\
+             *filter\n-A INPUT -j ACCEPT\n-A OUTPUT -j ACCEPT\nCOMMIT\n\
+             *security\n-A INPUT -j ACCEPT\nCOMMIT\n"
+        );
+    }
+
+    #[test]
+    fn t_source_comments() {
+        let rule = |rule_action| Rule {
+            chain: Filter::Custom("c".into()),
+            restrictions: vec![],
+            rule_action,
+        };
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]).with_source_comments(true);
+        iptables.push(
+            Action::NewChain,
+            rule(RuleAction::None),
+            RecreatingMode::Owned,
+        );
+        let line = line!() + 1;
+        iptables.push(
+            Action::Append,
+            rule(RuleAction::Accept),
+            RecreatingMode::Owned,
+        );
+        assert_eq!(
+            iptables.to_string(),
+            format!(
+                " -t filter -N c\n \
+                 -t filter -A c -m comment --comment src/iptables.rs:{line} -j ACCEPT\n"
+            )
+        );
+    }
+
+    #[test]
+    fn t_source_comments_of_helpers() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]).with_source_comments(true);
+        let line = line!() + 1;
+        iptables.finalize_chain(Filter::INPUT, "dropped:");
+        assert_eq!(
+            iptables.to_string(),
+            format!(
+                " -t filter -A INPUT -m limit --limit 5/minute \
+                 -m comment --comment src/iptables.rs:{line} -j LOG --log-prefix dropped:\n \
+                 -t filter -A INPUT -m comment --comment src/iptables.rs:{line} -j DROP\n"
+            )
+        );
+
+        // The source comments of all rules pushed by each helper
+        let comments = |iptables: &IptablesWriter| -> Vec<String> {
+            iptables
+                .build_plan(Effect::Creation)
+                .iter()
+                .map(|p| {
+                    let args = p.cmd_args();
+                    let i = args.iter().position(|a| a == "--comment").unwrap();
+                    args[i + 1].clone()
+                })
+                .collect()
+        };
+        let at = |line: u32, n: usize| vec![format!("src/iptables.rs:{line}"); n];
+
+        let mut iptables = IptablesWriter::new(vec!["ip6tables".into()]).with_source_comments(true);
+        let line = line!() + 1;
+        iptables.allow_icmpv6_essentials(Filter::INPUT);
+        assert_eq!(comments(&iptables), at(line, ICMPV6_ESSENTIAL_TYPES.len()));
+
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]).with_source_comments(true);
+        let line = line!() + 1;
+        iptables.drop_invalid_first(Filter::INPUT);
+        assert_eq!(comments(&iptables), at(line, 1));
+
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]).with_source_comments(true);
+        let line = line!() + 1;
+        iptables.allow_limited_ping(Filter::INPUT, "1/second");
+        assert_eq!(comments(&iptables), at(line, 1));
+
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]).with_source_comments(true);
+        let line = line!() + 1;
+        iptables.extend(crate::ruleset::RuleSet::established_related_accept(
+            Filter::INPUT,
+        ));
+        assert_eq!(comments(&iptables), at(line, 1));
+
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]).with_source_comments(true);
+        iptables.push_delete_by_num(Filter::INPUT, 1);
+        // (Only appended or inserted rules get comments)
+        assert_eq!(iptables.to_string(), " -t filter -D INPUT 1\n");
+    }
+
+    #[test]
+    fn t_plan_round_trip() {
+        let iptables = multi_table_writer();
+        let run = |f: &dyn Fn(&mut RecordingExecutor)| {
+            let mut executor = RecordingExecutor {
+                log: Default::default(),
+                fail_on: None,
+            };
+            f(&mut executor);
+            let log = executor.log.lock().unwrap().clone();
+            log
+        };
+        let json = iptables.to_json(Effect::Recreation);
+        let plan = Plan::from_json(&json).unwrap();
+        assert_eq!(plan.version, PLAN_FORMAT_VERSION);
+        let direct = run(&|executor| {
+            iptables
+                .execute(Effect::Recreation, None::<Vec<u8>>, executor)
+                .unwrap()
+        });
+        let applied = run(&|executor| plan.execute(None::<Vec<u8>>, executor).unwrap());
+        assert!(!direct.is_empty());
+        assert_eq!(applied, direct);
+
+        let future = json.replacen("\"version\": 1", "\"version\": 2", 1);
+        assert_eq!(
+            Plan::from_json(&future).unwrap_err().to_string(),
+            "plan has format version 2, only 1 is supported"
+        );
+
+        for action in [
+            AnyAction::Check,
+            Action::Insert(3).into(),
+            Action::EnsureChain.into(),
+            DeletionAction::DeleteByNum(2).into(),
+            DeletionAction::DeleteChain.into(),
+        ] {
+            assert_eq!(AnyAction::from_display(&action.to_string()), Some(action));
+        }
+        assert_eq!(AnyAction::from_display("insert"), None);
+    }
+
+    #[test]
+    fn t_notrack_warnings() {
+        let untracked = Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![Restriction::ConnState(
+                Negatable::Is,
+                vec![ConnState::Untracked],
+            )],
+            rule_action: RuleAction::Accept,
+        };
+        let notrack = Rule {
+            chain: Raw::PREROUTING,
+            restrictions: vec![
+                Restriction::Protocol(Negatable::Is, Protocol::Udp),
+                Restriction::DestinationPort(Negatable::Is, 53),
+            ],
+            rule_action: RuleAction::NoTrack,
+        };
+        assert_eq!(
+            notrack.cmd_args(Action::Append.into())[8..],
+            ["-j", "CT", "--notrack"]
+        );
+
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push(Action::Append, untracked.clone(), RecreatingMode::Owned);
+        assert_eq!(
+            iptables.warnings(),
+            [
+                "rule in chain \"INPUT\" of table filter matches UNTRACKED connections, \
+              but no rule in the raw table disables tracking"
+            ]
+        );
+        iptables.push(Action::Append, notrack.clone(), RecreatingMode::Owned);
+        assert!(iptables.warnings().is_empty());
+
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push(Action::Append, notrack, RecreatingMode::Owned);
+        assert_eq!(
+            iptables.warnings(),
+            [
+                "rule in chain \"PREROUTING\" of table raw disables tracking, but no rule \
+              matches UNTRACKED connections"
+            ]
+        );
+    }
+
+    #[test]
+    fn t_color() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        for rule_action in [RuleAction::Accept, RuleAction::Drop] {
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Filter::INPUT,
+                    restrictions: vec![],
+                    rule_action,
+                },
+                RecreatingMode::Owned,
+            );
+        }
+        let run = |color| {
+            let mut executor = RecordingExecutor {
+                log: Default::default(),
+                fail_on: Some("DROP"),
+            };
+            let mut out = Vec::new();
+            let options = ExecuteOptions {
+                color,
+                ..Default::default()
+            };
+            iptables
+                .execute_with(Effect::Creation, Some(&mut out), &mut executor, &options)
+                .unwrap_err();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            run(true),
+            "\x1b[32m+ iptables -t filter -A INPUT -j ACCEPT\x1b[0m\n\
+             \x1b[31mE iptables -t filter -A INPUT -j DROP\x1b[0m\n"
+        );
+        assert_eq!(
+            run(false),
+            "+ iptables -t filter -A INPUT -j ACCEPT\n\
+             E iptables -t filter -A INPUT -j DROP\n"
+        );
+    }
+
+    #[test]
+    fn t_push_prioritized() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        let rule = |chain, port| Rule {
+            chain,
+            restrictions: vec![
+                Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                Restriction::DestinationPort(Negatable::Is, port),
+            ],
+            rule_action: RuleAction::Accept,
+        };
+        iptables.push_prioritized(10, rule(Filter::INPUT, 10), RecreatingMode::Owned);
+        iptables.push(
+            Action::Insert(1),
+            rule(Filter::INPUT, 1),
+            RecreatingMode::Owned,
+        );
+        iptables.push_prioritized(30, rule(Filter::INPUT, 30), RecreatingMode::Owned);
+        iptables.push_prioritized(5, rule(Filter::FORWARD, 5), RecreatingMode::Owned);
+        iptables.push_prioritized(20, rule(Filter::INPUT, 20), RecreatingMode::Owned);
+        iptables.push_prioritized(20, rule(Filter::INPUT, 21), RecreatingMode::Owned);
+        let plan = |want| -> Vec<String> {
+            iptables
+                .build_plan(want)
+                .iter()
+                .map(|p| p.cmd_args()[2..].join(" ").replace(" -p tcp --dport", ""))
+                .collect()
+        };
+        assert_eq!(
+            plan(Effect::Creation),
+            [
+                "-A INPUT 30 -j ACCEPT",
+                "-I INPUT 1 1 -j ACCEPT",
+                "-A INPUT 20 -j ACCEPT",
+                "-A FORWARD 5 -j ACCEPT",
+                "-A INPUT 21 -j ACCEPT",
+                "-A INPUT 10 -j ACCEPT",
+            ]
+        );
+        assert_eq!(
+            plan(Effect::Deletion),
+            [
+                "-D INPUT 10 -j ACCEPT",
+                "-D INPUT 21 -j ACCEPT",
+                "-D FORWARD 5 -j ACCEPT",
+                "-D INPUT 20 -j ACCEPT",
+                "-D INPUT 1 -j ACCEPT",
+                "-D INPUT 30 -j ACCEPT",
+            ]
+        );
+    }
+
+    #[test]
+    fn t_spi() {
+        let rule = |protocol| Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![
+                Restriction::Protocol(Negatable::Is, protocol),
+                Restriction::Spi(Negatable::Is, 0x100),
+            ],
+            rule_action: RuleAction::Accept,
+        };
+        let args = |r: &Rule<Filter>| r.cmd_args(Action::Append.into())[4..].join(" ");
+        let esp = rule(Protocol::Esp);
+        esp.validate().unwrap();
+        assert_eq!(args(&esp), "-p esp -m esp --espspi 0x100 -j ACCEPT");
+        let ah = rule(Protocol::Ah);
+        ah.validate().unwrap();
+        assert_eq!(args(&ah), "-p ah -m ah --ahspi 0x100 -j ACCEPT");
+        assert_eq!(
+            RuleTrait::restriction_strings(&ah),
+            ["-p ah", "-m ah --ahspi 0x100"]
+        );
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push(Action::Append, ah, RecreatingMode::Owned);
+        assert_eq!(
+            iptables.to_csv(Effect::Creation).lines().nth(1).unwrap(),
+            "1,filter,INPUT,append,-p ah -m ah --ahspi 0x100,-j ACCEPT"
+        );
+        assert_eq!(
+            Restriction::Spi(Negatable::IsNot, 1).to_string(),
+            "-m esp '!' --espspi 0x1"
+        );
+        assert_eq!(
+            rule(Protocol::Udp).validate().unwrap_err().to_string(),
+            "rule in chain \"INPUT\": an SPI match requires an esp or ah protocol restriction"
+        );
+    }
+
+    #[test]
+    fn t_ports() {
+        let rule = |sport, dport, neg| Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![
+                Restriction::Protocol(Negatable::Is, Protocol::Udp),
+                Restriction::Ports { sport, dport, neg },
+            ],
+            rule_action: RuleAction::Accept,
+        };
+        let args = |r: Rule<Filter>| {
+            r.validate().unwrap();
+            r.cmd_args(Action::Append.into())[4..].join(" ")
+        };
+        assert_eq!(
+            args(rule(Some(53), None, Negatable::Is)),
+            "-p udp --sport 53 -j ACCEPT"
+        );
+        assert_eq!(
+            args(rule(None, Some(123), Negatable::Is)),
+            "-p udp --dport 123 -j ACCEPT"
+        );
+        assert_eq!(
+            args(rule(Some(123), Some(123), Negatable::IsNot)),
+            "-p udp ! --sport 123 ! --dport 123 -j ACCEPT"
+        );
+        let mut sctp = rule(Some(1), Some(2), Negatable::Is);
+        sctp.restrictions[0] = Restriction::Protocol(Negatable::Is, Protocol::Sctp);
+        assert_eq!(args(sctp), "-p sctp -m sctp --sport 1 --dport 2 -j ACCEPT");
+        assert_eq!(
+            rule(None, None, Negatable::Is)
+                .validate()
+                .unwrap_err()
+                .to_string(),
+            "rule in chain \"INPUT\": ports match without source or destination port"
+        );
+        let no_protocol = Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![Restriction::Ports {
+                sport: Some(53),
+                dport: None,
+                neg: Negatable::Is,
+            }],
+            rule_action: RuleAction::Accept,
+        };
+        assert_eq!(
+            no_protocol.validate().unwrap_err().to_string(),
+            "rule in chain \"INPUT\": --sport/--dport requires a tcp, udp, udplite, sctp \
+             or dccp protocol restriction"
+        );
+    }
+
+    #[test]
+    fn t_ssh_bruteforce_protection() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.ssh_bruteforce_protection(Filter::INPUT);
+        let plan: Vec<String> = iptables
+            .build_plan(Effect::Creation)
+            .iter()
+            .map(|p| p.cmd_args()[2..].join(" "))
+            .collect();
+        assert_eq!(
+            plan,
+            [
+                "-A INPUT -p tcp --dport 22 -m conntrack --ctstate NEW -m recent --set --name SSH",
+                "-A INPUT -p tcp --dport 22 -m conntrack --ctstate NEW -m recent --update \
+                 --seconds 60 --hitcount 4 --name SSH -j DROP"
+            ]
+        );
+        assert_eq!(
+            Restriction::Recent {
+                name: "x".into(),
+                op: RecentOp::Rcheck {
+                    seconds: None,
+                    hitcount: Some(2)
+                },
+                neg: Negatable::IsNot
+            }
+            .to_string(),
+            "-m recent '!' --rcheck --hitcount 2 --name x"
+        );
+    }
+
+    #[test]
+    fn t_non_creating_action_with_deleting_effect() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push_delete_by_num(Filter::INPUT, 3);
+        let err = iptables
+            .execute(Effect::Recreation, None::<Vec<u8>>, &mut DryExecutor)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "non-creating action `deletebynum 3` for chain \"INPUT\" of table filter can't be \
+             used with a deleting effect"
+        );
+        let mut output = Vec::new();
+        iptables
+            .execute(Effect::Creation, Some(&mut output), &mut DryExecutor)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "+ iptables -t filter -D INPUT 3\n"
+        );
+        assert_eq!(
+            iptables.teardown_script(),
+            "#!/bin/bash\nset -euo pipefail\n"
+        );
+        assert!(iptables.inverse_plan().is_empty());
+        assert_eq!(
+            iptables.to_csv(Effect::Recreation),
+            "order,table,chain,action,match,target\n1,filter,INPUT,deletebynum 3,,\n"
+        );
+    }
+
+    #[test]
+    fn t_interpretations() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::Owned,
+        );
+        // The rule to be deleted is missing
+        let mut executor = crate::executor::ScriptedDryExecutor::new().on(
+            "-D",
+            ExecutorStatus::ExitCode(1),
+            "iptables: Bad rule (does a matching rule exist in that chain?).",
+        );
+        let mut out = Vec::new();
+        let options = ExecuteOptions {
+            interpretations: true,
+            ..Default::default()
+        };
+        iptables
+            .execute_with(Effect::Recreation, Some(&mut out), &mut executor, &options)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "E[OkForDeletions] iptables -t filter -D INPUT -j ACCEPT\n\
+             +[Ok] iptables -t filter -A INPUT -j ACCEPT\n"
+        );
+    }
+
+    #[test]
+    fn t_sorted_save_chains() {
+        let services = Filter::Custom("services".into());
+        let save = |ports: &[u16]| {
+            let mut iptables = IptablesWriter::new(vec!["iptables".into()])
+                .with_sorted_save_chains(vec![(Table::Filter, "services".into())]);
+            let mut push = |action, chain, restrictions, rule_action| {
+                iptables.push(
+                    action,
+                    Rule {
+                        chain,
+                        restrictions,
+                        rule_action,
+                    },
+                    RecreatingMode::Owned,
+                )
+            };
+            push(Action::NewChain, services.clone(), vec![], RuleAction::None);
+            for port in ports {
+                push(
+                    Action::Append,
+                    services.clone(),
+                    vec![
+                        Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                        Restriction::DestinationPort(Negatable::Is, *port),
+                    ],
+                    RuleAction::Accept,
+                );
+            }
+            push(
+                Action::Append,
+                Filter::INPUT,
+                vec![],
+                RuleAction::Jump(services.clone()),
+            );
+            iptables.to_iptables_save()
+        };
+        let save1 = save(&[80, 22, 443]);
+        assert_eq!(save1, save(&[443, 80, 22]));
+        assert_eq!(
+            save1,
+            "*filter\n\
+             :services - [0:0]\n\
+             -A services -p tcp --dport 22 -j ACCEPT\n\
+             -A services -p tcp --dport 443 -j ACCEPT\n\
+             -A services -p tcp --dport 80 -j ACCEPT\n\
+             -A INPUT -j services\n\
+             COMMIT\n"
+        );
+
+        // Other chains keep the order of pushing
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        for rule_action in [RuleAction::Drop, RuleAction::Accept] {
+            iptables.push(
+                Action::Append,
+                Rule {
+                    chain: Filter::INPUT,
+                    restrictions: vec![],
+                    rule_action,
+                },
+                RecreatingMode::Owned,
+            );
+        }
+        assert_eq!(
+            iptables.to_iptables_save(),
+            "*filter\n-A INPUT -j DROP\n-A INPUT -j ACCEPT\nCOMMIT\n"
+        );
+        assert_eq!(strip_counters("[1:2] -A INPUT -j DROP"), "-A INPUT -j DROP");
+    }
+
+    #[test]
+    fn t_addrtype() {
+        let addrtype = |m| Restriction::AddrType(m).to_string();
+        assert_eq!(
+            addrtype(AddrTypeMatch {
+                dst_type: Some((Negatable::Is, vec![AddrType::Local])),
+                ..Default::default()
+            }),
+            "-m addrtype --dst-type LOCAL"
+        );
+        assert_eq!(
+            addrtype(AddrTypeMatch {
+                dst_type: Some((Negatable::Is, vec![AddrType::Local])),
+                limit_iface: Some(LimitIface::In),
+                ..Default::default()
+            }),
+            "-m addrtype --dst-type LOCAL --limit-iface-in"
+        );
+        assert_eq!(
+            addrtype(AddrTypeMatch {
+                src_type: Some((Negatable::IsNot, vec![AddrType::Unicast, AddrType::Anycast])),
+                dst_type: None,
+                limit_iface: Some(LimitIface::Out),
+            }),
+            "-m addrtype '!' --src-type UNICAST,ANYCAST --limit-iface-out"
+        );
+        let rule = Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![Restriction::AddrType(AddrTypeMatch {
+                limit_iface: Some(LimitIface::In),
+                ..Default::default()
+            })],
+            rule_action: RuleAction::Drop,
+        };
+        assert_eq!(
+            rule.validate().unwrap_err().to_string(),
+            "rule in chain \"INPUT\": addrtype match without source or destination type"
+        );
+    }
+
+    #[test]
+    fn t_nflog() {
+        let rule = |prefix: Option<&str>, range| Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![],
+            rule_action: RuleAction::NfLog {
+                group: 5,
+                prefix: prefix.map(String::from),
+                range,
+            },
+        };
+        let args = |r: Rule<Filter>| {
+            r.validate().unwrap();
+            r.cmd_args(Action::Append.into())[4..].join(" ")
+        };
+        assert_eq!(args(rule(None, None)), "-j NFLOG --nflog-group 5");
+        assert_eq!(
+            args(rule(Some("dropped: "), None)),
+            "-j NFLOG --nflog-group 5 --nflog-prefix dropped: "
+        );
+        assert_eq!(
+            args(rule(Some("x"), Some(128))),
+            "-j NFLOG --nflog-group 5 --nflog-prefix x --nflog-range 128"
+        );
+        let long = "x".repeat(NFLOG_PREFIX_MAX_LEN + 1);
+        assert!(rule(Some(&long), None)
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .ends_with("is longer than 63 bytes"));
+    }
+
+    #[test]
+    fn t_assert_position() {
+        let our_chain = Filter::Custom("our-chain".into());
+        let jump = Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![],
+            rule_action: RuleAction::Jump(our_chain.clone()),
+        };
+        let drop = Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![Restriction::Protocol(Negatable::Is, Protocol::Udp)],
+            rule_action: RuleAction::Drop,
+        };
+        let writer = |jump_action| {
+            let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+            iptables.push(
+                Action::NewChain,
+                Rule {
+                    chain: our_chain.clone(),
+                    restrictions: vec![],
+                    rule_action: RuleAction::None,
+                },
+                RecreatingMode::Owned,
+            );
+            iptables.push(Action::Append, drop.clone(), RecreatingMode::Owned);
+            iptables.push(jump_action, jump.clone(), RecreatingMode::Owned);
+            iptables
+        };
+
+        let appended = writer(Action::Append);
+        assert_eq!(
+            appended.assert_position(&jump, 1).unwrap_err().to_string(),
+            "rule `-t filter -C INPUT -j our-chain` in chain \"INPUT\" of table filter is at \
+             position 2, expected 1"
+        );
+        appended.assert_position(&drop, 1).unwrap();
+
+        let inserted = writer(Action::Insert(1));
+        inserted.assert_position(&jump, 1).unwrap();
+        inserted.assert_position(&drop, 2).unwrap();
+
+        let other = Rule {
+            chain: Filter::OUTPUT,
+            ..drop
+        };
+        assert_eq!(
+            inserted.assert_position(&other, 1).unwrap_err().to_string(),
+            "rule `-t filter -C OUTPUT -p udp -j DROP` is not in chain \"OUTPUT\" of table filter"
+        );
+    }
+
+    #[test]
+    fn t_push_for_host() {
+        let resolve = |host: &str| -> Result<Vec<IpNet>> {
+            assert_eq!(host, "server.example.com");
+            Ok(vec![
+                "192.0.2.10/32".parse().unwrap(),
+                "2001:db8::10/128".parse().unwrap(),
+                "192.0.2.11/32".parse().unwrap(),
+            ])
+        };
+        let push = |iptables: &mut IptablesWriter, side| {
+            iptables.push_for_host(
+                Filter::OUTPUT,
+                "server.example.com",
+                side,
+                resolve,
+                vec![Restriction::Protocol(Negatable::Is, Protocol::Tcp)],
+                RuleAction::Accept,
+            )
+        };
+        let plan = |iptables: &IptablesWriter| -> Vec<String> {
+            iptables
+                .build_plan(Effect::Creation)
+                .iter()
+                .map(|p| p.cmd_args()[4..].join(" "))
+                .collect()
+        };
+
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        push(&mut iptables, AddressSide::Destination).unwrap();
+        assert_eq!(
+            plan(&iptables),
+            [
+                "-d 192.0.2.10/32 -p tcp -j ACCEPT",
+                "-d 192.0.2.11/32 -p tcp -j ACCEPT"
+            ]
+        );
+
+        let mut ip6tables = IptablesWriter::new(vec!["ip6tables".into()]);
+        push(&mut ip6tables, AddressSide::Source).unwrap();
+        assert_eq!(plan(&ip6tables), ["-s 2001:db8::10/128 -p tcp -j ACCEPT"]);
+        assert_eq!(
+            ip6tables.actions[0].1.restrictions()[0],
+            Restriction::SourceAddress(Negatable::Is, "2001:db8::10/128".parse().unwrap())
+        );
+        assert!(ip6tables.validate().is_ok());
+
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        assert_eq!(
+            iptables
+                .push_for_host(
+                    Filter::OUTPUT,
+                    "v6only",
+                    AddressSide::Source,
+                    |_| Ok(vec!["2001:db8::1/128".parse().unwrap()]),
+                    vec![],
+                    RuleAction::Accept,
+                )
+                .unwrap_err()
+                .to_string(),
+            "host \"v6only\" has no IPv4 address"
+        );
+        assert_eq!(
+            resolve_host("127.0.0.1").unwrap(),
+            ["127.0.0.1/32".parse::<IpNet>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn t_negatable_tokens() {
+        assert_eq!(Negatable::Is.as_iptables_token(), None);
+        assert_eq!(Negatable::IsNot.as_iptables_token(), Some("!"));
+        assert_eq!(Negatable::Is.as_nft_token(), None);
+        assert_eq!(Negatable::IsNot.as_nft_token(), Some("!="));
+    }
+
+    #[test]
+    fn t_ct_dir() {
+        assert_eq!(
+            Restriction::ct_dir_reply().to_string(),
+            "-m conntrack --ctdir REPLY"
+        );
+        assert_eq!(
+            Restriction::ct_dir_original().to_string(),
+            "-m conntrack --ctdir ORIGINAL"
+        );
+    }
+
+    #[test]
+    fn t_negated() {
+        assert_eq!(
+            Restriction::SourcePort(Negatable::Is, 22).negated(),
+            Some(Restriction::SourcePort(Negatable::IsNot, 22))
+        );
+        assert_eq!(
+            Restriction::Ports {
+                sport: Some(1),
+                dport: None,
+                neg: Negatable::IsNot
+            }
+            .negated(),
+            Some(Restriction::Ports {
+                sport: Some(1),
+                dport: None,
+                neg: Negatable::Is
+            })
+        );
+        assert_eq!(Restriction::Limit("5/minute".into()).negated(), None);
+        assert_eq!(Restriction::Comment("x".into()).negated(), None);
     }
 }
@@ -0,0 +1,938 @@
+//! Reading (and quoting for) the `iptables-save` file format.
+
+use anyhow::{anyhow, bail, Context, Result};
+use ipnet::IpNet;
+use std::io::Write;
+use std::net::IpAddr;
+
+use crate::executor::Executor;
+use crate::iptables::{
+    Action, AnyAction, Effect, Filter, InterfaceSpec, IptablesWriter, Mangle, Nat, Negatable,
+    Protocol, Raw, RecreatingMode, RejectWith, Restriction, Rule, RuleAction, RuleTrait, Security,
+    Table, TablechainTrait,
+};
+
+fn is_save_safe(c: char) -> bool {
+    !(c.is_whitespace() || c == '"' || c == '\'' || c == '\\')
+}
+
+/// Quote a single argument the way `iptables-save` does: with
+/// double quotes, only if necessary.
+pub fn save_quote(s: &str) -> String {
+    if !s.is_empty() && s.chars().all(is_save_safe) {
+        return s.into();
+    }
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+
+pub fn save_quote_many<S: AsRef<str>>(ss: &[S]) -> String {
+    ss.iter()
+        .map(|s| save_quote(s.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Split a line into arguments, honouring single and double quotes
+/// and backslash escapes (the inverse of `save_quote_many`).
+pub fn save_split(line: &str) -> Result<Vec<String>> {
+    let mut out = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                let s = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        None => bail!("unterminated quote in {line:?}"),
+                        Some('\\') if c == '"' => match chars.next() {
+                            None => bail!("backslash at end of {line:?}"),
+                            Some(e) => s.push(e),
+                        },
+                        Some(d) if d == c => break,
+                        Some(d) => s.push(d),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                None => bail!("backslash at end of {line:?}"),
+                Some(e) => current.get_or_insert_with(String::new).push(e),
+            },
+            c if c.is_whitespace() => {
+                if let Some(s) = current.take() {
+                    out.push(s);
+                }
+            }
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(s) = current {
+        out.push(s);
+    }
+    Ok(out)
+}
+
+/// A rule line from an `iptables-save` file, split into arguments
+/// but otherwise uninterpreted.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParsedRule {
+    pub table: Table,
+    pub chain: String,
+    /// `Append` for `-A` lines, `Insert` for `-I` lines.
+    pub action: Action,
+    /// The arguments after the chain name (and insert index).
+    pub args: Vec<String>,
+    /// The `[packets:bytes]` counters preceding the rule, present
+    /// when saved with `iptables-save -c`.
+    pub counters: Option<(u64, u64)>,
+}
+
+/// The contents of an `iptables-save` file.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ParsedSave {
+    /// The chains declared via `:name POLICY [..]` lines, per table.
+    pub chains: Vec<(Table, String)>,
+    pub rules: Vec<ParsedRule>,
+}
+
+pub fn table_from_name(name: &str) -> Option<Table> {
+    match name {
+        "filter" => Some(Table::Filter),
+        "nat" => Some(Table::Nat),
+        "mangle" => Some(Table::Mangle),
+        "raw" => Some(Table::Raw),
+        "security" => Some(Table::Security),
+        _ => None,
+    }
+}
+
+/// Parse "packets:bytes".
+fn parse_counters(s: &str) -> Option<(u64, u64)> {
+    let (packets, bytes) = s.split_once(':')?;
+    Some((packets.parse().ok()?, bytes.parse().ok()?))
+}
+
+pub fn parse_save(input: &str) -> Result<ParsedSave> {
+    let mut parsed = ParsedSave::default();
+    let mut table = None;
+    for (lineno, line) in input.lines().enumerate() {
+        let lineno = lineno + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('*') {
+            table = Some(
+                table_from_name(name)
+                    .ok_or_else(|| anyhow!("line {lineno}: unknown table {name:?}"))?,
+            );
+            continue;
+        }
+        if line == "COMMIT" {
+            table = None;
+            continue;
+        }
+        let current_table =
+            table.ok_or_else(|| anyhow!("line {lineno}: rule outside of a table: {line:?}"))?;
+        if let Some(decl) = line.strip_prefix(':') {
+            let name = decl
+                .split_whitespace()
+                .next()
+                .ok_or_else(|| anyhow!("line {lineno}: missing chain name"))?;
+            parsed.chains.push((current_table, name.into()));
+            continue;
+        }
+        let (counters, line) =
+            match line.strip_prefix('[') {
+                Some(rest) => {
+                    let (counters, rest) = rest
+                        .split_once(']')
+                        .ok_or_else(|| anyhow!("line {lineno}: unterminated counters"))?;
+                    (
+                        Some(parse_counters(counters).ok_or_else(|| {
+                            anyhow!("line {lineno}: invalid counters {counters:?}")
+                        })?),
+                        rest.trim_start(),
+                    )
+                }
+                None => (None, line),
+            };
+        let args = save_split(line).with_context(|| anyhow!("line {lineno}"))?;
+        let mut args = args.into_iter().peekable();
+        let action = args.next();
+        let chain = args
+            .next()
+            .ok_or_else(|| anyhow!("line {lineno}: missing chain name in {line:?}"))?;
+        let action = match action.as_deref() {
+            Some("-A") => Action::Append,
+            Some("-I") => match args.peek().and_then(|n| n.parse().ok()) {
+                Some(n) => {
+                    args.next();
+                    Action::Insert(n)
+                }
+                None => Action::Insert(1),
+            },
+            _ => bail!("line {lineno}: unsupported line {line:?}"),
+        };
+        parsed.rules.push(ParsedRule {
+            table: current_table,
+            chain,
+            action,
+            args: args.collect(),
+            counters,
+        });
+    }
+    if table.is_some() {
+        bail!("missing COMMIT at end of input");
+    }
+    Ok(parsed)
+}
+
+fn parse_ipnet(s: &str) -> Option<IpNet> {
+    s.parse()
+        .ok()
+        .or_else(|| s.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+fn protocol_from_name(name: &str) -> Option<Protocol> {
+    Some(match name {
+        "all" => Protocol::All,
+        "tcp" => Protocol::Tcp,
+        "udp" => Protocol::Udp,
+        "udplite" => Protocol::Udplite,
+        "icmp" => Protocol::Icmp,
+        "icmpv6" | "ipv6-icmp" => Protocol::Icmpv6,
+        "esp" => Protocol::Esp,
+        "ah" => Protocol::Ah,
+        "sctp" => Protocol::Sctp,
+        "dccp" => Protocol::Dccp,
+        "mh" => Protocol::Mh,
+        _ => return None,
+    })
+}
+
+/// Convert the arguments of a parsed rule into a `Rule`. `to_chain`
+/// maps chain names in the rule's table to the chain type, `chains`
+/// are the chains declared in that table (to tell jumps to chains
+/// apart from extension targets).
+fn rule_from_args<C: TablechainTrait>(
+    chain: C,
+    args: &[String],
+    to_chain: fn(&str) -> C,
+    chains: &[&str],
+) -> Result<Rule<C>> {
+    let mut restrictions = Vec::new();
+    let mut rule_action = RuleAction::None;
+    // Arguments that we don't map, collected until the next
+    // recognized option.
+    let mut custom: Vec<String> = Vec::new();
+    let mut protocol: Option<String> = None;
+    let mut i = 0;
+    let mut negation = Negatable::Is;
+    let value = |i: usize| -> Result<&String> {
+        args.get(i + 1)
+            .ok_or_else(|| anyhow!("missing value after {:?}", args[i]))
+    };
+    while i < args.len() {
+        let arg = args[i].as_str();
+        if arg == "!" {
+            if negation == Negatable::IsNot {
+                bail!("double negation in {args:?}");
+            }
+            negation = Negatable::IsNot;
+            i += 1;
+            continue;
+        }
+        // The value, with the old-style `--opt ! value` negation
+        // taken into account
+        let mut neg_value = |i: &mut usize| -> Result<(Negatable, String)> {
+            let mut v = value(*i)?;
+            if v == "!" {
+                negation = Negatable::IsNot;
+                *i += 1;
+                v = value(*i)?;
+            }
+            *i += 2;
+            Ok((negation, v.clone()))
+        };
+        let mut start = i;
+        let recognized = match arg {
+            "-i" | "--in-interface" => {
+                let (neg, v) = neg_value(&mut i)?;
+                let spec = match v.strip_suffix('+') {
+                    Some(prefix) => InterfaceSpec::Prefix(prefix.into()),
+                    None => InterfaceSpec::Exact(v),
+                };
+                Some(Restriction::Interface(neg, spec))
+            }
+            "-p" | "--protocol" => {
+                let (neg, v) = neg_value(&mut i)?;
+                protocol = Some(v.clone());
+                protocol_from_name(&v).map(|p| Restriction::Protocol(neg, p))
+            }
+            "-s" | "--source" => {
+                let (neg, v) = neg_value(&mut i)?;
+                parse_ipnet(&v).map(|net| Restriction::SourceAddress(neg, net))
+            }
+            "-d" | "--destination" => {
+                let (neg, v) = neg_value(&mut i)?;
+                parse_ipnet(&v).map(|net| Restriction::DestinationAddress(neg, net))
+            }
+            "--sport" | "--source-port" if custom.is_empty() => {
+                let (neg, v) = neg_value(&mut i)?;
+                v.parse().ok().map(|n| Restriction::SourcePort(neg, n))
+            }
+            "--dport" | "--destination-port" if custom.is_empty() => {
+                let (neg, v) = neg_value(&mut i)?;
+                v.parse().ok().map(|n| Restriction::DestinationPort(neg, n))
+            }
+            "-m" | "--match" => {
+                let v = value(i)?;
+                if Some(v) == protocol.as_ref() {
+                    // The implicit module that iptables-save shows
+                    // for the protocol; our port options do without.
+                    i += 2;
+                    continue;
+                }
+                start = args.len();
+                None
+            }
+            "-j" | "-g" | "--jump" | "--goto" => {
+                let target = value(i)?;
+                let is_goto = arg == "-g" || arg == "--goto";
+                let options = &args[i + 2..];
+                let action = if target == "LOG"
+                    && options.len() == 2
+                    && options[0] == "--log-prefix"
+                {
+                    Some(RuleAction::Log(Some(options[1].clone())))
+                } else if target == "REJECT" && options.len() == 2 && options[0] == "--reject-with"
+                {
+                    RejectWith::from_arg(&options[1]).map(RuleAction::RejectWith)
+                } else if !options.is_empty() {
+                    // Other target with options
+                    None
+                } else if is_goto {
+                    Some(RuleAction::Goto(to_chain(target)))
+                } else {
+                    match target.as_str() {
+                        "ACCEPT" => Some(RuleAction::Accept),
+                        "LOG" => Some(RuleAction::Log(None)),
+                        "RETURN" => Some(RuleAction::Return),
+                        "DROP" => Some(RuleAction::Drop),
+                        "REJECT" => Some(RuleAction::Reject),
+                        t if chains.contains(&t) => Some(RuleAction::Jump(to_chain(t))),
+                        _ => None,
+                    }
+                };
+                if let Some(action) = action {
+                    rule_action = action;
+                    i = args.len();
+                } else {
+                    // Keep the target (and its options) verbatim.
+                    if negation == Negatable::IsNot {
+                        custom.push("!".into());
+                    }
+                    custom.extend(args[i..].iter().cloned());
+                    i = args.len();
+                }
+                negation = Negatable::Is;
+                continue;
+            }
+            _ => {
+                // Unrecognized, `i` has not been moved yet
+                start = args.len();
+                None
+            }
+        };
+        match recognized {
+            Some(restriction) => {
+                if !custom.is_empty() {
+                    restrictions.push(Restriction::Custom(std::mem::take(&mut custom)));
+                }
+                restrictions.push(restriction);
+            }
+            None => {
+                if start == args.len() {
+                    // Unrecognized option: take just this argument.
+                    if negation == Negatable::IsNot {
+                        custom.push("!".into());
+                    }
+                    custom.push(args[i].clone());
+                    i += 1;
+                } else {
+                    // Recognized option with unsupported value: keep
+                    // the original arguments.
+                    if negation == Negatable::IsNot && start > 0 && args[start - 1] == "!" {
+                        custom.push("!".into());
+                    }
+                    custom.extend(args[start..i].iter().cloned());
+                }
+            }
+        }
+        negation = Negatable::Is;
+    }
+    if !custom.is_empty() {
+        restrictions.push(Restriction::Custom(custom));
+    }
+    Ok(Rule {
+        chain,
+        restrictions,
+        rule_action,
+    })
+}
+
+fn filter_chain(name: &str) -> Filter {
+    match name {
+        "INPUT" => Filter::INPUT,
+        "FORWARD" => Filter::FORWARD,
+        "OUTPUT" => Filter::OUTPUT,
+        _ => Filter::Custom(name.into()),
+    }
+}
+
+fn nat_chain(name: &str) -> Nat {
+    match name {
+        "PREROUTING" => Nat::PREROUTING,
+        "INPUT" => Nat::INPUT,
+        "OUTPUT" => Nat::OUTPUT,
+        "POSTROUTING" => Nat::POSTROUTING,
+        _ => Nat::Custom(name.into()),
+    }
+}
+
+fn mangle_chain(name: &str) -> Mangle {
+    match name {
+        "PREROUTING" => Mangle::PREROUTING,
+        "INPUT" => Mangle::INPUT,
+        "OUTPUT" => Mangle::OUTPUT,
+        "FORWARD" => Mangle::FORWARD,
+        "POSTROUTING" => Mangle::POSTROUTING,
+        _ => Mangle::Custom(name.into()),
+    }
+}
+
+fn raw_chain(name: &str) -> Raw {
+    match name {
+        "PREROUTING" => Raw::PREROUTING,
+        "OUTPUT" => Raw::OUTPUT,
+        _ => Raw::Custom(name.into()),
+    }
+}
+
+fn security_chain(name: &str) -> Security {
+    match name {
+        "INPUT" => Security::INPUT,
+        "OUTPUT" => Security::OUTPUT,
+        "FORWARD" => Security::FORWARD,
+        _ => Security::Custom(name.into()),
+    }
+}
+
+/// Parse an `iptables-save` file into `Rule`s (in file order), for
+/// re-emitting them through this crate. Options that don't map to a
+/// `Restriction` or `RuleAction` are kept verbatim as
+/// `Restriction::Custom`. Chain declarations are not returned, and
+/// the insert position of `-I` lines is dropped.
+pub fn rules_from_save(input: &str) -> Result<Vec<(Table, Box<dyn RuleTrait>)>> {
+    let parsed = parse_save(input)?;
+    let mut out: Vec<(Table, Box<dyn RuleTrait>)> = Vec::new();
+    for rule in &parsed.rules {
+        let chains: Vec<&str> = parsed
+            .chains
+            .iter()
+            .filter(|(t, _)| *t == rule.table)
+            .map(|(_, c)| c.as_str())
+            .collect();
+        let args = &rule.args;
+        let context = || anyhow!("rule {:?} in chain {:?}", args, rule.chain);
+        let boxed: Box<dyn RuleTrait> = match rule.table {
+            Table::Filter => Box::new(
+                rule_from_args(filter_chain(&rule.chain), args, filter_chain, &chains)
+                    .with_context(context)?,
+            ),
+            Table::Nat => Box::new(
+                rule_from_args(nat_chain(&rule.chain), args, nat_chain, &chains)
+                    .with_context(context)?,
+            ),
+            Table::Mangle => Box::new(
+                rule_from_args(mangle_chain(&rule.chain), args, mangle_chain, &chains)
+                    .with_context(context)?,
+            ),
+            Table::Raw => Box::new(
+                rule_from_args(raw_chain(&rule.chain), args, raw_chain, &chains)
+                    .with_context(context)?,
+            ),
+            Table::Security => Box::new(
+                rule_from_args(security_chain(&rule.chain), args, security_chain, &chains)
+                    .with_context(context)?,
+            ),
+        };
+        out.push((rule.table, boxed));
+    }
+    Ok(out)
+}
+
+/// The result of `IptablesWriter::verify_applied`, with the rules
+/// (and chains) as `-t <table> -A|-N <chain> ...` lines.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Rules and chains that should exist but don't.
+    pub missing: Vec<String>,
+    /// Rules in the chains used by the writer that shouldn't exist.
+    pub unexpected: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+impl std::fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.missing {
+            writeln!(f, "missing: {line}")?;
+        }
+        for line in &self.unexpected {
+            writeln!(f, "unexpected: {line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The `iptables-save` command corresponding to `iptables_cmd`
+/// (`-save` appended to the `iptables`/`ip6tables` argument).
+fn save_cmd(iptables_cmd: &[String]) -> Vec<String> {
+    let mut cmd = iptables_cmd.to_vec();
+    if let Some(arg) = cmd.iter_mut().rev().find(|arg| {
+        let name = arg.rsplit('/').next().unwrap_or_default();
+        name == "iptables" || name == "ip6tables"
+    }) {
+        arg.push_str("-save");
+    }
+    cmd
+}
+
+impl IptablesWriter {
+    /// `write_iptables_save` with `[packets:bytes]` counters on the
+    /// rule lines, for `iptables-restore -c`, so that an atomic swap
+    /// keeps the statistics. The counters are taken from the rules in
+    /// `current` (the output of `iptables-save -c`), which are
+    /// matched after normalization via `rules_from_save`, each at most
+    /// once. Rules without known counters get `[0:0]`.
+    pub fn write_iptables_save_with_counters<W: Write>(
+        &self,
+        w: &mut W,
+        current: &str,
+    ) -> Result<()> {
+        let line = |rule: &dyn RuleTrait| self.save_line(rule);
+        let parsed = parse_save(current)?;
+        let mut known: Vec<(String, (u64, u64))> = rules_from_save(current)?
+            .iter()
+            .zip(&parsed.rules)
+            .filter_map(|((_, rule), parsed)| Some((line(&**rule), parsed.counters?)))
+            .collect();
+        self.write_iptables_save_counted(w, |rule| {
+            let line = line(rule);
+            Some(match known.iter().position(|(l, _)| *l == line) {
+                Some(i) => known.remove(i).1,
+                None => (0, 0),
+            })
+        })?;
+        Ok(())
+    }
+
+    pub fn to_iptables_save_with_counters(&self, current: &str) -> Result<String> {
+        let mut out = Vec::new();
+        self.write_iptables_save_with_counters(&mut out, current)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    /// Read the current state via `iptables-save` (run through
+    /// `executor`) and compare it with the state that `execute` with
+    /// `want` should have produced. Only the chains used by the
+    /// pushed rules are looked at; rules are compared after
+    /// normalization via `rules_from_save`, regardless of position.
+    pub fn verify_applied(
+        &self,
+        executor: &mut dyn Executor<AnyAction>,
+        want: Effect,
+    ) -> Result<VerifyReport> {
+        let dump = executor.read_output(AnyAction::Check, &save_cmd(self.iptables_cmd()))?;
+        let parsed = parse_save(&dump)?;
+        let line = |rule: &dyn RuleTrait| self.save_line(rule);
+
+        let mut report = VerifyReport::default();
+        let mut scope = Vec::new();
+        let mut expected = Vec::new();
+        for planned in self.build_plan(Effect::Creation) {
+            if planned.rule.is_command() {
+                continue;
+            }
+            let (table, chain) = planned.rule.table_and_chain_names();
+            let stays = match want {
+                Effect::Creation | Effect::Recreation | Effect::Check => true,
+                Effect::Deletion => {
+                    planned.recreating_mode == RecreatingMode::TryCreationNoDeletion
+                }
+            };
+            if planned.action.is_chain_creation() {
+                let exists = parsed
+                    .chains
+                    .iter()
+                    .any(|(t, c)| String::from(t) == table && *c == chain);
+                if stays && !exists {
+                    report
+                        .missing
+                        .push(format!("-t {table} -N {}", save_quote(&chain)));
+                }
+            } else if stays {
+                expected.push(line(planned.rule));
+            }
+            if !scope.contains(&(table.clone(), chain.clone())) {
+                scope.push((table, chain));
+            }
+        }
+
+        let mut actual = Vec::new();
+        for (_, rule) in rules_from_save(&dump)? {
+            if scope.contains(&rule.table_and_chain_names()) {
+                actual.push(line(&*rule));
+            }
+        }
+        for e in expected {
+            match actual.iter().position(|a| *a == e) {
+                Some(i) => {
+                    actual.remove(i);
+                }
+                None => report.missing.push(e),
+            }
+        }
+        report.unexpected = actual;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_iptables::FakeIptables;
+    use crate::iptables::{AnyAction, IptablesWriter, RecreatingMode};
+
+    fn verify_writer() -> IptablesWriter {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        let our_chain = Filter::Custom("our-chain".into());
+        iptables.push(
+            Action::NewChain,
+            Rule {
+                chain: our_chain.clone(),
+                restrictions: vec![],
+                rule_action: RuleAction::None,
+            },
+            RecreatingMode::Owned,
+        );
+        iptables.push(
+            Action::Insert(1),
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![],
+                rule_action: RuleAction::Jump(our_chain.clone()),
+            },
+            RecreatingMode::Owned,
+        );
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: our_chain,
+                restrictions: vec![
+                    Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                    Restriction::DestinationPort(Negatable::Is, 22),
+                ],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::Owned,
+        );
+        iptables
+    }
+
+    #[test]
+    fn t_verify_applied() {
+        let iptables = verify_writer();
+        let mut fake = FakeIptables::new();
+        let report = iptables
+            .verify_applied(&mut fake, Effect::Creation)
+            .unwrap();
+        assert_eq!(
+            report.missing,
+            [
+                "-t filter -N our-chain",
+                "-t filter -A INPUT -j our-chain",
+                "-t filter -A our-chain -p tcp --dport 22 -j ACCEPT"
+            ]
+        );
+
+        iptables
+            .execute(Effect::Recreation, None::<Vec<u8>>, &mut fake)
+            .unwrap();
+        let report = iptables
+            .verify_applied(&mut fake, Effect::Recreation)
+            .unwrap();
+        assert!(report.is_ok(), "{report}");
+
+        let cmd: Vec<String> = ["iptables", "-t", "filter", "-A", "our-chain", "-j", "DROP"]
+            .map(String::from)
+            .to_vec();
+        assert!(fake.execute(AnyAction::Check, &cmd).is_success());
+        let report = iptables
+            .verify_applied(&mut fake, Effect::Recreation)
+            .unwrap();
+        assert!(report.missing.is_empty());
+        assert_eq!(report.unexpected, ["-t filter -A our-chain -j DROP"]);
+        assert_eq!(
+            report.to_string(),
+            "unexpected: -t filter -A our-chain -j DROP\n"
+        );
+
+        assert_eq!(
+            save_cmd(&["sudo".into(), "/sbin/ip6tables".into()]),
+            ["sudo", "/sbin/ip6tables-save"]
+        );
+    }
+
+    #[test]
+    fn t_save_quote() {
+        assert_eq!(save_quote("eth0"), "eth0");
+        assert_eq!(save_quote(""), "\"\"");
+        assert_eq!(save_quote("a b"), "\"a b\"");
+        assert_eq!(save_quote("a\"b"), "\"a\\\"b\"");
+        let args = ["-m", "comment", "--comment", "it's \"x\""];
+        assert_eq!(save_split(&save_quote_many(&args)).unwrap(), args);
+        assert_eq!(save_split("a 'b c'  d").unwrap(), ["a", "b c", "d"]);
+    }
+
+    #[test]
+    fn t_parse_counters() {
+        let input = "\
+*filter
+:INPUT ACCEPT [1200:98304]
+[17:1020] -A INPUT -p tcp -m tcp --dport 22 -j ACCEPT
+-A INPUT -j DROP
+COMMIT
+";
+        let parsed = parse_save(input).unwrap();
+        assert_eq!(parsed.rules[0].counters, Some((17, 1020)));
+        assert_eq!(parsed.rules[0].args[..2], ["-p", "tcp"]);
+        assert_eq!(parsed.rules[1].counters, None);
+        assert!(parse_save("*filter\n[1:x] -A INPUT -j DROP\nCOMMIT\n").is_err());
+    }
+
+    #[test]
+    fn t_rules_from_save() {
+        let input = "\
+# Generated by iptables-save
+*filter
+:INPUT ACCEPT [0:0]
+:FORWARD ACCEPT [0:0]
+:our-chain - [0:0]
+-A INPUT -j our-chain
+-A our-chain ! -s 10.0.0.0/8 -i eth+ -p tcp -m tcp --dport 22 -j RETURN
+-A our-chain -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT
+-A our-chain -p tcp -m multiport --dports 80,443 -j REJECT --reject-with tcp-reset
+COMMIT
+";
+        let rules = rules_from_save(input).unwrap();
+        let args: Vec<String> = rules
+            .iter()
+            .map(|(table, rule)| {
+                assert_eq!(*table, Table::Filter);
+                rule.cmd_args(AnyAction::Check).join(" ")
+            })
+            .collect();
+        assert_eq!(
+            args,
+            [
+                "-t filter -C INPUT -j our-chain",
+                "-t filter -C our-chain ! -s 10.0.0.0/8 -i eth+ -p tcp --dport 22 -j RETURN",
+                "-t filter -C our-chain -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT",
+                "-t filter -C our-chain -p tcp -m multiport --dports 80,443 \
+                 -j REJECT --reject-with tcp-reset",
+            ]
+        );
+        assert_eq!(
+            rules[1].1.restrictions()[0],
+            Restriction::SourceAddress(Negatable::IsNot, "10.0.0.0/8".parse().unwrap())
+        );
+        assert_eq!(
+            rules[2].1.restrictions(),
+            [Restriction::Custom(
+                ["-m", "conntrack", "--ctstate", "RELATED,ESTABLISHED"]
+                    .map(String::from)
+                    .into()
+            )]
+        );
+        assert_eq!(rules[2].1.rule_action_string(), "-j ACCEPT");
+
+        let rules =
+            rules_from_save("*filter\n-A INPUT -s fd00::/64 -d fd00::1 -j ACCEPT\nCOMMIT\n")
+                .unwrap();
+        assert_eq!(
+            rules[0].1.restrictions(),
+            [
+                Restriction::SourceAddress(Negatable::Is, "fd00::/64".parse().unwrap()),
+                Restriction::DestinationAddress(Negatable::Is, "fd00::1/128".parse().unwrap())
+            ]
+        );
+
+        assert!(rules_from_save("-A INPUT -j DROP\n").is_err());
+        assert!(rules_from_save("*filter\n-A INPUT -j DROP\n").is_err());
+    }
+
+    #[test]
+    fn t_round_trip() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        let our_chain = Filter::Custom("our-chain".into());
+        iptables.push(
+            Action::NewChain,
+            Rule {
+                chain: our_chain.clone(),
+                restrictions: vec![],
+                rule_action: RuleAction::None,
+            },
+            RecreatingMode::Owned,
+        );
+        let rules = vec![
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![],
+                rule_action: RuleAction::Jump(our_chain.clone()),
+            },
+            Rule {
+                chain: our_chain.clone(),
+                restrictions: vec![
+                    Restriction::Interface(Negatable::Is, InterfaceSpec::Exact("eth0".into())),
+                    Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                    Restriction::DestinationPort(Negatable::IsNot, 22),
+                ],
+                rule_action: RuleAction::Return,
+            },
+            Rule {
+                chain: our_chain.clone(),
+                restrictions: vec![
+                    Restriction::SourceAddress(Negatable::Is, "192.168.1.0/24".parse().unwrap()),
+                    Restriction::Custom(vec![
+                        "-m".into(),
+                        "comment".into(),
+                        "--comment".into(),
+                        "a b".into(),
+                    ]),
+                ],
+                rule_action: RuleAction::Drop,
+            },
+        ];
+        for rule in &rules {
+            iptables.push(Action::Append, rule.clone(), RecreatingMode::Owned);
+        }
+
+        let save = iptables.to_iptables_save();
+        assert_eq!(
+            save,
+            "*filter\n\
+             :our-chain - [0:0]\n\
+             -A INPUT -j our-chain\n\
+             -A our-chain -i eth0 -p tcp ! --dport 22 -j RETURN\n\
+             -A our-chain -s 192.168.1.0/24 -m comment --comment \"a b\" -j DROP\n\
+             COMMIT\n"
+        );
+        let parsed = rules_from_save(&save).unwrap();
+        assert_eq!(parsed.len(), rules.len());
+        for (rule, (table, parsed)) in rules.iter().zip(&parsed) {
+            assert_eq!(*table, Table::Filter);
+            assert_eq!(rule.restrictions, parsed.restrictions());
+            assert_eq!(
+                rule.cmd_args(AnyAction::Check),
+                parsed.cmd_args(AnyAction::Check)
+            );
+        }
+    }
+
+    #[test]
+    fn t_iptables_save_with_counters() {
+        let iptables = verify_writer();
+        // (iptables-save shows the jump as appended, and adds `-m tcp`)
+        let current = "\
+*filter
+:INPUT ACCEPT [0:0]
+:our-chain - [0:0]
+[3:180] -A INPUT -j our-chain
+[17:1020] -A our-chain -p tcp -m tcp --dport 22 -j ACCEPT
+COMMIT
+";
+        assert_eq!(
+            iptables.to_iptables_save_with_counters(current).unwrap(),
+            "*filter\n\
+             :our-chain - [0:0]\n\
+             [3:180] -I INPUT 1 -j our-chain\n\
+             [17:1020] -A our-chain -p tcp --dport 22 -j ACCEPT\n\
+             COMMIT\n"
+        );
+        assert_eq!(
+            iptables
+                .to_iptables_save_with_counters("*filter\n:INPUT ACCEPT [0:0]\nCOMMIT\n")
+                .unwrap(),
+            "*filter\n\
+             :our-chain - [0:0]\n\
+             [0:0] -I INPUT 1 -j our-chain\n\
+             [0:0] -A our-chain -p tcp --dport 22 -j ACCEPT\n\
+             COMMIT\n"
+        );
+    }
+
+    #[test]
+    fn t_bare_host_addresses_verify_and_counters() {
+        let mut iptables =
+            IptablesWriter::new(vec!["iptables".into()]).with_bare_host_addresses(true);
+        iptables.push(
+            Action::Append,
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![Restriction::SourceAddress(
+                    Negatable::Is,
+                    "10.0.0.1/32".parse().unwrap(),
+                )],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::Owned,
+        );
+        let mut fake = FakeIptables::new();
+        iptables
+            .execute(Effect::Creation, None::<Vec<u8>>, &mut fake)
+            .unwrap();
+        let report = iptables
+            .verify_applied(&mut fake, Effect::Creation)
+            .unwrap();
+        assert!(report.is_ok(), "{report}");
+
+        // (iptables-save always shows the prefix)
+        let current = "\
+*filter
+:INPUT ACCEPT [0:0]
+[5:300] -A INPUT -s 10.0.0.1/32 -j ACCEPT
+COMMIT
+";
+        assert_eq!(
+            iptables.to_iptables_save_with_counters(current).unwrap(),
+            "*filter\n\
+             [5:300] -A INPUT -s 10.0.0.1 -j ACCEPT\n\
+             COMMIT\n"
+        );
+    }
+}
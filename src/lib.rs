@@ -1,5 +1,12 @@
+pub mod ansible;
 pub mod command_util;
+pub mod csv_util;
+pub mod dual;
+pub mod error;
 pub mod executor;
+pub mod fake_iptables;
 pub mod iptables;
+pub mod iptables_save;
 pub mod network_interfaces;
+pub mod ruleset;
 pub mod shell_quote;
@@ -1,4 +1,6 @@
-use anyhow::Result;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
 use nispor::{IfaceType, NetStateFilter, NetStateIfaceFilter};
 
 pub fn find_network_interfaces() -> Result<Vec<String>> {
@@ -19,3 +21,133 @@ pub fn find_network_interfaces() -> Result<Vec<String>> {
 
     Ok(interface_names)
 }
+
+/// The names of all network interfaces (of any type, including
+/// loopback, bridges and tunnels).
+pub fn find_all_network_interfaces() -> Result<Vec<String>> {
+    let mut filter = NetStateFilter::minimum();
+    filter.iface = Some(NetStateIfaceFilter::minimum());
+    let result = nispor::NetState::retrieve_with_filter(&filter)?;
+    Ok(result.ifaces.into_keys().collect())
+}
+
+/// The environment variable holding a comma-separated list of
+/// interfaces, for when auto-detection doesn't work (e.g. in
+/// containers).
+pub const INTERFACES_ENV_VAR: &str = "FIREWALL_INTERFACES";
+
+/// Split a comma-separated list of interface names, ignoring
+/// whitespace around names and empty entries.
+pub fn parse_interface_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The interface names in `content`, one per line; `#` starts a
+/// comment, blank lines are ignored.
+pub fn parse_interface_file(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Read a file in the format of `parse_interface_file`.
+pub fn read_interface_file(path: &Path) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("reading interfaces file {path:?}"))?;
+    Ok(parse_interface_file(&content))
+}
+
+/// Choose the interfaces to use: the ones `given` explicitly (e.g.
+/// via `--interfaces`) if any, else those from the value of
+/// `INTERFACES_ENV_VAR` if set, else the result of `detect`. Fails if
+/// that leaves no interfaces, as rules for no interfaces are most
+/// likely not what was intended.
+pub fn select_network_interfaces(
+    given: Vec<String>,
+    env_value: Option<String>,
+    detect: impl FnOnce() -> Result<Vec<String>>,
+) -> Result<Vec<String>> {
+    if !given.is_empty() {
+        return Ok(given);
+    }
+    if let Some(value) = env_value {
+        let interfaces = parse_interface_list(&value);
+        if interfaces.is_empty() {
+            bail!("{INTERFACES_ENV_VAR} is set but lists no interfaces");
+        }
+        return Ok(interfaces);
+    }
+    let interfaces = detect().with_context(|| {
+        format!("detecting network interfaces (set {INTERFACES_ENV_VAR} to specify them)")
+    })?;
+    if interfaces.is_empty() {
+        bail!("no network interfaces detected (set {INTERFACES_ENV_VAR} to specify them)");
+    }
+    Ok(interfaces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_parse_interface_list() {
+        assert_eq!(parse_interface_list("eth0,eth1"), ["eth0", "eth1"]);
+        assert_eq!(parse_interface_list(" eth0 , ,eth1,"), ["eth0", "eth1"]);
+        assert!(parse_interface_list("").is_empty());
+    }
+
+    #[test]
+    fn t_read_interface_file() {
+        let path = std::env::temp_dir().join(format!("firewall-interfaces-{}", std::process::id()));
+        std::fs::write(&path, "# uplinks\neth0\n\n  eth1  # backup\n#eth2\n").unwrap();
+        let result = read_interface_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap(), ["eth0", "eth1"]);
+        let err = read_interface_file(&path).unwrap_err();
+        assert!(err.to_string().starts_with("reading interfaces file"));
+    }
+
+    #[test]
+    fn t_select_network_interfaces() {
+        let detected = || Ok(vec!["detected0".to_string()]);
+        let failing = || -> Result<Vec<String>> { bail!("no netlink") };
+        let env = || Some("env0,env1".to_string());
+
+        assert_eq!(
+            select_network_interfaces(vec!["flag0".into()], env(), failing).unwrap(),
+            ["flag0"]
+        );
+        assert_eq!(
+            select_network_interfaces(vec![], env(), failing).unwrap(),
+            ["env0", "env1"]
+        );
+        assert_eq!(
+            select_network_interfaces(vec![], None, detected).unwrap(),
+            ["detected0"]
+        );
+        let err = select_network_interfaces(vec![], None, failing).unwrap_err();
+        assert!(err.to_string().contains(INTERFACES_ENV_VAR));
+
+        let none = || Ok(vec![]);
+        assert_eq!(
+            select_network_interfaces(vec![], None, none)
+                .unwrap_err()
+                .to_string(),
+            format!("no network interfaces detected (set {INTERFACES_ENV_VAR} to specify them)")
+        );
+        assert_eq!(
+            select_network_interfaces(vec![], Some(" , ".into()), detected)
+                .unwrap_err()
+                .to_string(),
+            format!("{INTERFACES_ENV_VAR} is set but lists no interfaces")
+        );
+    }
+}
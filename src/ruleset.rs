@@ -0,0 +1,252 @@
+//! Reusable fragments of rules, to be spliced into an
+//! `IptablesWriter` (possibly several times, for different chains).
+
+use crate::iptables::{
+    Action, ConnState, IptablesWriter, Negatable, RecreatingMode, Restriction, Rule, RuleAction,
+    TablechainTrait,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleSet<T: TablechainTrait> {
+    pub rules: Vec<(Action, Rule<T>, RecreatingMode)>,
+}
+
+impl<T: TablechainTrait> Default for RuleSet<T> {
+    fn default() -> Self {
+        Self { rules: Vec::new() }
+    }
+}
+
+impl<T: TablechainTrait> RuleSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Same as `IptablesWriter::push`, but into the fragment.
+    pub fn push(&mut self, action: Action, rule: Rule<T>, recreating_mode: RecreatingMode) {
+        self.rules.push((action, rule, recreating_mode));
+    }
+
+    /// Append the rules of `other` after the existing ones.
+    pub fn extend(&mut self, other: RuleSet<T>) {
+        self.rules.extend(other.rules);
+    }
+
+    /// The usual first rule: accept packets of related and
+    /// established connections. Pushed as `TryCreationNoDeletion`,
+    /// since other tools may rely on it too.
+    pub fn established_related_accept(chain: T) -> Self {
+        let mut ruleset = RuleSet::new();
+        ruleset.push(
+            Action::Append,
+            Rule {
+                chain,
+                restrictions: vec![Restriction::ConnState(
+                    Negatable::Is,
+                    vec![ConnState::Related, ConnState::Established],
+                )],
+                rule_action: RuleAction::Accept,
+            },
+            RecreatingMode::TryCreationNoDeletion,
+        );
+        ruleset
+    }
+
+    /// The same fragment with all rules placed into `chain`; jump
+    /// and goto targets are left alone. This way a fragment can be
+    /// written once (for any chain) and used for several chains.
+    pub fn for_chain(&self, chain: T) -> Self {
+        RuleSet {
+            rules: self
+                .rules
+                .iter()
+                .map(|(action, rule, mode)| {
+                    (
+                        *action,
+                        Rule {
+                            chain: chain.clone(),
+                            ..rule.clone()
+                        },
+                        *mode,
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A chain that only lets through what matches one of `allows`, and
+/// applies `default` (typically `Drop`) to the rest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowListChain<T: TablechainTrait> {
+    pub chain: T,
+    /// The allow rules; their `chain` is replaced by the list's.
+    pub allows: Vec<Rule<T>>,
+    pub default: RuleAction<T>,
+}
+
+impl<T: TablechainTrait> AllowListChain<T> {
+    /// The `NewChain`, the allow rules and the final default rule,
+    /// all `Owned` so that they are removed again on deletion.
+    pub fn compile(self) -> RuleSet<T> {
+        let mut ruleset = RuleSet::new();
+        ruleset.push(
+            Action::NewChain,
+            Rule {
+                chain: self.chain.clone(),
+                restrictions: vec![],
+                rule_action: RuleAction::None,
+            },
+            RecreatingMode::Owned,
+        );
+        for allow in self.allows {
+            ruleset.push(
+                Action::Append,
+                Rule {
+                    chain: self.chain.clone(),
+                    ..allow
+                },
+                RecreatingMode::Owned,
+            );
+        }
+        ruleset.push(
+            Action::Append,
+            Rule {
+                chain: self.chain,
+                restrictions: vec![],
+                rule_action: self.default,
+            },
+            RecreatingMode::Owned,
+        );
+        ruleset
+    }
+}
+
+impl IptablesWriter {
+    /// Push all rules of the fragment, in order.
+    #[track_caller]
+    pub fn extend<T: TablechainTrait + 'static>(&mut self, ruleset: RuleSet<T>) {
+        for (action, rule, mode) in ruleset.rules {
+            self.push(action, rule, mode);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iptables::{Effect, Filter, Protocol};
+
+    #[test]
+    fn t_compose_rulesets() {
+        let mut management = RuleSet::new();
+        for port in [22, 9080] {
+            management.push(
+                Action::Append,
+                Rule {
+                    chain: Filter::INPUT,
+                    restrictions: vec![
+                        Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                        Restriction::DestinationPort(Negatable::Is, port),
+                    ],
+                    rule_action: RuleAction::Accept,
+                },
+                RecreatingMode::Owned,
+            );
+        }
+        let mut reject = RuleSet::new();
+        reject.push(
+            Action::Append,
+            Rule {
+                chain: Filter::INPUT,
+                restrictions: vec![],
+                rule_action: RuleAction::Reject,
+            },
+            RecreatingMode::Owned,
+        );
+
+        let mut combined = management.clone();
+        combined.extend(reject);
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.extend(management.for_chain(Filter::FORWARD));
+        iptables.extend(combined.for_chain(Filter::Custom("mgmt".into())));
+        let plan: Vec<String> = iptables
+            .build_plan(Effect::Creation)
+            .iter()
+            .map(|p| p.cmd_args().join(" "))
+            .collect();
+        assert_eq!(
+            plan,
+            [
+                "-t filter -A FORWARD -p tcp --dport 22 -j ACCEPT",
+                "-t filter -A FORWARD -p tcp --dport 9080 -j ACCEPT",
+                "-t filter -A mgmt -p tcp --dport 22 -j ACCEPT",
+                "-t filter -A mgmt -p tcp --dport 9080 -j ACCEPT",
+                "-t filter -A mgmt -j REJECT",
+            ]
+        );
+    }
+
+    #[test]
+    fn t_allow_list_chain() {
+        let allow_tcp = |port| Rule {
+            chain: Filter::INPUT,
+            restrictions: vec![
+                Restriction::Protocol(Negatable::Is, Protocol::Tcp),
+                Restriction::DestinationPort(Negatable::Is, port),
+            ],
+            rule_action: RuleAction::Accept,
+        };
+        let list = AllowListChain {
+            chain: Filter::Custom("allowed".into()),
+            allows: vec![allow_tcp(22), allow_tcp(443)],
+            default: RuleAction::Drop,
+        };
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.extend(list.compile());
+        let plan = |want| -> Vec<String> {
+            iptables
+                .build_plan(want)
+                .iter()
+                .map(|p| p.cmd_args().join(" "))
+                .collect()
+        };
+        assert_eq!(
+            plan(Effect::Creation),
+            [
+                "-t filter -N allowed",
+                "-t filter -A allowed -p tcp --dport 22 -j ACCEPT",
+                "-t filter -A allowed -p tcp --dport 443 -j ACCEPT",
+                "-t filter -A allowed -j DROP",
+            ]
+        );
+        assert_eq!(
+            plan(Effect::Deletion),
+            [
+                "-t filter -D allowed -j DROP",
+                "-t filter -D allowed -p tcp --dport 443 -j ACCEPT",
+                "-t filter -D allowed -p tcp --dport 22 -j ACCEPT",
+                "-t filter -F allowed",
+                "-t filter -X allowed",
+            ]
+        );
+    }
+
+    #[test]
+    fn t_established_related_accept() {
+        let mut iptables = IptablesWriter::new(vec!["iptables".into()]);
+        iptables.extend(RuleSet::established_related_accept(Filter::INPUT));
+        let plan = |want| -> Vec<String> {
+            iptables
+                .build_plan(want)
+                .iter()
+                .map(|p| p.cmd_args().join(" "))
+                .collect()
+        };
+        assert_eq!(
+            plan(Effect::Creation),
+            ["-t filter -A INPUT -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT"]
+        );
+        assert!(plan(Effect::Deletion).is_empty());
+    }
+}
@@ -13,6 +13,13 @@ pub fn is_quote_safe(c: char) -> bool {
     c.is_ascii_alphanumeric() || SAFE_CHARS.contains(&c)
 }
 
+/// Whether `shell_quote` would need to quote `s` (i.e. allocate a
+/// new string). Useful to warn about unusual characters in
+/// e.g. interface names.
+pub fn needs_quoting(s: &str) -> bool {
+    s.is_empty() || !s.chars().all(is_quote_safe)
+}
+
 // internal function, only ever call with strings not containing a
 // singlequote character!
 fn shell_quote_without_singlequote(s: &str) -> Cow<str> {
@@ -48,11 +55,60 @@ pub fn shell_quote(s: &str) -> Cow<str> {
     Cow::Owned(out)
 }
 
+/// How `shell_quote_with` renders strings containing control
+/// characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuoteStyle {
+    /// Single quotes, keeping control characters literally (valid
+    /// for any POSIX shell).
+    #[default]
+    SingleQuotes,
+    /// Bash ANSI-C quoting (`$'a\tb'`) for strings containing control
+    /// characters, so that they are readable; other strings as with
+    /// `SingleQuotes`.
+    AnsiC,
+}
+
+fn ansi_c_quote(s: &str) -> String {
+    let mut out = String::from("$'");
+    for c in s.chars() {
+        match c {
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\\' => out.push_str("\\\\"),
+            '\'' => out.push_str("\\'"),
+            // `\x` gives a byte, which is only the same as the
+            // character for ASCII; C1 controls need `\u` to be
+            // UTF-8 encoded
+            c if c.is_control() && c.is_ascii() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('\'');
+    out
+}
+
+pub fn shell_quote_with(s: &str, style: QuoteStyle) -> Cow<'_, str> {
+    match style {
+        QuoteStyle::AnsiC if s.chars().any(char::is_control) => Cow::Owned(ansi_c_quote(s)),
+        _ => shell_quote(s),
+    }
+}
+
 /// Note: the empty input slice will yield an empty string!
 pub fn shell_quote_many<S: AsRef<str>>(ss: &[S]) -> String {
+    shell_quote_many_with(ss, QuoteStyle::SingleQuotes)
+}
+
+pub fn shell_quote_many_with<S: AsRef<str>>(ss: &[S], style: QuoteStyle) -> String {
     let mut out = String::new();
     let mut is_first = true;
-    for s in ss.into_iter().map(|s| shell_quote((*s).as_ref())) {
+    for s in ss
+        .into_iter()
+        .map(|s| shell_quote_with((*s).as_ref(), style))
+    {
         if is_first {
             is_first = false
         } else {
@@ -80,6 +136,38 @@ mod tests {
         assert_eq!(shell_quote("ab'foo*2"), "ab\\''foo*2'");
     }
 
+    #[test]
+    fn t_shell_quote_ansi_c() {
+        assert_eq!(shell_quote("a\tb"), "'a\tb'");
+        assert_eq!(shell_quote_with("a\tb", QuoteStyle::SingleQuotes), "'a\tb'");
+        assert_eq!(shell_quote_with("a\tb", QuoteStyle::AnsiC), "$'a\\tb'");
+        assert_eq!(
+            shell_quote_with("it's\na \\ \x1b", QuoteStyle::AnsiC),
+            "$'it\\'s\\na \\\\ \\x1b'"
+        );
+        assert_eq!(shell_quote_with("a b", QuoteStyle::AnsiC), "'a b'");
+        assert_eq!(
+            shell_quote_many_with(&["echo", "x\ty"], QuoteStyle::AnsiC),
+            "echo $'x\\ty'"
+        );
+    }
+
+    #[test]
+    fn t_needs_quoting() {
+        assert!(needs_quoting(""));
+        assert!(!needs_quoting("eth0"));
+        assert!(!needs_quoting("foo_bar-1.2"));
+        assert!(needs_quoting("eth*"));
+        assert!(needs_quoting("a b"));
+        assert!(needs_quoting("it's"));
+        for s in ["", "eth0", "eth*", "it's"] {
+            assert_eq!(
+                needs_quoting(s),
+                matches!(shell_quote(s), Cow::Owned(_)) || s.is_empty()
+            );
+        }
+    }
+
     #[test]
     fn t_shell_quote_many() {
         assert_eq!(
@@ -90,4 +178,25 @@ mod tests {
         assert_eq!(shell_quote_many::<String>(&[]), "");
         assert_eq!(shell_quote_many(&[String::from("foo")]), "foo");
     }
+
+    #[test]
+    fn t_shell_quote_ansi_c_round_trip() {
+        assert_eq!(
+            shell_quote_with("a\u{85}b", QuoteStyle::AnsiC),
+            "$'a\\u0085b'"
+        );
+        assert_eq!(shell_quote_with("\x7f", QuoteStyle::AnsiC), "$'\\x7f'");
+        let s = "it's\t\x01\x1b\x7f\u{80}\u{85}\u{9f} ä\\";
+        let output = std::process::Command::new("bash")
+            .env("LC_ALL", "C.UTF-8")
+            .arg("-c")
+            .arg(format!(
+                "printf %s {}",
+                shell_quote_with(s, QuoteStyle::AnsiC)
+            ))
+            .output()
+            .expect("running bash");
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), s);
+    }
 }
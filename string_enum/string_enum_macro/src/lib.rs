@@ -1,10 +1,63 @@
 use proc_macro::TokenStream;
-use quote::quote;
+use quote::{format_ident, quote};
+
+/// The conversions (from a reference or by value) into String,
+/// changing the case of the member names via the `str` method
+/// `to_case`. A `Custom(String)` member yields its payload instead
+/// (cloned, or moved out when converting by value).
+fn string_conversions(ast: &syn::DeriveInput, to_case: &str) -> impl quote::ToTokens {
+    let name = &ast.ident;
+    let to_case = format_ident!("{to_case}");
+    let has_custom = match &ast.data {
+        syn::Data::Enum(data) => data.variants.iter().any(|variant| {
+            variant.ident == "Custom"
+                && matches!(&variant.fields,
+                            syn::Fields::Unnamed(fields) if fields.unnamed.len() == 1)
+        }),
+        _ => false,
+    };
+    if has_custom {
+        quote! {
+            impl From<&#name> for String {
+                fn from(value: &#name) -> Self {
+                    if let #name::Custom(payload) = value {
+                        return payload.clone();
+                    }
+                    let name: &'static str = value.into();
+                    name.#to_case()
+                }
+            }
+            impl From<#name> for String {
+                fn from(value: #name) -> Self {
+                    match value {
+                        #name::Custom(payload) => payload,
+                        other => String::from(&other),
+                    }
+                }
+            }
+        }
+    } else {
+        quote! {
+            impl From<&#name> for String {
+                fn from(value: &#name) -> Self {
+                    let name: &'static str = value.into();
+                    name.#to_case()
+                }
+            }
+            impl From<#name> for String {
+                fn from(value: #name) -> Self {
+                    String::from(&value)
+                }
+            }
+        }
+    }
+}
 
 /// Define an enum with the given members, deriving `Debug, PartialEq,
-/// Eq, Clone`, and with a conversion into String that
-/// lower-cases the member names. (Also available is `strum`'s
-/// `Into<&'static str>` that retains the original casing.)
+/// Eq, Clone`, and with conversions (from a reference or by value)
+/// into String that lower-case the member names (or give the
+/// payload of a `Custom(String)` member). (Also available is
+/// `strum`'s `Into<&'static str>` that retains the original casing.)
 #[proc_macro_attribute]
 pub fn lc_string_enum(atts: TokenStream, input: TokenStream) -> TokenStream {
     if !atts.is_empty() {
@@ -13,6 +66,7 @@ pub fn lc_string_enum(atts: TokenStream, input: TokenStream) -> TokenStream {
     let ast: syn::DeriveInput = syn::parse(input).expect("can't parse as Rust code");
 
     let name = &ast.ident;
+    let conversions = string_conversions(&ast, "to_ascii_lowercase");
     let gen = quote! {
         impl string_enum::ToLcString for #name {
             fn to_lc_string(&self) -> String {
@@ -20,12 +74,7 @@ pub fn lc_string_enum(atts: TokenStream, input: TokenStream) -> TokenStream {
                 name.to_ascii_lowercase()
             }
         }
-        impl From<&#name> for String {
-            fn from(value: &#name) -> Self {
-                let name: &'static str = value.into();
-                name.to_ascii_lowercase()
-            }
-        }
+        #conversions
         #[derive(Debug, PartialEq, Eq, Clone, strum_macros::IntoStaticStr)]
         #ast
     };
@@ -33,9 +82,10 @@ pub fn lc_string_enum(atts: TokenStream, input: TokenStream) -> TokenStream {
 }
 
 /// Define an enum with the given members, deriving `Debug, PartialEq,
-/// Eq, Clone`, and with a conversion into String that keeps the
-/// original casing of the member names. (Also available is `strum`'s
-/// `Into<&'static str>` that retains the original casing.)
+/// Eq, Clone`, and with conversions (from a reference or by value)
+/// into String that upper-case the member names (or give the
+/// payload of a `Custom(String)` member). (Also available is
+/// `strum`'s `Into<&'static str>` that retains the original casing.)
 #[proc_macro_attribute]
 pub fn uc_string_enum(atts: TokenStream, input: TokenStream) -> TokenStream {
     if !atts.is_empty() {
@@ -43,14 +93,9 @@ pub fn uc_string_enum(atts: TokenStream, input: TokenStream) -> TokenStream {
     }
     let ast: syn::DeriveInput = syn::parse(input).expect("can't parse as Rust code");
 
-    let name = &ast.ident;
+    let conversions = string_conversions(&ast, "to_ascii_uppercase");
     let gen = quote! {
-        impl From<&#name> for String {
-            fn from(value: &#name) -> Self {
-                let name: &'static str = value.into();
-                name.to_ascii_uppercase()
-            }
-        }
+        #conversions
         #[derive(Debug, PartialEq, Eq, Clone, strum_macros::IntoStaticStr)]
         #ast
     };
@@ -1,6 +1,7 @@
 use std::process::Command;
 
 use anyhow::{anyhow, Context, Result};
+use firewall::iptables::{Plan, PlanEntry};
 use indoc::indoc;
 
 #[test]
@@ -16,6 +17,11 @@ fn verify_verbose_output() -> Result<()> {
             "--",
             "start",
             "--dry-run",
+            // Don't depend on the interfaces of the host running the
+            // test: the detected ones end up in the expected output,
+            // and if none are found, `start` now fails.
+            "--interfaces",
+            "eth0",
         ])
         .output()
         .with_context(|| anyhow!("running {example_name:?}"))?;
@@ -26,10 +32,19 @@ fn verify_verbose_output() -> Result<()> {
 
     let stderr = String::from_utf8_lossy(&output.stderr);
     let expected_stderr = indoc! {"
+        + iptables -t filter -D our-chain -i eth0 -j REJECT
+        + iptables -t filter -D our-chain -i eth0 -p tcp -m multiport --dports 22,80,9080 -j RETURN
+        + iptables -t filter -D FORWARD -j our-chain
+        + iptables -t filter -D INPUT -j our-chain
+        + iptables -t filter -F our-chain
+        + iptables -t filter -X our-chain
+        + iptables -t filter -N our-chain
+        + iptables -t filter -I INPUT 1 -j our-chain
+        + iptables -t filter -I FORWARD 1 -j our-chain
+        + iptables -t filter -A our-chain -i eth0 -p tcp -m multiport --dports 22,80,9080 -j RETURN
+        + iptables -t filter -A our-chain -i eth0 -j REJECT
         + ip6tables -t filter -D our-chain -i eth0 -j REJECT
-        + ip6tables -t filter -D our-chain -i eth0 -p tcp --dport 9080 -j RETURN
-        + ip6tables -t filter -D our-chain -i eth0 -p tcp --dport 80 -j RETURN
-        + ip6tables -t filter -D our-chain -i eth0 -p tcp --dport 22 -j RETURN
+        + ip6tables -t filter -D our-chain -i eth0 -p tcp -m multiport --dports 22,80,9080 -j RETURN
         + ip6tables -t filter -D FORWARD -j our-chain
         + ip6tables -t filter -D INPUT -j our-chain
         + ip6tables -t filter -F our-chain
@@ -37,12 +52,170 @@ fn verify_verbose_output() -> Result<()> {
         + ip6tables -t filter -N our-chain
         + ip6tables -t filter -I INPUT 1 -j our-chain
         + ip6tables -t filter -I FORWARD 1 -j our-chain
-        + ip6tables -t filter -A our-chain -i eth0 -p tcp --dport 22 -j RETURN
-        + ip6tables -t filter -A our-chain -i eth0 -p tcp --dport 80 -j RETURN
-        + ip6tables -t filter -A our-chain -i eth0 -p tcp --dport 9080 -j RETURN
+        + ip6tables -t filter -A our-chain -i eth0 -p tcp -m multiport --dports 22,80,9080 -j RETURN
         + ip6tables -t filter -A our-chain -i eth0 -j REJECT
     "};
     assert_eq!(stderr, expected_stderr);
 
     Ok(())
 }
+
+#[test]
+fn verify_json_output() -> Result<()> {
+    let example_name = "main";
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--bin",
+            example_name,
+            "--",
+            "start",
+            "--output",
+            "json",
+            "--interfaces",
+            "eth0",
+        ])
+        .output()
+        .with_context(|| anyhow!("running {example_name:?}"))?;
+
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+
+    let plan = Plan::from_json(std::str::from_utf8(&output.stdout)?)?.commands;
+    assert_eq!(plan.len(), 22);
+    for (i, entry) in plan.iter().enumerate() {
+        let cmd = if i < 11 { "iptables" } else { "ip6tables" };
+        assert_eq!(entry.order, i + 1);
+        assert_eq!(entry.table, "filter");
+        assert_eq!(entry.cmd[..3], [cmd, "-t", "filter"]);
+    }
+    assert_eq!(plan[0].action, "delete");
+    assert!(plan[0].may_fail);
+    assert_eq!(
+        plan[7],
+        PlanEntry {
+            order: 8,
+            table: "filter".into(),
+            chain: "INPUT".into(),
+            action: "insert 1".into(),
+            cmd: [
+                "iptables",
+                "-t",
+                "filter",
+                "-I",
+                "INPUT",
+                "1",
+                "-j",
+                "our-chain"
+            ]
+            .map(String::from)
+            .to_vec(),
+            may_fail: false,
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn verify_status_output() -> Result<()> {
+    let example_name = "main";
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--bin",
+            example_name,
+            "--",
+            "status",
+            "--dry-run",
+            "--interfaces",
+            "eth0",
+        ])
+        .output()
+        .with_context(|| anyhow!("running {example_name:?}"))?;
+
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let expected_stdout = indoc! {"
+        present: iptables -t filter -C INPUT -j our-chain
+        present: iptables -t filter -C FORWARD -j our-chain
+        present: iptables -t filter -C our-chain -i eth0 -p tcp -m multiport --dports 22,80,9080 -j RETURN
+        present: iptables -t filter -C our-chain -i eth0 -j REJECT
+        present: ip6tables -t filter -C INPUT -j our-chain
+        present: ip6tables -t filter -C FORWARD -j our-chain
+        present: ip6tables -t filter -C our-chain -i eth0 -p tcp -m multiport --dports 22,80,9080 -j RETURN
+        present: ip6tables -t filter -C our-chain -i eth0 -j REJECT
+    "};
+    assert_eq!(stdout, expected_stdout);
+
+    Ok(())
+}
+
+#[test]
+fn verify_family_selection() -> Result<()> {
+    let example_name = "main";
+
+    for (family, cmd) in [("4", "iptables"), ("6", "ip6tables")] {
+        let output = Command::new("cargo")
+            .args([
+                "run",
+                "--quiet",
+                "--bin",
+                example_name,
+                "--",
+                "start",
+                "--dry-run",
+                "--family",
+                family,
+                "--interfaces",
+                "eth0",
+            ])
+            .output()
+            .with_context(|| anyhow!("running {example_name:?}"))?;
+
+        assert!(output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(stderr.lines().count(), 11);
+        for line in stderr.lines() {
+            assert!(line.starts_with(&format!("+ {cmd} -t filter ")), "{line}");
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn verify_summary_output() -> Result<()> {
+    let example_name = "main";
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--bin",
+            example_name,
+            "--",
+            "start",
+            "--dry-run",
+            "--summary",
+            "--interfaces",
+            "eth0",
+        ])
+        .output()
+        .with_context(|| anyhow!("running {example_name:?}"))?;
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "start: ran 22 commands across filter/our-chain, filter/FORWARD, filter/INPUT \
+         (0 failed)\n"
+    );
+
+    Ok(())
+}